@@ -0,0 +1,325 @@
+//! Control messages sent alongside data on Unix sockets (Linux only)
+//!
+//! `sendmsg` and `recvmsg` can carry file descriptors and the sender's
+//! credentials as well as bytes. [`AbstractStream::send_msg`] and
+//! [`AbstractDatagram::send_msg`] encode [`ControlMessage`]s into the
+//! kernel's format, and `recv_msg` decodes what arrives, taking
+//! ownership of any descriptors so none are leaked.
+
+use crate::{sys, AbstractDatagram, AbstractStream};
+use std::convert::{TryFrom, TryInto};
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::net::Ipv6Addr;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+const SOL_SOCKET: i32 = 1;
+const SCM_RIGHTS: i32 = 1;
+const SCM_CREDENTIALS: i32 = 2;
+const SO_PASSCRED: i32 = 16;
+
+/// The process, user and group on the other end of a Unix socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// This process's credentials, the only ones an unprivileged
+    /// process may send
+    pub fn current() -> Credentials {
+        let (pid, uid, gid) = sys::current_ids();
+        Credentials { pid, uid, gid }
+    }
+
+    fn to_bytes(self) -> [u8; 12] {
+        let mut b = [0u8; 12];
+        b[..4].copy_from_slice(&self.pid.to_ne_bytes());
+        b[4..8].copy_from_slice(&self.uid.to_ne_bytes());
+        b[8..].copy_from_slice(&self.gid.to_ne_bytes());
+        b
+    }
+
+    fn from_bytes(b: &[u8]) -> Option<Credentials> {
+        let word = |i: usize| Some(<[u8; 4]>::try_from(b.get(i..i + 4)?).unwrap());
+        Some(Credentials {
+            pid: i32::from_ne_bytes(word(0)?),
+            uid: u32::from_ne_bytes(word(4)?),
+            gid: u32::from_ne_bytes(word(8)?),
+        })
+    }
+}
+
+/// A control message to send
+#[derive(Debug, Clone, Copy)]
+pub enum ControlMessage<'a> {
+    /// Descriptors for the receiver to get copies of (`SCM_RIGHTS`)
+    Fds(&'a [BorrowedFd<'a>]),
+    /// `SCM_CREDENTIALS`
+    Credentials(Credentials),
+    /// Any other message, as its level, type and data
+    Other { level: i32, ty: i32, data: &'a [u8] },
+}
+
+/// A control message that was received
+#[derive(Debug)]
+pub enum ReceivedControl {
+    Fds(Vec<OwnedFd>),
+    /// Only arrives once [`set_passcred`](AbstractStream::set_passcred)
+    /// is on
+    Credentials(Credentials),
+    /// A UDP datagram's destination address and the index of the
+    /// interface it arrived on, once
+    /// [`set_recv_pktinfo`](AbstractDatagram::set_recv_pktinfo) is on
+    PacketInfo {
+        addr: Ipv6Addr,
+        interface: u32,
+    },
+    /// A UDP datagram's traffic class, once
+    /// [`set_recv_tclass`](AbstractDatagram::set_recv_tclass) is on
+    TrafficClass(u8),
+    Other {
+        level: i32,
+        ty: i32,
+        data: Vec<u8>,
+    },
+}
+
+/// The result of `recv_msg`
+#[derive(Debug)]
+pub struct ReceivedMsg {
+    /// How many bytes of data were read
+    pub len: usize,
+    pub control: Vec<ReceivedControl>,
+    /// The datagram was longer than the buffers and the rest was lost
+    pub truncated: bool,
+    /// Control messages didn't fit in `control_len` and some were lost
+    pub control_truncated: bool,
+}
+
+const HDR: usize = std::mem::size_of::<usize>() + 8;
+
+fn align(len: usize) -> usize {
+    let a = std::mem::size_of::<usize>();
+    (len + a - 1) & !(a - 1)
+}
+
+/// The bytes `control` takes up in the kernel's format
+fn encode(control: &[ControlMessage<'_>]) -> Vec<u8> {
+    let mut out = vec![];
+    for msg in control {
+        let creds;
+        let fds: Vec<u8>;
+        let (level, ty, data): (i32, i32, &[u8]) = match *msg {
+            ControlMessage::Fds(f) => {
+                fds = f
+                    .iter()
+                    .flat_map(|fd| fd.as_raw_fd().to_ne_bytes())
+                    .collect();
+                (SOL_SOCKET, SCM_RIGHTS, &fds)
+            }
+            ControlMessage::Credentials(c) => {
+                creds = c.to_bytes();
+                (SOL_SOCKET, SCM_CREDENTIALS, &creds)
+            }
+            ControlMessage::Other { level, ty, data } => (level, ty, data),
+        };
+        let start = out.len();
+        out.extend_from_slice(&(align(HDR) + data.len()).to_ne_bytes());
+        out.extend_from_slice(&level.to_ne_bytes());
+        out.extend_from_slice(&ty.to_ne_bytes());
+        out.resize(start + align(HDR), 0);
+        out.extend_from_slice(data);
+        out.resize(start + align(HDR) + align(data.len()), 0);
+    }
+    out
+}
+
+fn decode(buf: &[u8]) -> Vec<ReceivedControl> {
+    let word = std::mem::size_of::<usize>();
+    let mut out = vec![];
+    let mut at = 0;
+    while at + HDR <= buf.len() {
+        let len = usize::from_ne_bytes(buf[at..at + word].try_into().unwrap());
+        let level = i32::from_ne_bytes(buf[at + word..at + word + 4].try_into().unwrap());
+        let ty = i32::from_ne_bytes(buf[at + word + 4..at + HDR].try_into().unwrap());
+        if len < align(HDR) || at + len > buf.len() {
+            break;
+        }
+        let data = &buf[at + align(HDR)..at + len];
+        out.push(match (level, ty) {
+            (SOL_SOCKET, SCM_RIGHTS) => ReceivedControl::Fds(
+                data.chunks_exact(4)
+                    .map(|b| {
+                        let fd = RawFd::from_ne_bytes(b.try_into().unwrap());
+                        // the kernel just installed it for us
+                        unsafe { OwnedFd::from_raw_fd(fd) }
+                    })
+                    .collect(),
+            ),
+            (SOL_SOCKET, SCM_CREDENTIALS) if Credentials::from_bytes(data).is_some() => {
+                ReceivedControl::Credentials(Credentials::from_bytes(data).unwrap())
+            }
+            (sys::IPPROTO_IPV6, sys::IPV6_PKTINFO) if data.len() >= 20 => {
+                ReceivedControl::PacketInfo {
+                    addr: Ipv6Addr::from(<[u8; 16]>::try_from(&data[..16]).unwrap()),
+                    interface: u32::from_ne_bytes(data[16..20].try_into().unwrap()),
+                }
+            }
+            (sys::IPPROTO_IPV6, sys::IPV6_TCLASS) if data.len() >= 4 => {
+                ReceivedControl::TrafficClass(
+                    i32::from_ne_bytes(data[..4].try_into().unwrap()) as u8
+                )
+            }
+            _ => ReceivedControl::Other {
+                level,
+                ty,
+                data: data.to_vec(),
+            },
+        });
+        at += align(len);
+    }
+    out
+}
+
+pub(crate) fn send(
+    fd: RawFd,
+    bufs: &[IoSlice<'_>],
+    control: &[ControlMessage<'_>],
+) -> Result<usize> {
+    sys::send_msg(fd, bufs, &encode(control))
+}
+
+pub(crate) fn recv(
+    fd: RawFd,
+    bufs: &mut [IoSliceMut<'_>],
+    control_len: usize,
+) -> Result<ReceivedMsg> {
+    // usize words keep the headers aligned
+    let mut space = vec![0usize; control_len.div_ceil(std::mem::size_of::<usize>())];
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(
+            space.as_mut_ptr() as *mut u8,
+            space.len() * std::mem::size_of::<usize>(),
+        )
+    };
+    let (len, used, flags) = sys::recv_msg(fd, bufs, bytes)?;
+    Ok(ReceivedMsg {
+        len,
+        control: decode(&bytes[..used.min(bytes.len())]),
+        truncated: flags & sys::MSG_TRUNC != 0,
+        control_truncated: flags & sys::MSG_CTRUNC != 0,
+    })
+}
+
+fn no_control() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "control messages need a Unix socket",
+    )
+}
+
+impl AbstractStream {
+    /// Sends data along with control messages
+    pub fn send_msg(&self, bufs: &[IoSlice<'_>], control: &[ControlMessage<'_>]) -> Result<usize> {
+        match self {
+            Self::Unix(s) => send(s.as_raw_fd(), bufs, control),
+            _ => Err(no_control()),
+        }
+    }
+
+    /// Receives data and up to `control_len` bytes of control messages
+    ///
+    /// Each message takes a header of two or three words plus its data,
+    /// padded to a word; 256 is plenty for credentials and a few dozen
+    /// descriptors.
+    pub fn recv_msg(&self, bufs: &mut [IoSliceMut<'_>], control_len: usize) -> Result<ReceivedMsg> {
+        match self {
+            Self::Unix(s) => recv(s.as_raw_fd(), bufs, control_len),
+            _ => Err(no_control()),
+        }
+    }
+
+    /// Whether the sender's credentials arrive with every message
+    pub fn set_passcred(&self, on: bool) -> Result<()> {
+        match self {
+            Self::Unix(s) => {
+                sys::setsockopt_raw(s.as_raw_fd(), SOL_SOCKET, SO_PASSCRED, &(on as i32))
+            }
+            _ => Err(no_control()),
+        }
+    }
+}
+
+impl AbstractDatagram {
+    /// Sends a datagram along with control messages, on a connected socket
+    pub fn send_msg(&self, bufs: &[IoSlice<'_>], control: &[ControlMessage<'_>]) -> Result<usize> {
+        match self {
+            Self::Unix(s) => send(s.as_raw_fd(), bufs, control),
+            Self::Udp(s) => send(s.as_raw_fd(), bufs, control),
+        }
+    }
+
+    /// Like [`AbstractStream::recv_msg`], and on UDP sockets too
+    pub fn recv_msg(&self, bufs: &mut [IoSliceMut<'_>], control_len: usize) -> Result<ReceivedMsg> {
+        match self {
+            Self::Unix(s) => recv(s.as_raw_fd(), bufs, control_len),
+            Self::Udp(s) => recv(s.as_raw_fd(), bufs, control_len),
+        }
+    }
+
+    pub fn set_passcred(&self, on: bool) -> Result<()> {
+        match self {
+            Self::Unix(s) => {
+                sys::setsockopt_raw(s.as_raw_fd(), SOL_SOCKET, SO_PASSCRED, &(on as i32))
+            }
+            _ => Err(no_control()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn pass_fd_and_creds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (a, b) = (AbstractStream::from(a), AbstractStream::from(b));
+        b.set_passcred(true).unwrap();
+
+        let (mut mine, theirs) = UnixStream::pair().unwrap();
+        a.send_msg(
+            &[IoSlice::new(b"fd")],
+            &[
+                ControlMessage::Fds(&[theirs.as_fd()]),
+                ControlMessage::Credentials(Credentials::current()),
+            ],
+        )
+        .unwrap();
+        drop(theirs);
+
+        let mut buf = [0u8; 8];
+        let got = b.recv_msg(&mut [IoSliceMut::new(&mut buf)], 256).unwrap();
+        assert_eq!(&buf[..got.len], b"fd");
+        assert!(!got.control_truncated);
+        let mut creds = None;
+        let mut fd = None;
+        for c in got.control {
+            match c {
+                ReceivedControl::Fds(mut f) => fd = f.pop(),
+                ReceivedControl::Credentials(c) => creds = Some(c),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(creds, Some(Credentials::current()));
+        let mut passed = UnixStream::from(fd.unwrap());
+        passed.write_all(b"x").unwrap();
+        let mut b = [0u8];
+        mine.read_exact(&mut b).unwrap();
+    }
+}
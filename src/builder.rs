@@ -0,0 +1,730 @@
+//! Listeners with options that apply to how they accept
+
+use crate::{AbstractAddr, AbstractListener, AbstractStream, AbstractToSocketAddrs, RateLimited};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr as IpSocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Binds an [`AbstractListener`] with extra options
+///
+/// ```no_run
+/// # use anysocket::ListenerBuilder;
+/// let listener = ListenerBuilder::new()
+///     .nonblocking(true)
+///     .bind("unix:/run/example.sock")?;
+/// let (stream, _) = listener.accept()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ListenerBuilder {
+    accept: AcceptOptions,
+    accept_filter: Option<String>,
+    defer_accept: Option<Duration>,
+    rate_limit: Option<u64>,
+    backlog: Option<u32>,
+    congestion: Option<String>,
+    transparent: bool,
+    md5_keys: Vec<(IpAddr, Vec<u8>)>,
+}
+
+/// The backlog std listens with, which a listener has unless told otherwise
+const DEFAULT_BACKLOG: u32 = 128;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AcceptOptions {
+    pub(crate) inheritable: bool,
+    pub(crate) nonblocking: bool,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl AcceptOptions {
+    /// Sets the options that don't have to be set while accepting
+    fn apply(&self, stream: &AbstractStream) -> Result<()> {
+        if let AbstractStream::Tcp(s) = stream {
+            if self.nodelay {
+                s.set_nodelay(true)?;
+            }
+        }
+        if self.read_timeout.is_some() {
+            stream.set_read_timeout(self.read_timeout)?;
+        }
+        if self.write_timeout.is_some() {
+            stream.set_write_timeout(self.write_timeout)?;
+        }
+        if self.keepalive.is_none()
+            && self.recv_buffer_size.is_none()
+            && self.send_buffer_size.is_none()
+        {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use crate::sys;
+            let fd = stream.raw_fd();
+            if let (AbstractStream::Tcp(_), Some(idle)) = (stream, self.keepalive) {
+                sys::set_keepalive(fd, Some(idle))?;
+            }
+            if let Some(size) = self.recv_buffer_size {
+                sys::set_buffer_size(fd, false, size)?;
+            }
+            if let Some(size) = self.send_buffer_size {
+                sys::set_buffer_size(fd, true, size)?;
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "keepalive and buffer sizes are not supported on this platform",
+        ))
+    }
+}
+
+impl ListenerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether accepted streams stay open in child processes
+    /// started with exec (Unix only, off by default)
+    ///
+    /// Either way the flag is set as the connection is accepted, so with
+    /// the default a stream is never leaked into a child that another
+    /// thread forks at the wrong moment. Only Linux and Android do this
+    /// atomically for inheritable or nonblocking streams; elsewhere the
+    /// flags are changed right after accepting.
+    pub fn inheritable(mut self, inheritable: bool) -> Self {
+        self.accept.inheritable = inheritable;
+        self
+    }
+
+    /// Whether accepted streams are in nonblocking mode
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.accept.nonblocking = nonblocking;
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on accepted TCP streams
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.accept.nodelay = nodelay;
+        self
+    }
+
+    /// Turns on TCP keepalive for accepted TCP streams, probing after
+    /// `idle` without traffic where the platform allows choosing that
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.accept.keepalive = Some(idle);
+        self
+    }
+
+    /// The read timeout of accepted streams
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.accept.read_timeout = Some(timeout);
+        self
+    }
+
+    /// The write timeout of accepted streams
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.accept.write_timeout = Some(timeout);
+        self
+    }
+
+    /// The kernel receive buffer size (`SO_RCVBUF`) of accepted streams
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.accept.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// The kernel send buffer size (`SO_SNDBUF`) of accepted streams
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.accept.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Chooses the TCP congestion control algorithm accepted
+    /// connections use, like [`AbstractStream::set_congestion`]
+    pub fn congestion(mut self, name: &str) -> Self {
+        self.congestion = Some(name.to_owned());
+        self
+    }
+
+    /// Sets `IP_TRANSPARENT`, so the listener takes connections that a
+    /// `TPROXY` rule intercepted, whatever address they were headed for
+    ///
+    /// The option is set before the socket is bound, so the address may
+    /// be one that isn't this host's. This needs `CAP_NET_ADMIN`, and
+    /// binding fails with `Unsupported` for addresses that aren't TCP
+    /// and on platforms other than Linux.
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Requires TCP MD5 signatures made with `key` on connections from
+    /// `peer`, like [`AbstractListener::set_md5_key`]; call it again for
+    /// each peer
+    pub fn md5_key(mut self, peer: IpAddr, key: &[u8]) -> Self {
+        self.md5_keys.push((peer, key.to_owned()));
+        self
+    }
+
+    /// Installs a FreeBSD accept filter, such as `dataready` or
+    /// `httpready`, so `accept` only returns connections once the
+    /// filter is satisfied
+    ///
+    /// The filter's kernel module must be loaded. Binding fails with
+    /// `Unsupported` on other platforms.
+    pub fn accept_filter(mut self, name: &str) -> Self {
+        self.accept_filter = Some(name.to_owned());
+        self
+    }
+
+    /// Sets `TCP_DEFER_ACCEPT` (Linux only), so `accept` doesn't return
+    /// a connection until the client has sent something or `timeout`
+    /// (rounded up to whole seconds) has passed
+    ///
+    /// Binding fails with `Unsupported` on other platforms.
+    pub fn defer_accept(mut self, timeout: Duration) -> Self {
+        self.defer_accept = Some(timeout);
+        self
+    }
+
+    /// Caps the throughput of each stream from
+    /// [`accept_limited`](ConfiguredListener::accept_limited) to
+    /// `bytes_per_sec` in each direction
+    pub fn rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// How many connections the kernel queues for `accept`, instead of
+    /// the 128 std listens with
+    ///
+    /// Binding fails with `Unsupported` on platforms other than Unix.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    pub fn bind<A: AbstractToSocketAddrs + ?Sized>(&self, addr: &A) -> Result<ConfiguredListener> {
+        if self.transparent {
+            return self.bind_tcp(addr.to_ip_addrs()?, false);
+        }
+        let inner = addr.bind_any()?;
+        self.configure(&inner)?;
+        Ok(ConfiguredListener::new(inner, self))
+    }
+
+    /// Like [`bind`](Self::bind), but with `SO_REUSEPORT`, so listeners
+    /// in this process or others run by the same user can share the
+    /// address, and the kernel spreads connections between them
+    ///
+    /// Only TCP can share addresses this way, so `addr` is anything std
+    /// can resolve to IP addresses. It fails with `Unsupported` on
+    /// platforms without Unix sockets.
+    pub fn bind_reuseport<A: ToSocketAddrs + ?Sized>(
+        &self,
+        addr: &A,
+    ) -> Result<ConfiguredListener> {
+        self.bind_tcp(addr.to_socket_addrs()?, true)
+    }
+
+    /// Binds the first of `addrs` that it can with a socket made by
+    /// hand, for the options that must be set before `bind`
+    fn bind_tcp(
+        &self,
+        addrs: impl IntoIterator<Item = IpSocketAddr>,
+        reuseport: bool,
+    ) -> Result<ConfiguredListener> {
+        let mut last = None;
+        for local in addrs {
+            #[cfg(unix)]
+            let bound = crate::sys::bind_tcp(&local, reuseport, self.transparent);
+            #[cfg(not(unix))]
+            let bound = Err(Error::new(
+                ErrorKind::Unsupported,
+                "binding TCP sockets by hand is not supported on this platform",
+            ));
+            match crate::error::context(crate::Operation::Bind, &local, bound) {
+                Ok(l) => {
+                    let inner = AbstractListener::from(l);
+                    self.configure(&inner)?;
+                    return Ok(ConfiguredListener::new(inner, self));
+                }
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "no addresses to bind to")))
+    }
+
+    /// Applies the options that are set on the listening socket itself
+    fn configure(&self, listener: &AbstractListener) -> Result<()> {
+        if let Some(backlog) = self.backlog {
+            #[cfg(unix)]
+            crate::sys::set_backlog(listener.raw_fd(), Some(backlog))?;
+            #[cfg(not(unix))]
+            {
+                let _ = backlog;
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "setting the backlog is not supported on this platform",
+                ));
+            }
+        }
+        if let Some(name) = &self.congestion {
+            listener.set_congestion(name)?;
+        }
+        for (peer, key) in &self.md5_keys {
+            listener.set_md5_key(*peer, key)?;
+        }
+        if let Some(name) = &self.accept_filter {
+            #[cfg(target_os = "freebsd")]
+            crate::sys::set_accept_filter(listener.raw_fd(), name)?;
+            #[cfg(not(target_os = "freebsd"))]
+            {
+                let _ = listener;
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("accept filter {:?} needs FreeBSD", name),
+                ));
+            }
+        }
+        if let Some(timeout) = self.defer_accept {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            {
+                use crate::sys;
+                let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+                let secs = secs.min(std::os::raw::c_int::MAX as u64) as std::os::raw::c_int;
+                sys::setsockopt_raw(
+                    listener.raw_fd(),
+                    sys::IPPROTO_TCP,
+                    sys::TCP_DEFER_ACCEPT,
+                    &secs,
+                )?;
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            {
+                let _ = (listener, timeout);
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "TCP_DEFER_ACCEPT needs Linux",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a daemon's config file says about one listener, filled in
+/// field by field from whatever format it reads
+///
+/// Everything but `address` is optional and left as the system has it
+/// when unset. [`build`](Self::build) binds with a [`ListenerBuilder`]
+/// made from the fields, then sets those the builder has no option for.
+///
+/// There is no `serde` derive, since the crate has no dependencies, so
+/// the caller fills the fields in from what it parsed. Nor are there
+/// fields for TLS certificates or the PROXY protocol: the crate speaks
+/// neither, and those are left to whatever wraps the accepted streams.
+///
+/// ```no_run
+/// # use anysocket::ListenerConfig;
+/// let listener = ListenerConfig {
+///     backlog: Some(64),
+///     permissions: Some(0o660),
+///     ..ListenerConfig::new("unix:/run/example.sock")
+/// }
+/// .build()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListenerConfig {
+    /// Anything the string API binds, such as `0.0.0.0:8000` or
+    /// `unix:/run/x.sock`
+    pub address: String,
+    /// How many connections the kernel queues for `accept` (Unix only)
+    pub backlog: Option<u32>,
+    /// The mode for the socket file of a Unix listener, such as `0o660`
+    pub permissions: Option<u32>,
+    pub nonblocking: bool,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    /// Per-stream cap for [`ConfiguredListener::accept_limited`], in
+    /// bytes per second each way
+    pub rate_limit: Option<u64>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: &str) -> Self {
+        ListenerConfig {
+            address: address.to_owned(),
+            ..Self::default()
+        }
+    }
+
+    /// The builder for the fields it has options for
+    pub fn builder(&self) -> ListenerBuilder {
+        let mut b = ListenerBuilder::new()
+            .nonblocking(self.nonblocking)
+            .nodelay(self.nodelay);
+        b.accept.keepalive = self.keepalive;
+        b.accept.read_timeout = self.read_timeout;
+        b.accept.write_timeout = self.write_timeout;
+        b.accept.recv_buffer_size = self.recv_buffer_size;
+        b.accept.send_buffer_size = self.send_buffer_size;
+        b.rate_limit = self.rate_limit;
+        b.backlog = self.backlog;
+        b
+    }
+
+    /// Binds the listener and applies everything the config asks for
+    ///
+    /// The socket file only gets its `permissions` once it's bound, so
+    /// for a moment it has the mode the umask gives. `permissions` on
+    /// anything but a Unix listener with a path fails with
+    /// `InvalidInput`, and `backlog` fails with `Unsupported` off Unix.
+    pub fn build(&self) -> Result<ConfiguredListener> {
+        let listener = self.builder().bind(self.address.as_str())?;
+        if let Some(mode) = self.permissions {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let path = listener.local_addr()?.unix_path().map(|p| p.to_owned());
+                let path = path.ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} has no socket file to set permissions on", self.address),
+                    )
+                })?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = mode;
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} has no socket file to set permissions on", self.address),
+                ));
+            }
+        }
+        Ok(listener)
+    }
+}
+
+/// A listener made by [`ListenerBuilder`]
+///
+/// Dereferences to the [`AbstractListener`], but its own `accept`
+/// applies the builder's options to each accepted stream.
+#[derive(Debug)]
+pub struct ConfiguredListener {
+    inner: AbstractListener,
+    accept: AcceptOptions,
+    rate_limit: Option<u64>,
+    /// What to put the backlog back to after a pause shrinks it
+    backlog: u32,
+    paused: Mutex<bool>,
+    shrunk: AtomicBool,
+    resumed: Condvar,
+}
+
+impl ConfiguredListener {
+    fn new(inner: AbstractListener, builder: &ListenerBuilder) -> Self {
+        ConfiguredListener {
+            inner,
+            accept: builder.accept,
+            rate_limit: builder.rate_limit,
+            backlog: builder.backlog.unwrap_or(DEFAULT_BACKLOG),
+            paused: Mutex::new(false),
+            shrunk: AtomicBool::new(false),
+            resumed: Condvar::new(),
+        }
+    }
+
+    /// Waits while the listener is [paused](Self::pause), then accepts
+    pub fn accept(&self) -> Result<(AbstractStream, AbstractAddr)> {
+        self.wait_resumed();
+        let (stream, addr) = self.inner.accept_with(&self.accept)?;
+        // an accept that was already waiting when pause was called
+        // holds on to its connection rather than dropping it
+        self.wait_resumed();
+        self.accept.apply(&stream)?;
+        Ok((stream, addr))
+    }
+
+    fn wait_resumed(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+    }
+
+    /// Stops accepting, so new connections wait in the kernel's backlog
+    /// rather than being accepted only to be closed, until
+    /// [`resume`](Self::resume)
+    ///
+    /// With `backlog`, the kernel is also told to queue only that many,
+    /// so further connections are refused or, for TCP on Linux, retried
+    /// by the client. Unix systems only; elsewhere a `backlog` gives
+    /// `Unsupported`.
+    pub fn pause(&self, backlog: Option<u32>) -> Result<()> {
+        if let Some(backlog) = backlog {
+            #[cfg(unix)]
+            crate::sys::set_backlog(self.inner.raw_fd(), Some(backlog))?;
+            #[cfg(not(unix))]
+            {
+                let _ = backlog;
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "changing the backlog is not supported on this platform",
+                ));
+            }
+            self.shrunk.store(true, Ordering::SeqCst);
+        }
+        *self.paused.lock().unwrap() = true;
+        Ok(())
+    }
+
+    /// Accepts again after [`pause`](Self::pause), putting the backlog
+    /// back to what it was bound with if `pause` shrank it
+    pub fn resume(&self) -> Result<()> {
+        #[cfg(unix)]
+        if self.shrunk.swap(false, Ordering::SeqCst) {
+            crate::sys::set_backlog(self.inner.raw_fd(), Some(self.backlog))?;
+        }
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Like `accept`, but wraps the stream to keep to the builder's
+    /// [`rate_limit`](ListenerBuilder::rate_limit), if there is one
+    pub fn accept_limited(&self) -> Result<(RateLimited, AbstractAddr)> {
+        let (stream, addr) = self.accept()?;
+        let limit = self.rate_limit.unwrap_or(u64::MAX);
+        Ok((RateLimited::new(stream, limit), addr))
+    }
+
+    pub fn into_inner(self) -> AbstractListener {
+        self.inner
+    }
+}
+
+impl std::ops::Deref for ConfiguredListener {
+    type Target = AbstractListener;
+    fn deref(&self) -> &AbstractListener {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    #[cfg(unix)]
+    fn from_config() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("anysocket-config-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ListenerConfig {
+            backlog: Some(4),
+            permissions: Some(0o640),
+            read_timeout: Some(Duration::from_millis(10)),
+            ..ListenerConfig::new(&format!("unix:{}", path.display()))
+        };
+        let listener = config.build().unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        let _client = listener.local_addr().unwrap().connect_any().unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let e = stream.read(&mut [0u8]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+        std::fs::remove_file(&path).unwrap();
+
+        let tcp = ListenerConfig {
+            permissions: Some(0o600),
+            ..ListenerConfig::new("127.0.0.1:0")
+        };
+        assert_eq!(
+            tcp.build().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn accept_options() {
+        let listener = ListenerBuilder::new()
+            .nonblocking(true)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let _client = listener.local_addr().unwrap().connect_any().unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let e = stream.read(&mut [0u8]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+
+        #[cfg(not(target_os = "freebsd"))]
+        {
+            let e = ListenerBuilder::new()
+                .accept_filter("dataready")
+                .bind("127.0.0.1:0")
+                .unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let listener = ListenerBuilder::new()
+                .defer_accept(Duration::from_secs(1))
+                .bind("127.0.0.1:0")
+                .unwrap();
+            let mut client = listener.local_addr().unwrap().connect_any().unwrap();
+            std::io::Write::write_all(&mut client, b"x").unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0u8]).unwrap();
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            let cloexec = |s: &AbstractStream| {
+                let fd = match s {
+                    AbstractStream::Tcp(s) => s.as_raw_fd(),
+                    _ => unreachable!(),
+                };
+                let flags = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(format!(
+                        "test -e /proc/self/fd/{} || test -e /dev/fd/{}",
+                        fd, fd
+                    ))
+                    .status()
+                    .unwrap();
+                !flags.success()
+            };
+            assert!(cloexec(&stream));
+
+            let listener = ListenerBuilder::new()
+                .inheritable(true)
+                .bind("127.0.0.1:0")
+                .unwrap();
+            let _client = listener.local_addr().unwrap().connect_any().unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            assert!(!cloexec(&stream));
+        }
+
+        let listener = ListenerBuilder::new()
+            .nodelay(true)
+            .read_timeout(Duration::from_secs(7))
+            .keepalive(Duration::from_secs(30))
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let _client = listener.local_addr().unwrap().connect_any().unwrap();
+        let (profiled, _) = listener.accept().unwrap();
+        assert_eq!(
+            profiled.read_timeout().unwrap(),
+            Some(Duration::from_secs(7))
+        );
+        assert!(profiled.into_tcp().unwrap().nodelay().unwrap());
+    }
+
+    #[test]
+    fn pause_resume() {
+        let listener = std::sync::Arc::new(ListenerBuilder::new().bind("127.0.0.1:0").unwrap());
+        listener.pause(Some(1)).unwrap();
+        assert!(listener.is_paused());
+        let _client = listener.local_addr().unwrap().connect_any().unwrap();
+        let accepting = {
+            let listener = listener.clone();
+            std::thread::spawn(move || listener.accept().map(drop))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!accepting.is_finished());
+        listener.resume().unwrap();
+        accepting.join().unwrap().unwrap();
+        assert!(!listener.is_paused());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rebind_over_time_wait() {
+        let any: IpSocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = crate::sys::bind_tcp(&any, false, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        // closing the accepted end first leaves it in TIME_WAIT
+        drop(listener.accept().unwrap());
+        drop(listener);
+        drop(client);
+        crate::sys::bind_tcp(&addr, false, false).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn transparent_before_bind() {
+        // not this host's, so binding only works once IP_TRANSPARENT is set
+        match ListenerBuilder::new().transparent(true).bind("192.0.2.1:0") {
+            Ok(listener) => {
+                assert!(matches!(
+                    listener.local_addr().unwrap(),
+                    AbstractAddr::Ip(a) if a.ip() == IpAddr::from([192, 0, 2, 1])
+                ));
+                let mut set = [0u8; 4];
+                let fd = listener.raw_fd();
+                crate::sys::getsockopt_bytes(fd, crate::sys::IPPROTO_IP, 19, &mut set).unwrap();
+                assert_eq!(i32::from_ne_bytes(set), 1);
+            }
+            Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+        }
+        let e = ListenerBuilder::new()
+            .transparent(true)
+            .bind("unix:/nonexistent/anysocket.sock")
+            .unwrap_err();
+        assert_ne!(e.kind(), ErrorKind::PermissionDenied);
+    }
+
+    /// The most connections the kernel queues for a TCP listener, which
+    /// Linux reports as `tcpi_sacked`
+    #[cfg(target_os = "linux")]
+    fn max_backlog(listener: &ConfiguredListener) -> u32 {
+        use std::convert::TryInto;
+        let mut info = [0u8; 104];
+        crate::sys::getsockopt_bytes(listener.raw_fd(), crate::sys::IPPROTO_TCP, 11, &mut info)
+            .unwrap();
+        u32::from_ne_bytes(info[28..32].try_into().unwrap())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resume_restores_backlog() {
+        let listener = ListenerBuilder::new()
+            .backlog(5)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        assert_eq!(max_backlog(&listener), 5);
+        listener.pause(Some(1)).unwrap();
+        assert_eq!(max_backlog(&listener), 1);
+        listener.resume().unwrap();
+        assert_eq!(max_backlog(&listener), 5);
+
+        let listener = ListenerBuilder::new().bind("127.0.0.1:0").unwrap();
+        listener.pause(None).unwrap();
+        listener.resume().unwrap();
+        assert_eq!(max_backlog(&listener), DEFAULT_BACKLOG);
+    }
+}
@@ -0,0 +1,171 @@
+//! Injecting transport failures, for tests
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::time::Duration;
+
+/// Makes a stream misbehave the way real connections sometimes do
+///
+/// Nothing happens until it's asked for: add delays with
+/// [`latency`](Self::latency), split reads with
+/// [`short_reads`](Self::short_reads), fail calls at random with
+/// [`would_block`](Self::would_block) and [`errors`](Self::errors), or
+/// cut the connection after some bytes with
+/// [`disconnect_after`](Self::disconnect_after).
+///
+/// Probabilities are between 0 and 1 and apply to each `read` and `write`.
+#[derive(Debug)]
+pub struct Chaos<S = AbstractStream> {
+    inner: S,
+    latency: Duration,
+    max_read: usize,
+    would_block: f64,
+    errors: f64,
+    /// Bytes left until the connection is cut
+    remaining: Option<u64>,
+}
+
+impl<S: Read + Write> Chaos<S> {
+    pub fn new(inner: S) -> Self {
+        Chaos {
+            inner,
+            latency: Duration::ZERO,
+            max_read: usize::MAX,
+            would_block: 0.0,
+            errors: 0.0,
+            remaining: None,
+        }
+    }
+
+    /// Sleeps for `latency` before each `read` and `write`
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Reads at most `max` bytes at a time
+    pub fn short_reads(mut self, max: usize) -> Self {
+        self.max_read = max.max(1);
+        self
+    }
+
+    /// Fails with `WouldBlock` with probability `p`, without doing anything
+    pub fn would_block(mut self, p: f64) -> Self {
+        self.would_block = p;
+        self
+    }
+
+    /// Fails with `ConnectionReset` with probability `p`, without doing anything
+    pub fn errors(mut self, p: f64) -> Self {
+        self.errors = p;
+        self
+    }
+
+    /// Acts as if the peer went away once `bytes` have been read and
+    /// written in total: reads see the end of the stream and writes
+    /// fail with `BrokenPipe`
+    pub fn disconnect_after(mut self, bytes: u64) -> Self {
+        self.remaining = Some(bytes);
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// What each call goes through first, giving how many bytes it may move
+    fn before(&mut self, len: usize) -> Result<usize> {
+        if !self.latency.is_zero() {
+            std::thread::sleep(self.latency);
+        }
+        if chance(self.would_block) {
+            return Err(Error::new(ErrorKind::WouldBlock, "injected WouldBlock"));
+        }
+        if chance(self.errors) {
+            return Err(Error::new(ErrorKind::ConnectionReset, "injected error"));
+        }
+        Ok(match self.remaining {
+            Some(left) => len.min(left.min(usize::MAX as u64) as usize),
+            None => len,
+        })
+    }
+
+    fn moved(&mut self, n: usize) {
+        if let Some(left) = &mut self.remaining {
+            *left -= n as u64;
+        }
+    }
+}
+
+fn chance(p: f64) -> bool {
+    p > 0.0 && (crate::random_u64() >> 11) as f64 / (1u64 << 53) as f64 <= p
+}
+
+impl<S: Read + Write> Read for Chaos<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let want = self.before(buf.len().min(self.max_read))?;
+        if want == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..want])?;
+        self.moved(n);
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for Chaos<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let want = self.before(buf.len())?;
+        if want == 0 {
+            return Err(Error::new(ErrorKind::BrokenPipe, "injected disconnect"));
+        }
+        let n = self.inner.write(&buf[..want])?;
+        self.moved(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misbehaves() {
+        let data: &[u8] = b"hello world";
+        let mut c = Chaos::new(std::io::Cursor::new(data.to_vec())).short_reads(3);
+        let mut buf = [0u8; 16];
+        assert_eq!(c.read(&mut buf).unwrap(), 3);
+
+        let mut c = Chaos::new(std::io::Cursor::new(data.to_vec())).disconnect_after(5);
+        let mut got = vec![];
+        c.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"hello");
+        let e = c.write(b"x").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+
+        let mut c = Chaos::new(std::io::Cursor::new(vec![])).would_block(1.0);
+        assert_eq!(c.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        let mut c = Chaos::new(std::io::Cursor::new(vec![])).errors(1.0);
+        assert_eq!(
+            c.write(b"x").unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+    }
+}
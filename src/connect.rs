@@ -0,0 +1,272 @@
+//! Outgoing connections with options that apply before connecting
+
+use crate::error::{self, Operation};
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr as IpSocketAddr;
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// Connects an [`AbstractStream`] with extra options
+///
+/// The options are for TCP, so `connect` takes anything std can
+/// resolve to IP addresses rather than any abstract address.
+///
+/// ```no_run
+/// # use anysocket::ConnectBuilder;
+/// let stream = ConnectBuilder::new()
+///     .local_addr("10.0.0.5:0".parse().unwrap())
+///     .connect("example.com:80")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConnectBuilder {
+    local_addr: Option<IpSocketAddr>,
+    interface: Option<String>,
+    congestion: Option<String>,
+    transparent: bool,
+    md5_key: Option<Vec<u8>>,
+}
+
+impl ConnectBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds the socket to `addr` before connecting, to choose the
+    /// source address (port 0 picks any port)
+    ///
+    /// Remote addresses of the other IP version are skipped.
+    pub fn local_addr(mut self, addr: IpSocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Sends the connection through the network interface `name`,
+    /// whatever the routing table says
+    ///
+    /// This is `SO_BINDTODEVICE` on Linux, which needs `CAP_NET_RAW`
+    /// on older kernels, and `IP_BOUND_IF` on macOS. Connecting fails
+    /// with `Unsupported` on other platforms.
+    pub fn interface(mut self, name: &str) -> Self {
+        self.interface = Some(name.to_owned());
+        self
+    }
+
+    /// Chooses the TCP congestion control algorithm, like
+    /// [`AbstractStream::set_congestion`], before the handshake
+    pub fn congestion(mut self, name: &str) -> Self {
+        self.congestion = Some(name.to_owned());
+        self
+    }
+
+    /// Sets `IP_TRANSPARENT`, so [`local_addr`](Self::local_addr) may
+    /// be an address that isn't this host's, as when a transparent
+    /// proxy connects upstream as the original client
+    ///
+    /// This needs `CAP_NET_ADMIN` and routing that brings the replies
+    /// back, and connecting fails with `Unsupported` except on Linux.
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Signs the connection with TCP MD5 signatures (RFC 2385) made
+    /// with `key`, as some routers require of BGP sessions
+    ///
+    /// Keys are at most 80 bytes, and connecting fails with
+    /// `Unsupported` except on Linux.
+    pub fn md5_key(mut self, key: &[u8]) -> Self {
+        self.md5_key = Some(key.to_owned());
+        self
+    }
+
+    /// Tries each address `addr` resolves to until one connects
+    pub fn connect<A: ToSocketAddrs + ?Sized>(&self, addr: &A) -> Result<AbstractStream> {
+        let mut last = None;
+        for remote in addr.to_socket_addrs()? {
+            if self
+                .local_addr
+                .is_some_and(|l| l.is_ipv4() != remote.is_ipv4())
+            {
+                continue;
+            }
+            match error::context(Operation::Connect, &remote, self.connect_one(&remote)) {
+                Ok(s) => return Ok(s.into()),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "no addresses to connect to with these options",
+            )
+        }))
+    }
+
+    fn connect_one(&self, remote: &IpSocketAddr) -> Result<TcpStream> {
+        if self.local_addr.is_none()
+            && self.interface.is_none()
+            && self.congestion.is_none()
+            && !self.transparent
+            && self.md5_key.is_none()
+        {
+            return TcpStream::connect(remote);
+        }
+        self.connect_raw(remote)
+    }
+
+    #[cfg(unix)]
+    fn connect_raw(&self, remote: &IpSocketAddr) -> Result<TcpStream> {
+        use crate::sys;
+        use std::os::unix::io::AsRawFd;
+        let fd = sys::tcp_socket(remote)?;
+        if let Some(name) = &self.interface {
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios"
+            ))]
+            sys::bind_to_device(fd.as_raw_fd(), name, remote.is_ipv6())?;
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios"
+            )))]
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("can't choose interface {:?} on this platform", name),
+            ));
+        }
+        if let Some(name) = &self.congestion {
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+            sys::set_congestion(fd.as_raw_fd(), name)?;
+            #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "can't choose congestion control {:?} on this platform",
+                    name
+                ),
+            ));
+        }
+        if self.transparent {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            sys::set_transparent(fd.as_raw_fd(), remote.is_ipv6())?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "IP_TRANSPARENT needs Linux",
+            ));
+        }
+        if let Some(key) = &self.md5_key {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            crate::sockopt::set_md5_key(fd.as_raw_fd(), remote.is_ipv6(), remote.ip(), key)?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            {
+                let _ = key;
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "TCP MD5 signatures need Linux",
+                ));
+            }
+        }
+        if let Some(local) = &self.local_addr {
+            sys::bind_ip(fd.as_raw_fd(), local)?;
+        }
+        sys::connect_ip(fd.as_raw_fd(), remote)?;
+        Ok(fd.into())
+    }
+
+    #[cfg(not(unix))]
+    fn connect_raw(&self, _: &IpSocketAddr) -> Result<TcpStream> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "connect options are not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn local_addr() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+
+        let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = probe.local_addr().unwrap();
+        drop(probe);
+
+        let stream = ConnectBuilder::new()
+            .local_addr(local)
+            .connect(target.as_str())
+            .unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, local.into());
+        assert_eq!(stream.peer_addr().unwrap().to_string(), target);
+
+        let e = ConnectBuilder::new()
+            .local_addr("[::1]:0".parse().unwrap())
+            .connect(target.as_str())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn interface() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let e = ConnectBuilder::new()
+            .interface("anysocket-none")
+            .connect(target.as_str())
+            .unwrap_err();
+        // ENODEV, or EPERM without the capability
+        assert_ne!(e.kind(), ErrorKind::InvalidInput);
+        assert!(crate::error::os_code(&e).is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn md5() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let key = b"anysocket";
+        if let Err(e) = listener.set_md5_key("127.0.0.1".parse().unwrap(), key) {
+            // kernels built without CONFIG_TCP_MD5SIG
+            assert_eq!(e.raw_os_error(), Some(92));
+            return;
+        }
+        ConnectBuilder::new()
+            .md5_key(key)
+            .connect(target.as_str())
+            .unwrap();
+        listener.accept().unwrap();
+        let e = ConnectBuilder::new()
+            .md5_key(&[0; 81])
+            .connect(target.as_str())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn transparent() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        match ConnectBuilder::new()
+            .transparent(true)
+            .connect(target.as_str())
+        {
+            Ok(_) => {
+                listener.accept().unwrap();
+            }
+            Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Datagram sockets
+
+use crate::AbstractAddr;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// Like UdpSocket
+///
+/// Either a [`UdpSocket`](https://doc.rust-lang.org/std/net/struct.UdpSocket.html)
+/// or [`UnixDatagram`](https://doc.rust-lang.org/std/os/unix/net/struct.UnixDatagram.html)
+///
+/// The IP-only options, such as multicast, fail with `Unsupported`
+/// on Unix sockets.
+#[derive(Debug)]
+pub enum AbstractDatagram {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl From<UdpSocket> for AbstractDatagram {
+    fn from(s: UdpSocket) -> AbstractDatagram {
+        AbstractDatagram::Udp(s)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixDatagram> for AbstractDatagram {
+    fn from(s: UnixDatagram) -> AbstractDatagram {
+        AbstractDatagram::Unix(s)
+    }
+}
+
+fn no_ip() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Unix datagram sockets have no IP options",
+    )
+}
+
+#[cfg(unix)]
+fn wrong_kind(addr: &AbstractAddr) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} is of the wrong kind for this socket", addr),
+    )
+}
+
+impl AbstractDatagram {
+    /// Parses `unix:/path/to/socket`, `unix:@abstract` (Linux only)
+    /// or anything accepted by `UdpSocket::bind`, optionally prefixed
+    /// with `udp:`
+    pub fn bind(addr: &str) -> Result<AbstractDatagram> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return UnixDatagram::bind_addr(&crate::unix_addr_from_str(path)?).map(Into::into);
+        }
+        #[cfg(not(unix))]
+        if addr.starts_with("unix:") {
+            return Err(crate::unix_unsupported());
+        }
+        UdpSocket::bind(addr.strip_prefix("udp:").unwrap_or(addr)).map(Into::into)
+    }
+
+    /// Binds to exactly `addr`
+    pub fn bind_addr(addr: &AbstractAddr) -> Result<AbstractDatagram> {
+        match addr {
+            AbstractAddr::Ip(a) => UdpSocket::bind(a).map(Into::into),
+            #[cfg(unix)]
+            AbstractAddr::Unix(a) => UnixDatagram::bind_addr(a).map(Into::into),
+            AbstractAddr::Name(host, port) => UdpSocket::bind((&host[..], *port)).map(Into::into),
+        }
+    }
+
+    /// A Unix socket bound to a unique abstract name chosen by the
+    /// kernel (Linux only), so a client can get replies without making
+    /// up a path for itself
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn unix_autobind() -> Result<AbstractDatagram> {
+        use std::os::unix::io::AsRawFd;
+        let s = UnixDatagram::unbound()?;
+        crate::sys::autobind(s.as_raw_fd())?;
+        Ok(s.into())
+    }
+
+    pub fn local_addr(&self) -> Result<AbstractAddr> {
+        match self {
+            Self::Udp(s) => s.local_addr().map(Into::into),
+            #[cfg(unix)]
+            Self::Unix(s) => s.local_addr().map(Into::into),
+        }
+    }
+
+    /// Sets the default destination for `send`, and only receives from it
+    pub fn connect(&self, addr: &AbstractAddr) -> Result<()> {
+        match (self, addr) {
+            (Self::Udp(s), AbstractAddr::Ip(a)) => s.connect(a),
+            (Self::Udp(s), AbstractAddr::Name(host, port)) => s.connect((&host[..], *port)),
+            #[cfg(unix)]
+            (Self::Unix(s), AbstractAddr::Unix(a)) => s.connect_addr(a),
+            #[cfg(unix)]
+            _ => Err(wrong_kind(addr)),
+        }
+    }
+
+    /// Sends a datagram to `addr`, which has to be the same kind of
+    /// address as the socket's own
+    ///
+    /// An unnamed Unix address, like that of a peer that didn't bind,
+    /// can't be sent to.
+    pub fn send_to(&self, buf: &[u8], addr: &AbstractAddr) -> Result<usize> {
+        match (self, addr) {
+            (Self::Udp(s), AbstractAddr::Ip(a)) => s.send_to(buf, a),
+            (Self::Udp(s), AbstractAddr::Name(host, port)) => s.send_to(buf, (&host[..], *port)),
+            #[cfg(unix)]
+            (Self::Unix(_), AbstractAddr::Unix(_)) if addr.is_unnamed() => Err(Error::new(
+                ErrorKind::NotFound,
+                "cannot send to an unnamed address",
+            )),
+            #[cfg(unix)]
+            (Self::Unix(s), AbstractAddr::Unix(a)) => s.send_to_addr(buf, a),
+            #[cfg(unix)]
+            _ => Err(wrong_kind(addr)),
+        }
+    }
+
+    /// Receives a datagram and the address it came from, which can be
+    /// passed straight back to [`send_to`](Self::send_to) to reply
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, AbstractAddr)> {
+        match self {
+            Self::Udp(s) => s.recv_from(buf).map(|(n, a)| (n, a.into())),
+            #[cfg(unix)]
+            Self::Unix(s) => s.recv_from(buf).map(|(n, a)| (n, a.into())),
+        }
+    }
+
+    pub fn send(&self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Self::Udp(s) => s.send(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.send(buf),
+        }
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::Udp(s) => s.recv(buf),
+            #[cfg(unix)]
+            Self::Unix(s) => s.recv(buf),
+        }
+    }
+
+    /// Sends each of `bufs` as a datagram to the connected peer, giving
+    /// how many were sent
+    ///
+    /// Linux does this in one `sendmmsg` call; elsewhere it is a loop
+    /// that stops at the first failure after something was sent.
+    pub fn send_batch(&self, bufs: &[&[u8]]) -> Result<usize> {
+        if bufs.is_empty() {
+            return Ok(0);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::unix::io::AsRawFd;
+            let fd = match self {
+                Self::Udp(s) => s.as_raw_fd(),
+                Self::Unix(s) => s.as_raw_fd(),
+            };
+            crate::sys::send_mmsg(fd, bufs)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            for (i, buf) in bufs.iter().enumerate() {
+                if let Err(e) = self.send(buf) {
+                    return if i == 0 { Err(e) } else { Ok(i) };
+                }
+            }
+            Ok(bufs.len())
+        }
+    }
+
+    /// Receives datagrams into `bufs`, storing each one's length in
+    /// `lens`, and gives how many arrived
+    ///
+    /// This waits for the first datagram only. Linux then takes any
+    /// others already queued in the same `recvmmsg` call; elsewhere only
+    /// one is received at a time.
+    pub fn recv_batch(&self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> Result<usize> {
+        if bufs.is_empty() || lens.is_empty() {
+            return Ok(0);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::unix::io::AsRawFd;
+            let fd = match self {
+                Self::Udp(s) => s.as_raw_fd(),
+                Self::Unix(s) => s.as_raw_fd(),
+            };
+            crate::sys::recv_mmsg(fd, bufs, lens)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            lens[0] = self.recv(bufs[0])?;
+            Ok(1)
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Self::Udp(s) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        match self {
+            Self::Udp(s) => s.set_read_timeout(dur),
+            #[cfg(unix)]
+            Self::Unix(s) => s.set_read_timeout(dur),
+        }
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        match self {
+            Self::Udp(s) => s.set_write_timeout(dur),
+            #[cfg(unix)]
+            Self::Unix(s) => s.set_write_timeout(dur),
+        }
+    }
+
+    pub fn take_error(&self) -> Result<Option<Error>> {
+        match self {
+            Self::Udp(s) => s.take_error(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.take_error(),
+        }
+    }
+
+    pub(crate) fn udp(&self) -> Result<&UdpSocket> {
+        match self {
+            Self::Udp(s) => Ok(s),
+            #[cfg(unix)]
+            Self::Unix(_) => Err(no_ip()),
+        }
+    }
+
+    /// Whether datagrams may be sent to broadcast addresses
+    pub fn set_broadcast(&self, on: bool) -> Result<()> {
+        self.udp()?.set_broadcast(on)
+    }
+
+    pub fn broadcast(&self) -> Result<bool> {
+        self.udp()?.broadcast()
+    }
+
+    /// Receives datagrams sent to the group `multiaddr` on the
+    /// interface with the address `interface` (`0.0.0.0` for any)
+    pub fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+        self.udp()?.join_multicast_v4(&multiaddr, &interface)
+    }
+
+    /// Receives datagrams sent to the group `multiaddr` on the
+    /// interface with index `interface` (0 for any)
+    pub fn join_multicast_v6(&self, multiaddr: Ipv6Addr, interface: u32) -> Result<()> {
+        self.udp()?.join_multicast_v6(&multiaddr, interface)
+    }
+
+    pub fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+        self.udp()?.leave_multicast_v4(&multiaddr, &interface)
+    }
+
+    pub fn leave_multicast_v6(&self, multiaddr: Ipv6Addr, interface: u32) -> Result<()> {
+        self.udp()?.leave_multicast_v6(&multiaddr, interface)
+    }
+
+    /// Whether multicast datagrams sent from here are also delivered here
+    pub fn set_multicast_loop_v4(&self, on: bool) -> Result<()> {
+        self.udp()?.set_multicast_loop_v4(on)
+    }
+
+    pub fn set_multicast_loop_v6(&self, on: bool) -> Result<()> {
+        self.udp()?.set_multicast_loop_v6(on)
+    }
+
+    /// How many routers multicast datagrams may pass through
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> Result<()> {
+        self.udp()?.set_multicast_ttl_v4(ttl)
+    }
+
+    /// Sends multicast datagrams out of the interface with the
+    /// address `interface`, instead of the one the routing table picks
+    pub fn set_multicast_if_v4(&self, interface: Ipv4Addr) -> Result<()> {
+        let s = self.udp()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            crate::sys::set_multicast_if_v4(s.as_raw_fd(), interface)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (s, interface);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "choosing the multicast interface is not supported on this platform",
+            ))
+        }
+    }
+
+    /// Sends multicast datagrams out of the interface with index `interface`
+    pub fn set_multicast_if_v6(&self, interface: u32) -> Result<()> {
+        let s = self.udp()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            crate::sys::set_multicast_if_v6(s.as_raw_fd(), interface)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (s, interface);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "choosing the multicast interface is not supported on this platform",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multicast() {
+        let group = Ipv4Addr::new(239, 255, 77, 1);
+        let rx = AbstractDatagram::bind("udp:0.0.0.0:0").unwrap();
+        let port = rx.local_addr().unwrap().port().unwrap();
+        rx.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        if rx.join_multicast_v4(group, Ipv4Addr::LOCALHOST).is_err() {
+            // no multicast-capable loopback in this environment
+            return;
+        }
+        let tx = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        tx.set_multicast_if_v4(Ipv4Addr::LOCALHOST).unwrap();
+        tx.set_multicast_loop_v4(true).unwrap();
+        tx.set_multicast_ttl_v4(1).unwrap();
+        tx.connect(&std::net::SocketAddr::from((group, port)).into())
+            .unwrap();
+        if tx.send(b"hello").is_err() {
+            return;
+        }
+        let mut buf = [0u8; 16];
+        if let Ok(n) = rx.recv(&mut buf) {
+            assert_eq!(&buf[..n], b"hello");
+        }
+        rx.leave_multicast_v4(group, Ipv4Addr::LOCALHOST).unwrap();
+    }
+
+    #[test]
+    fn send_recv() {
+        let server = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        let client = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"ping", &server.local_addr().unwrap())
+            .unwrap();
+        let mut buf = [0u8; 8];
+        let (n, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+        server.send_to(b"pong", &from).unwrap();
+        assert_eq!(client.recv(&mut buf).unwrap(), 4);
+
+        #[cfg(unix)]
+        {
+            let (a, b) = UnixDatagram::pair().unwrap();
+            let (a, b) = (AbstractDatagram::from(a), AbstractDatagram::from(b));
+            a.send(b"x").unwrap();
+            let (_, from) = b.recv_from(&mut buf).unwrap();
+            assert!(from.is_unnamed());
+            let e = b.send_to(b"y", &from).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::NotFound);
+            let e = b.send_to(b"y", &server.local_addr().unwrap()).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("unix:@anysocket-dgram-{}", crate::random_u64());
+            let server = AbstractDatagram::bind(&name).unwrap();
+            let client = AbstractDatagram::unix_autobind().unwrap();
+            assert!(!client.local_addr().unwrap().is_unnamed());
+            client
+                .send_to(b"ping", &server.local_addr().unwrap())
+                .unwrap();
+            let (_, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(from, client.local_addr().unwrap());
+            server.send_to(b"pong", &from).unwrap();
+            assert_eq!(client.recv(&mut buf).unwrap(), 4);
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let rx = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        let tx = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        tx.connect(&rx.local_addr().unwrap()).unwrap();
+        assert_eq!(tx.send_batch(&[b"one", b"two", b"three"]).unwrap(), 3);
+
+        let mut got = 0;
+        let mut all = vec![];
+        while got < 3 {
+            let (mut a, mut b, mut c) = ([0u8; 8], [0u8; 8], [0u8; 8]);
+            let mut lens = [0; 3];
+            let n = rx
+                .recv_batch(&mut [&mut a, &mut b, &mut c], &mut lens)
+                .unwrap();
+            for (buf, len) in [a, b, c].iter().zip(lens).take(n) {
+                all.push(buf[..len].to_vec());
+            }
+            got += n;
+        }
+        assert_eq!(all, [&b"one"[..], b"two", b"three"]);
+    }
+
+    #[test]
+    fn broadcast() {
+        let s = AbstractDatagram::bind("127.0.0.1:0").unwrap();
+        s.set_broadcast(true).unwrap();
+        assert!(s.broadcast().unwrap());
+
+        #[cfg(unix)]
+        {
+            let (a, _) = UnixDatagram::pair().unwrap();
+            let a = AbstractDatagram::from(a);
+            let e = a.set_broadcast(true).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Unsupported);
+            let e = a.join_multicast_v4(Ipv4Addr::new(239, 255, 77, 1), Ipv4Addr::UNSPECIFIED);
+            assert_eq!(e.unwrap_err().kind(), ErrorKind::Unsupported);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Caching name resolution for string addresses
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::SocketAddr as IpSocketAddr;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+static GLOBAL: RwLock<Option<DnsCache>> = RwLock::new(None);
+
+/// Remembers what host names resolved to, so reconnecting to the same
+/// name doesn't go through the system resolver every time
+///
+/// The system resolver doesn't say how long its answers are valid, so
+/// entries are kept for a fixed `ttl`. An entry is also forgotten when
+/// none of its addresses could be connected to, in case the name has
+/// moved.
+///
+/// Once [`install`](Self::install)ed, string addresses given to
+/// `connect_any` use the cache. Cloning a `DnsCache` gives another
+/// handle to the same cache.
+#[derive(Debug, Clone)]
+pub struct DnsCache {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Vec<IpSocketAddr>, Instant)>>,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration) -> Self {
+        DnsCache {
+            shared: Arc::new(Shared {
+                ttl,
+                entries: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Makes this the cache used by `connect_any` on string addresses
+    pub fn install(&self) {
+        *GLOBAL.write().unwrap() = Some(self.clone());
+    }
+
+    /// Goes back to resolving every string address afresh
+    pub fn uninstall() {
+        *GLOBAL.write().unwrap() = None;
+    }
+
+    /// Resolves `addr`, such as `example.com:80`, like `ToSocketAddrs`
+    pub fn resolve(&self, addr: &str) -> Result<Vec<IpSocketAddr>> {
+        if let Ok(ip) = addr.parse::<IpSocketAddr>() {
+            return Ok(vec![ip]);
+        }
+        {
+            let entries = self.shared.entries.lock().unwrap();
+            if let Some((addrs, at)) = entries.get(addr) {
+                if at.elapsed() < self.shared.ttl {
+                    return Ok(addrs.clone());
+                }
+            }
+        }
+        let addrs: Vec<IpSocketAddr> = addr.to_socket_addrs()?.collect();
+        let mut entries = self.shared.entries.lock().unwrap();
+        let ttl = self.shared.ttl;
+        entries.retain(|_, (_, at)| at.elapsed() < ttl);
+        entries.insert(addr.to_owned(), (addrs.clone(), Instant::now()));
+        Ok(addrs)
+    }
+
+    /// Forgets what `addr` resolved to
+    pub fn forget(&self, addr: &str) {
+        self.shared.entries.lock().unwrap().remove(addr);
+    }
+
+    pub fn clear(&self) {
+        self.shared.entries.lock().unwrap().clear();
+    }
+}
+
+/// The installed cache, if any
+pub(crate) fn global() -> Option<DnsCache> {
+    GLOBAL.read().unwrap().clone()
+}
+
+/// Held by tests that install a cache, or that need none installed
+#[cfg(test)]
+pub(crate) fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn caches() {
+        let cache = DnsCache::new(Duration::from_secs(60));
+        let first = cache.resolve("localhost:80").unwrap();
+        assert!(!first.is_empty());
+        assert_eq!(cache.shared.entries.lock().unwrap().len(), 1);
+        assert_eq!(cache.resolve("localhost:80").unwrap(), first);
+        cache.forget("localhost:80");
+        assert!(cache.shared.entries.lock().unwrap().is_empty());
+
+        // literal addresses never need resolving
+        cache.resolve("127.0.0.1:80").unwrap();
+        assert!(cache.shared.entries.lock().unwrap().is_empty());
+
+        let expired = DnsCache::new(Duration::ZERO);
+        expired.resolve("localhost:80").unwrap();
+        expired.resolve("localhost:81").unwrap();
+        assert_eq!(expired.shared.entries.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn installed() {
+        let _lock = test_lock();
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let ip: IpSocketAddr =
+            std::convert::TryInto::try_into(listener.local_addr().unwrap()).unwrap();
+        // not a name the system resolver knows, so only the cache can answer
+        let name = format!("anysocket-cached.invalid:{}", ip.port());
+        let cache = DnsCache::new(Duration::from_millis(200));
+        let at = Instant::now();
+        cache
+            .shared
+            .entries
+            .lock()
+            .unwrap()
+            .insert(name.clone(), (vec![ip], at));
+        cache.install();
+
+        name.connect_any().unwrap();
+        name.connect_any().unwrap();
+        assert_eq!(cache.shared.entries.lock().unwrap()[&name].1, at);
+        listener.accept().unwrap();
+        listener.accept().unwrap();
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(name.connect_any().is_err());
+        DnsCache::uninstall();
+        assert!(global().is_none());
+    }
+}
@@ -0,0 +1,223 @@
+//! Errors that say what was being done, and to which address
+
+use crate::AbstractAddr;
+use std::fmt;
+use std::io;
+
+/// What failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Bind,
+    Connect,
+    Accept,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Bind => "bind",
+            Operation::Connect => "connect",
+            Operation::Accept => "accept",
+        })
+    }
+}
+
+/// An `io::Error` along with the operation and address it came from
+///
+/// Binding, connecting and accepting still return `io::Error`, with the
+/// same `kind()` the operating system reported, but the error carries one
+/// of these inside and includes it in its message, such as
+/// `connect to unix:/run/app.sock: Connection refused`.
+/// Use [`Error::from_io`] to get at the details.
+///
+/// The `io::Error` itself has no `raw_os_error()` then, so code checking
+/// for `EADDRINUSE` and the like asks [`Error::raw_os_error`] instead.
+/// That falls back to the `io::Error`'s own code when there's no context
+/// attached.
+#[derive(Debug)]
+pub struct Error {
+    operation: Operation,
+    target: String,
+    addr: Option<AbstractAddr>,
+    source: io::Error,
+}
+
+impl Error {
+    /// The context inside an `io::Error` returned by this crate, if it has any
+    pub fn from_io(e: &io::Error) -> Option<&Error> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// The address as it was given, such as `env:LISTEN` or `example.com:80`
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The address, if the failure was for an [`AbstractAddr`]
+    pub fn addr(&self) -> Option<&AbstractAddr> {
+        self.addr.as_ref()
+    }
+
+    /// The error from the operating system
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        self.source.kind()
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+}
+
+/// The operating system's code for `e`, whether or not it carries context
+pub(crate) fn os_code(e: &io::Error) -> Option<i32> {
+    Error::from_io(e).map_or_else(|| e.raw_os_error(), Error::raw_os_error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prep = match self.operation {
+            Operation::Bind => "to",
+            Operation::Connect => "to",
+            Operation::Accept => "on",
+        };
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.operation, prep, self.target, self.source
+        )
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// Adds context to errors that don't already have it
+pub(crate) fn context<T>(
+    operation: Operation,
+    target: &dyn fmt::Display,
+    r: io::Result<T>,
+) -> io::Result<T> {
+    r.map_err(|source| {
+        if Error::from_io(&source).is_some() {
+            return source;
+        }
+        Error {
+            operation,
+            target: target.to_string(),
+            addr: None,
+            source,
+        }
+        .into()
+    })
+}
+
+pub(crate) fn addr_context<T>(
+    operation: Operation,
+    addr: &AbstractAddr,
+    r: io::Result<T>,
+) -> io::Result<T> {
+    r.map_err(|source| {
+        if Error::from_io(&source).is_some() {
+            return source;
+        }
+        Error {
+            operation,
+            target: addr.to_string(),
+            addr: Some(addr.clone()),
+            source,
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn carries_address() {
+        let var = format!("ANYSOCKET_UNSET_{}", std::process::id());
+        let e = format!("env:{}", var).connect_any().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let ctx = Error::from_io(&e).unwrap();
+        assert_eq!(ctx.operation(), Operation::Connect);
+        assert_eq!(ctx.target(), format!("env:{}", var));
+        assert!(e
+            .to_string()
+            .starts_with(&format!("connect to env:{}: ", var)));
+
+        #[cfg(unix)]
+        {
+            let (a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+            let unnamed = AbstractAddr::Unix(a.local_addr().unwrap());
+            let e = unnamed.connect_any().unwrap_err();
+            assert_eq!(Error::from_io(&e).unwrap().addr(), Some(&unnamed));
+        }
+    }
+
+    #[test]
+    fn keeps_os_errors() {
+        let closed = "127.0.0.1:0".bind_any().unwrap().local_addr().unwrap();
+        let e = closed.connect_any().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(e
+            .to_string()
+            .starts_with(&format!("connect to {}: ", closed)));
+        let ctx = Error::from_io(&e).unwrap();
+        assert_eq!(ctx.addr(), Some(&closed));
+        assert!(ctx.raw_os_error().is_some());
+        assert_eq!(os_code(&e), ctx.raw_os_error());
+
+        let e = crate::Tcp(ip(&closed)).connect_any().unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with(&format!("connect to {}: ", closed)));
+        assert_eq!(Error::from_io(&e).unwrap().addr(), Some(&closed));
+        let e = crate::Tcp(&[ip(&closed), ip(&closed)][..])
+            .connect_any()
+            .unwrap_err();
+        assert_eq!(
+            Error::from_io(&e).unwrap().target(),
+            format!("{0}, {0}", closed)
+        );
+
+        let e = "127.0.0.1:1".connect_any().unwrap_err();
+        assert_eq!(Error::from_io(&e).unwrap().target(), "127.0.0.1:1");
+        assert!(Error::from_io(&e).unwrap().raw_os_error().is_some());
+
+        let taken = "127.0.0.1:0".bind_any().unwrap();
+        let e = taken.local_addr().unwrap().bind_any().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(Error::from_io(&e).unwrap().raw_os_error().is_some());
+        let e = crate::Tcp(ip(&taken.local_addr().unwrap()))
+            .bind_any()
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(e.to_string().starts_with("bind to "));
+    }
+
+    fn ip(a: &AbstractAddr) -> std::net::SocketAddr {
+        match a {
+            AbstractAddr::Ip(a) => *a,
+            _ => unreachable!(),
+        }
+    }
+}
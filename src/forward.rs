@@ -0,0 +1,84 @@
+//! Forwarding bytes between two streams
+
+use crate::AbstractStream;
+use std::io::Result;
+use std::net::Shutdown;
+
+/// Copies bytes both ways between `a` and `b` until each direction
+/// reaches its end, giving how many went from `a` to `b` and how many
+/// from `b` to `a`
+///
+/// When one side finishes sending, the other's writing half is shut
+/// down so it sees the end too, and the other direction carries on.
+/// An error in either direction shuts both streams down and is
+/// returned. On Linux the bytes are moved with `splice`, without
+/// coming up to userspace.
+///
+/// One direction runs on a new thread, so both streams must be
+/// possible to [`try_clone`](AbstractStream::try_clone), which
+/// websocket streams aren't.
+pub fn proxy(a: AbstractStream, b: AbstractStream) -> Result<(u64, u64)> {
+    let (a2, b2) = (a.try_clone()?, b.try_clone()?);
+    let back = std::thread::Builder::new().spawn(move || one_way(b2, a2))?;
+    let there = one_way(a, b);
+    let back = back
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("proxy thread panicked")));
+    Ok((there?, back?))
+}
+
+fn one_way(mut from: AbstractStream, mut to: AbstractStream) -> Result<u64> {
+    let result = copy(&mut from, &mut to);
+    if result.is_ok() {
+        let _ = to.shutdown(Shutdown::Write);
+    } else {
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+fn copy(from: &mut AbstractStream, to: &mut AbstractStream) -> Result<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if plain_socket(from) && plain_socket(to) {
+        if let Some(n) = crate::sys::splice_all(from.raw_fd(), to.raw_fd())? {
+            return Ok(n);
+        }
+    }
+    std::io::copy(from, to)
+}
+
+/// Whether the bytes on the descriptor are the stream's bytes, as
+/// they aren't for a WebSocket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn plain_socket(s: &AbstractStream) -> bool {
+    matches!(s, AbstractStream::Tcp(_) | AbstractStream::Unix(_))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn both_ways() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut client = listener.local_addr().unwrap().connect_any().unwrap();
+        let (front, _) = listener.accept().unwrap();
+        let (back, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let forwarder = std::thread::spawn(move || proxy(front, back.into()).unwrap());
+
+        client.write_all(b"request").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut got = vec![];
+        server.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"request");
+        server.write_all(b"response!").unwrap();
+        drop(server);
+        got.clear();
+        client.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"response!");
+        assert_eq!(forwarder.join().unwrap(), (7, 9));
+    }
+}
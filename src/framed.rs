@@ -0,0 +1,241 @@
+//! Length-prefixed messages
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Sends and receives whole messages, each prefixed by its length
+/// as a big-endian u32
+///
+/// Messages longer than `max_len` (16MiB by default) are refused in
+/// both directions, as a corrupt or hostile length would otherwise
+/// allocate up to 4GiB.
+///
+/// What arrives of a message before a read fails with `WouldBlock` or
+/// times out is kept, and the next `recv_msg` carries on from there.
+/// Likewise, a message that stops partway through being written for
+/// either reason has the rest kept, to go out before anything else on
+/// the next `send_msg` or [`flush`](Self::flush).
+#[derive(Debug)]
+pub struct Framed<S = AbstractStream> {
+    inner: S,
+    max_len: u32,
+    /// The part of the next message read so far, length included
+    received: Vec<u8>,
+    /// What is left to write of a message that was partly sent
+    unsent: Vec<u8>,
+}
+
+impl<S: Read + Write> Framed<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_len(inner, 16 << 20)
+    }
+
+    pub fn with_max_len(inner: S, max_len: u32) -> Self {
+        Framed {
+            inner,
+            max_len,
+            received: vec![],
+            unsent: vec![],
+        }
+    }
+
+    /// Writes `msg` as one message and flushes
+    ///
+    /// Whatever was left of an earlier message goes first. If that can't
+    /// all be written, or none of `msg` can, this fails and `msg` isn't
+    /// sent. Once some of `msg` was written it is sent in full, even if
+    /// the rest has to wait for `WouldBlock` or a timeout to pass.
+    pub fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        if msg.len() > self.max_len as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("message of {} bytes exceeds {}", msg.len(), self.max_len),
+            ));
+        }
+        self.write_unsent()?;
+        self.unsent
+            .extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        self.unsent.extend_from_slice(msg);
+        match self.write_unsent() {
+            Ok(()) => self.inner.flush(),
+            Err(e) if self.unsent.len() == msg.len() + 4 => {
+                self.unsent.clear();
+                Err(e)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes what is left of a partly sent message, then flushes
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_unsent()?;
+        self.inner.flush()
+    }
+
+    /// Writes as much of `unsent` as the stream takes, keeping the rest
+    fn write_unsent(&mut self) -> Result<()> {
+        let mut sent = 0;
+        let r = loop {
+            if sent == self.unsent.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.unsent[sent..]) {
+                Ok(0) => break Err(ErrorKind::WriteZero.into()),
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.unsent.drain(..sent);
+        r
+    }
+
+    /// Reads the next message
+    ///
+    /// Returns `None` if the peer closed the connection between messages.
+    pub fn recv_msg(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.fill(4)? {
+            return Ok(None);
+        }
+        let r = &self.received;
+        let len = u32::from_be_bytes([r[0], r[1], r[2], r[3]]);
+        if len > self.max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("message of {} bytes exceeds {}", len, self.max_len),
+            ));
+        }
+        if !self.fill(4 + len as usize)? {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let msg = self.received.split_off(4);
+        self.received.clear();
+        Ok(Some(msg))
+    }
+
+    /// Reads until `received` holds `want` bytes, giving false if the
+    /// peer closed the connection before any of them arrived
+    fn fill(&mut self, want: usize) -> Result<bool> {
+        while self.received.len() < want {
+            let got = self.received.len();
+            self.received.resize(want, 0);
+            let r = self.inner.read(&mut self.received[got..]);
+            // only what was read stays
+            self.received.truncate(got + r.as_ref().map_or(0, |&n| n));
+            match r {
+                Ok(0) if got == 0 => return Ok(false),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let mut f = Framed::new(Cursor::new(vec![]));
+        f.send_msg(b"hello").unwrap();
+        f.send_msg(b"").unwrap();
+        assert_eq!(f.get_ref().get_ref()[..4], [0, 0, 0, 5]);
+
+        let mut f = Framed::new(Cursor::new(f.into_inner().into_inner()));
+        assert_eq!(f.recv_msg().unwrap().unwrap(), b"hello");
+        assert_eq!(f.recv_msg().unwrap().unwrap(), b"");
+        assert!(f.recv_msg().unwrap().is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let mut f = Framed::with_max_len(Cursor::new(vec![0, 0, 1, 0, 1]), 255);
+        assert_eq!(f.recv_msg().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            f.send_msg(&[0; 256]).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        let mut f = Framed::new(Cursor::new(vec![0, 0, 0, 9, 1]));
+        assert_eq!(f.recv_msg().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    /// Gives and takes a byte at a time, with a `WouldBlock` before each
+    struct Trickle(Cursor<Vec<u8>>, bool, Vec<u8>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.2.extend_from_slice(&buf[..buf.len().min(1)]);
+            Ok(buf.len().min(1))
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resumes_after_would_block() {
+        let bytes = vec![0, 0, 0, 3, b'a', b'b', b'c', 0, 0, 0, 1, b'd'];
+        let mut f = Framed::new(Trickle(Cursor::new(bytes), false, vec![]));
+        let mut msgs = vec![];
+        while msgs.len() < 2 {
+            match f.recv_msg() {
+                Ok(msg) => msgs.push(msg.unwrap()),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(msgs, [&b"abc"[..], b"d"]);
+        assert_eq!(f.recv_msg().unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert!(f.recv_msg().unwrap().is_none());
+    }
+
+    #[test]
+    fn finishes_partial_sends() {
+        let mut f = Framed::new(Trickle(Cursor::new(vec![]), false, vec![]));
+        // nothing written, so nothing sent
+        assert_eq!(f.send_msg(b"ab").unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert!(f.get_ref().2.is_empty());
+        // one byte went, so the rest will too
+        f.send_msg(b"ab").unwrap();
+        assert_eq!(f.get_ref().2, [0]);
+        // which has to finish before another message can start
+        assert_eq!(f.send_msg(b"c").unwrap_err().kind(), ErrorKind::WouldBlock);
+        while f.flush().is_err() {}
+        assert_eq!(f.get_ref().2, [0, 0, 0, 2, b'a', b'b']);
+        while f.send_msg(b"c").is_err() {}
+        while f.flush().is_err() {}
+        assert_eq!(f.get_ref().2[6..], [0, 0, 0, 1, b'c']);
+    }
+}
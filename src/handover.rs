@@ -0,0 +1,154 @@
+//! Handing listening sockets to a replacement process (Linux only)
+//!
+//! The old process starts the new one with
+//! [`AbstractListener::hand_over`], which gives it one end of a Unix
+//! socket pair, named by the `ANYSOCKET_HANDOVER` environment variable.
+//! The listeners are sent over it with `SCM_RIGHTS`, and the new process
+//! picks them up with [`AbstractListener::from_handover`] and answers
+//! with a byte once it has them. From then on both processes are
+//! accepting on the same sockets, so the old one can drain and exit
+//! without any connection being refused.
+
+use crate::ancillary;
+use crate::inherit::pass_fd;
+use crate::{AbstractListener, ControlMessage, ReceivedControl};
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Read, Result, Write};
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command};
+
+const ENV: &str = "ANYSOCKET_HANDOVER";
+/// The most descriptors Linux passes in one message
+const MAX_FDS: usize = 253;
+
+fn send_listeners(mut control: &UnixStream, listeners: &[&AbstractListener]) -> Result<()> {
+    if listeners.len() > MAX_FDS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("can hand over at most {} listeners", MAX_FDS),
+        ));
+    }
+    let fds: Vec<_> = listeners
+        .iter()
+        .map(|l| match l {
+            AbstractListener::Tcp(l) => l.as_fd(),
+            AbstractListener::Unix(l) => l.as_fd(),
+        })
+        .collect();
+    let count = (listeners.len() as u32).to_be_bytes();
+    ancillary::send(
+        control.as_raw_fd(),
+        &[IoSlice::new(&count)],
+        &[ControlMessage::Fds(&fds)],
+    )?;
+    let mut ack = [0u8];
+    if control.read(&mut ack)? == 0 {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "the new process went away before taking the listeners",
+        ));
+    }
+    Ok(())
+}
+
+fn recv_listeners(mut control: &UnixStream) -> Result<Vec<AbstractListener>> {
+    let mut count = [0u8; 4];
+    let got = ancillary::recv(
+        control.as_raw_fd(),
+        &mut [IoSliceMut::new(&mut count)],
+        32 + 4 * MAX_FDS,
+    )?;
+    if got.len != 4 || got.control_truncated {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "malformed listener handover",
+        ));
+    }
+    let mut fds = vec![];
+    for c in got.control {
+        if let ReceivedControl::Fds(f) = c {
+            fds.extend(f);
+        }
+    }
+    let count = u32::from_be_bytes(count) as usize;
+    if fds.len() != count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("expected {} listeners, got {}", count, fds.len()),
+        ));
+    }
+    let listeners = fds
+        .into_iter()
+        .map(AbstractListener::from_listening_fd)
+        .collect::<Result<Vec<_>>>()?;
+    control.write_all(b"k")?;
+    Ok(listeners)
+}
+
+impl AbstractListener {
+    /// Spawns `cmd` and hands it `listeners`, returning once it has
+    /// picked them up with [`from_handover`](Self::from_handover)
+    ///
+    /// The listeners stay open here too; close them once the new process
+    /// is up, after draining whatever connections are still running.
+    pub fn hand_over(listeners: &[&AbstractListener], cmd: &mut Command) -> Result<Child> {
+        let (ours, theirs) = UnixStream::pair()?;
+        let fd = theirs.as_raw_fd();
+        cmd.env(ENV, fd.to_string());
+        pass_fd(fd, cmd, fd);
+        let mut child = cmd.spawn()?;
+        drop(theirs);
+        match send_listeners(&ours, listeners) {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes the listeners handed over by the process that started
+    /// this one, in the order they were given to
+    /// [`hand_over`](Self::hand_over)
+    ///
+    /// Fails with `NotFound` if this process wasn't started that way,
+    /// and can only succeed once.
+    pub fn from_handover() -> Result<Vec<AbstractListener>> {
+        let fd: RawFd = std::env::var(ENV)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no listeners were handed over"))?;
+        std::env::remove_var(ENV);
+        crate::sys::set_inheritable(fd, false)?;
+        // the parent gave us this descriptor for nothing else
+        let control = unsafe { OwnedFd::from_raw_fd(fd) };
+        recv_listeners(&UnixStream::from(control))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn listeners_cross_over() {
+        let tcp = "127.0.0.1:0".bind_any().unwrap();
+        let (unix, path) = AbstractListener::bind_temp_unix("anysocket-test").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        let receiver = std::thread::spawn(move || recv_listeners(&b).unwrap());
+        send_listeners(&a, &[&tcp, &unix]).unwrap();
+        let got = receiver.join().unwrap();
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].local_addr().unwrap(), addr);
+        assert!(matches!(got[1], AbstractListener::Unix(_)));
+        std::fs::remove_dir_all(path.unix_path().unwrap().parent().unwrap()).unwrap();
+
+        assert_eq!(
+            AbstractListener::from_handover().unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
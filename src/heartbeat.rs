@@ -0,0 +1,300 @@
+//! Application level keepalives
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::time::{Duration, Instant};
+
+const DATA: u8 = 0;
+const HEARTBEAT: u8 = 1;
+
+/// Sends heartbeats over an otherwise idle connection and notices
+/// when the peer has gone silent
+///
+/// Both ends of the connection must use a `Heartbeat`, because written
+/// data is framed so the heartbeats can be told apart from it. A
+/// heartbeat is a single byte, and each `write` sends a 5 byte header
+/// (a zero byte and a big-endian u32 length) followed by the data.
+///
+/// Heartbeats are sent while blocked in `read`, or by calling
+/// [`tick`](Self::tick) from code that isn't reading. If nothing at all
+/// arrives from the peer for `silence`, `read` fails with `TimedOut`.
+///
+/// The wrapper manages the stream's read timeout itself. On a
+/// nonblocking stream `read` sends any heartbeat that is due and then
+/// fails with `WouldBlock`, rather than waiting. A frame header
+/// split across reads that time out is kept until the rest arrives, and
+/// frame bytes the stream couldn't take yet, after a `WouldBlock` or
+/// write timeout, are sent ahead of anything else.
+#[derive(Debug)]
+pub struct Heartbeat {
+    inner: AbstractStream,
+    interval: Duration,
+    silence: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    /// Bytes left in the data frame being read
+    remaining: u32,
+    header: [u8; 5],
+    header_len: usize,
+    /// Frame bytes the stream hasn't taken yet
+    pending: Vec<u8>,
+}
+
+impl Heartbeat {
+    /// Sends a heartbeat after `interval` without writing,
+    /// and gives up on the peer after `silence` without reading
+    pub fn new(inner: AbstractStream, interval: Duration, silence: Duration) -> Self {
+        let now = Instant::now();
+        Heartbeat {
+            inner,
+            interval,
+            silence,
+            last_sent: now,
+            last_received: now,
+            remaining: 0,
+            header: [0; 5],
+            header_len: 0,
+            pending: vec![],
+        }
+    }
+
+    /// Sends a heartbeat if nothing was written for `interval`,
+    /// and fails if the peer has been silent for too long
+    pub fn tick(&mut self) -> Result<()> {
+        if self.last_received.elapsed() >= self.silence {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("peer silent for {:?}", self.last_received.elapsed()),
+            ));
+        }
+        // a heartbeat mustn't land in the middle of a frame
+        self.write_pending()?;
+        if self.last_sent.elapsed() >= self.interval {
+            self.pending.push(HEARTBEAT);
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &AbstractStream {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> AbstractStream {
+        self.inner
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.inner.write(&self.pending)?;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write heartbeat frame",
+                ));
+            }
+            self.pending.drain(..n);
+            self.last_sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// How long `read` can block before there's something to do
+    fn wait(&self) -> Duration {
+        let send = self.interval.saturating_sub(self.last_sent.elapsed());
+        let give_up = self.silence.saturating_sub(self.last_received.elapsed());
+        send.min(give_up).max(Duration::from_millis(1))
+    }
+}
+
+impl Read for Heartbeat {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            self.inner.set_read_timeout(Some(self.wait()))?;
+            let r = if self.remaining > 0 {
+                let want = buf.len().min(self.remaining as usize);
+                self.inner.read(&mut buf[..want])
+            } else {
+                let want = if self.header_len == 0 { 1 } else { 5 };
+                self.inner.read(&mut self.header[self.header_len..want])
+            };
+            match r {
+                Ok(0) if self.remaining == 0 && self.header_len == 0 => return Ok(0),
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed inside a heartbeat frame",
+                    ))
+                }
+                Ok(n) => {
+                    self.last_received = Instant::now();
+                    if self.remaining > 0 {
+                        self.remaining -= n as u32;
+                        return Ok(n);
+                    }
+                    self.header_len += n;
+                    match self.header[0] {
+                        HEARTBEAT => self.header_len = 0,
+                        DATA if self.header_len == 5 => {
+                            let mut len = [0u8; 4];
+                            len.copy_from_slice(&self.header[1..]);
+                            self.remaining = u32::from_be_bytes(len);
+                            self.header_len = 0;
+                        }
+                        DATA => {}
+                        t => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("unknown heartbeat frame type {}", t),
+                            ))
+                        }
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    self.tick()?;
+                    // the read timeout only applies to blocking streams
+                    if e.kind() == ErrorKind::WouldBlock && self.inner.is_nonblocking()? {
+                        return Err(e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for Heartbeat {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.write_pending()?;
+        let len = buf.len().min(u32::MAX as usize);
+        self.pending.push(DATA);
+        self.pending.extend_from_slice(&(len as u32).to_be_bytes());
+        self.pending.extend_from_slice(&buf[..len]);
+        // buf is taken either way, what's left goes out next time
+        match self.write_pending() {
+            Err(e) if !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Err(e),
+            _ => Ok(len),
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    fn pair() -> (AbstractStream, AbstractStream) {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let client = listener.local_addr().unwrap().connect_any().unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn keeps_alive() {
+        let (client, server) = pair();
+        let ms = Duration::from_millis;
+        let (done_tx, done) = std::sync::mpsc::channel::<()>();
+        let t = std::thread::spawn(move || {
+            let mut client = Heartbeat::new(client, ms(10), ms(1000));
+            for _ in 0..10 {
+                std::thread::sleep(ms(10));
+                client.tick().unwrap();
+            }
+            client.write_all(b"hello").unwrap();
+            // closing with the server's heartbeats unread would reset the connection
+            let _ = done.recv();
+        });
+        let mut server = Heartbeat::new(server, ms(10), ms(50));
+        let mut got = [0u8; 5];
+        server.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"hello");
+        drop(done_tx);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn split_header() {
+        let (mut client, server) = pair();
+        let ms = Duration::from_millis;
+        client.write_all(&[DATA, 0, 0]).unwrap();
+        let t = std::thread::spawn(move || {
+            // long enough for the server's reads to time out a few times
+            std::thread::sleep(ms(50));
+            client.write_all(&[0, 2, b'h', b'i']).unwrap();
+            client
+        });
+        let mut server = Heartbeat::new(server, ms(10), ms(1000));
+        let mut got = [0u8; 2];
+        server.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"hi");
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn keeps_unsent_frame() {
+        let (client, server) = pair();
+        let ms = Duration::from_millis;
+        client.set_nonblocking(true).unwrap();
+        let mut client = Heartbeat::new(client, ms(1000), ms(1000));
+        let data: Vec<u8> = (0..8 << 20).map(|i| i as u8).collect();
+        // more than the socket buffers hold, so some of it has to wait
+        assert_eq!(client.write(&data).unwrap(), data.len());
+        assert!(!client.pending.is_empty());
+        let expected = data.clone();
+        let t = std::thread::spawn(move || {
+            let mut server = Heartbeat::new(server, ms(1000), ms(5000));
+            let mut got = vec![0u8; expected.len()];
+            server.read_exact(&mut got).unwrap();
+            assert!(got == expected);
+            server
+        });
+        loop {
+            match client.flush() {
+                Ok(()) => break,
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+            std::thread::sleep(ms(1));
+        }
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn nonblocking_read() {
+        let (mut client, server) = pair();
+        let ms = Duration::from_millis;
+        server.set_nonblocking(true).unwrap();
+        let mut server = Heartbeat::new(server, ms(10), ms(1000));
+        std::thread::sleep(ms(20));
+        let started = Instant::now();
+        let e = server.read(&mut [0u8; 4]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        assert!(started.elapsed() < ms(500));
+        // the heartbeat that was due still went out
+        let mut b = [0u8];
+        client.read_exact(&mut b).unwrap();
+        assert_eq!(b[0], HEARTBEAT);
+    }
+
+    #[test]
+    fn silent_peer() {
+        let (mut client, server) = pair();
+        let ms = Duration::from_millis;
+        let mut server = Heartbeat::new(server, ms(10), ms(50));
+        let e = server.read(&mut [0u8; 4]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        let mut b = [0u8];
+        client.read_exact(&mut b).unwrap();
+        assert_eq!(b[0], HEARTBEAT);
+    }
+}
@@ -0,0 +1,147 @@
+//! Closing connections that have gone quiet
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::time::{Duration, Instant};
+
+/// Closes the connection once no bytes have been read or written
+/// for `timeout`
+///
+/// The read or write that notices fails with `TimedOut`, the stream is
+/// shut down in both directions, and every later call fails the same way.
+/// Unlike a plain read timeout, time spent writing keeps a connection
+/// alive even if the peer sends nothing.
+///
+/// The wrapper manages the stream's read and write timeouts itself. On
+/// a nonblocking stream, reads and writes that would block fail with
+/// `WouldBlock` as usual, until the connection has been idle too long.
+#[derive(Debug)]
+pub struct IdleTimeout {
+    inner: AbstractStream,
+    timeout: Duration,
+    last_activity: Instant,
+    expired: bool,
+}
+
+impl IdleTimeout {
+    pub fn new(inner: AbstractStream, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            last_activity: Instant::now(),
+            expired: false,
+        }
+    }
+
+    /// Whether the connection was closed for being idle
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    pub fn get_ref(&self) -> &AbstractStream {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> AbstractStream {
+        self.inner
+    }
+
+    fn left(&mut self) -> Result<Duration> {
+        let left = self.timeout.saturating_sub(self.last_activity.elapsed());
+        if self.expired || left.is_zero() {
+            if !self.expired {
+                self.expired = true;
+                let _ = self.inner.shutdown(std::net::Shutdown::Both);
+            }
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("connection idle for {:?}", self.timeout),
+            ));
+        }
+        Ok(left)
+    }
+
+    fn io<T>(
+        &mut self,
+        set_timeout: fn(&AbstractStream, Option<Duration>) -> Result<()>,
+        mut op: impl FnMut(&mut AbstractStream) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            let left = self.left()?;
+            set_timeout(&self.inner, Some(left))?;
+            match op(&mut self.inner) {
+                Ok(t) => {
+                    self.last_activity = Instant::now();
+                    return Ok(t);
+                }
+                // the timeouts only apply to blocking streams
+                Err(e) if e.kind() == ErrorKind::WouldBlock && self.inner.is_nonblocking()? => {
+                    return Err(e)
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Read for IdleTimeout {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.io(AbstractStream::set_read_timeout, |s| s.read(buf))
+    }
+}
+
+impl Write for IdleTimeout {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.io(AbstractStream::set_write_timeout, |s| s.write(buf))
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.io(AbstractStream::set_write_timeout, |s| s.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn closes_when_idle() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut client = listener.local_addr().unwrap().connect_any().unwrap();
+        let mut server = IdleTimeout::new(listener.accept().unwrap().0, Duration::from_millis(30));
+
+        client.write_all(b"x").unwrap();
+        let mut b = [0u8];
+        server.read_exact(&mut b).unwrap();
+
+        let started = Instant::now();
+        let e = server.read(&mut b).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(25));
+        assert!(server.is_expired());
+        assert_eq!(server.write(b"y").unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(client.read(&mut b).unwrap(), 0);
+    }
+
+    #[test]
+    fn nonblocking() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let _client = listener.local_addr().unwrap().connect_any().unwrap();
+        let server = listener.accept().unwrap().0;
+        server.set_nonblocking(true).unwrap();
+        let mut server = IdleTimeout::new(server, Duration::from_millis(30));
+
+        let started = Instant::now();
+        let mut b = [0u8];
+        assert_eq!(
+            server.read(&mut b).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert!(started.elapsed() < Duration::from_millis(25));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(server.read(&mut b).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(server.is_expired());
+    }
+}
@@ -0,0 +1,140 @@
+//! Turning internationalized host names into the ASCII form DNS uses
+
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Result};
+
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+
+/// The dots that separate labels, as IDNA counts them
+const DOTS: [char; 4] = ['.', '\u{3002}', '\u{ff0e}', '\u{ff61}'];
+
+/// Converts the host of `host:port`, or a bare host, to its ASCII
+/// (punycode) form, leaving anything already ASCII as it is
+///
+/// Labels are lowercased, which is most of what IDNA's mapping step
+/// does for names people type; the rest of it isn't attempted.
+pub(crate) fn host_port(addr: &str) -> Result<Cow<'_, str>> {
+    if addr.is_ascii() {
+        return Ok(Cow::Borrowed(addr));
+    }
+    Ok(Cow::Owned(match addr.rsplit_once(':') {
+        Some((host, port)) => format!("{}:{}", to_ascii(host)?, port),
+        None => to_ascii(addr)?,
+    }))
+}
+
+/// Converts a host name to ASCII, a label at a time
+pub(crate) fn to_ascii(host: &str) -> Result<String> {
+    let mut labels = vec![];
+    for label in host.split(&DOTS[..]) {
+        if label.is_ascii() {
+            labels.push(label.to_owned());
+            continue;
+        }
+        let label = label.to_lowercase();
+        let encoded = format!("xn--{}", punycode(&label).ok_or_else(|| bad_name(host))?);
+        if encoded.len() > 63 {
+            return Err(bad_name(host));
+        }
+        labels.push(encoded);
+    }
+    Ok(labels.join("."))
+}
+
+fn bad_name(host: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("cannot encode host name {:?}", host),
+    )
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > (BASE - T_MIN) * T_MAX / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+/// RFC 3492's encoding, or `None` if the label is too long for it
+fn punycode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(|c| c as u32).collect();
+    let mut out: String = label.chars().filter(char::is_ascii).collect();
+    let basic = out.len() as u32;
+    if basic > 0 {
+        out.push('-');
+    }
+    let (mut n, mut delta, mut bias) = (128u32, 0u32, 72u32);
+    let mut handled = basic;
+    while (handled as usize) < input.len() {
+        let m = *input.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c != n {
+                continue;
+            }
+            let mut q = delta;
+            let mut k = BASE;
+            loop {
+                let t = if k <= bias {
+                    T_MIN
+                } else if k >= bias + T_MAX {
+                    T_MAX
+                } else {
+                    k - bias
+                };
+                if q < t {
+                    break;
+                }
+                out.push(digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            out.push(digit(q));
+            bias = adapt(delta, handled + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes() {
+        assert_eq!(to_ascii("Bücher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("例え。テスト").unwrap(), "xn--r8jz45g.xn--zckzah");
+        assert_eq!(
+            host_port("münchen.de:443").unwrap(),
+            "xn--mnchen-3ya.de:443"
+        );
+        assert!(matches!(
+            host_port("[::1]:80").unwrap(),
+            Cow::Borrowed("[::1]:80")
+        ));
+        assert!(to_ascii(&"ü".repeat(64)).is_err());
+    }
+}
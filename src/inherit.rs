@@ -0,0 +1,172 @@
+//! Handing sockets to child processes
+//!
+//! The parent either marks a socket inheritable or arranges for it to
+//! appear as a particular descriptor number in a [`Command`], and the
+//! child picks it up again with `from_inherited`. This is enough for
+//! pre-forking servers and for re-executing a new version of a binary
+//! without closing its listening socket.
+
+use crate::{sys, AbstractListener, AbstractStream};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+impl AbstractListener {
+    pub(crate) fn raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(l) => l.as_raw_fd(),
+            Self::Unix(l) => l.as_raw_fd(),
+        }
+    }
+
+    /// Whether the listener stays open in child processes started with exec
+    pub fn set_inheritable(&self, inheritable: bool) -> Result<()> {
+        sys::set_inheritable(self.raw_fd(), inheritable)
+    }
+
+    /// Arranges for the listener to be descriptor `child_fd`
+    /// in the process `cmd` spawns
+    ///
+    /// The listener itself isn't changed, but it must still be open
+    /// when `cmd` is spawned.
+    pub fn pass_to_child(&self, cmd: &mut Command, child_fd: RawFd) {
+        pass_fd(self.raw_fd(), cmd, child_fd)
+    }
+
+    /// Takes over a listening socket passed in by the parent process
+    ///
+    /// The descriptor is marked close-on-exec again, so it isn't passed
+    /// further down to this process's own children.
+    ///
+    /// If `fd` turns out to be the wrong kind of socket, it is closed.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor that nothing else in this
+    /// process owns or will close.
+    pub unsafe fn from_inherited(fd: RawFd) -> Result<AbstractListener> {
+        let owned = OwnedFd::from_raw_fd(fd);
+        sys::set_inheritable(fd, false)?;
+        Self::from_listening_fd(owned)
+    }
+
+    /// Takes a descriptor known to be a socket, checking it's listening
+    pub(crate) fn from_listening_fd(owned: OwnedFd) -> Result<AbstractListener> {
+        let fd = owned.as_raw_fd();
+        if !sys::is_listening(fd)? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("descriptor {} is not a listening socket", fd),
+            ));
+        }
+        let unix = UnixListener::from(owned);
+        if unix.local_addr().is_ok() {
+            return Ok(unix.into());
+        }
+        let tcp = TcpListener::from(OwnedFd::from(unix));
+        tcp.local_addr()?;
+        Ok(tcp.into())
+    }
+}
+
+impl AbstractStream {
+    pub(crate) fn raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(l) => l.as_raw_fd(),
+            Self::Unix(l) => l.as_raw_fd(),
+            Self::WebSocket(l) => l.get_ref().as_raw_fd(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.as_raw_fd(),
+            Self::Pipe(l) => l.as_raw_fd(),
+        }
+    }
+
+    /// Whether the stream stays open in child processes started with exec
+    pub fn set_inheritable(&self, inheritable: bool) -> Result<()> {
+        sys::set_inheritable(self.raw_fd(), inheritable)
+    }
+
+    /// Arranges for the stream to be descriptor `child_fd`
+    /// in the process `cmd` spawns
+    ///
+    /// A websocket stream is passed as its Tcp connection, the child
+    /// can't resume the websocket protocol from it.
+    pub fn pass_to_child(&self, cmd: &mut Command, child_fd: RawFd) {
+        pass_fd(self.raw_fd(), cmd, child_fd)
+    }
+
+    /// Takes over a connected socket passed in by the parent process
+    ///
+    /// If `fd` turns out to be the wrong kind of socket, it is closed.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor that nothing else in this
+    /// process owns or will close.
+    pub unsafe fn from_inherited(fd: RawFd) -> Result<AbstractStream> {
+        let owned = OwnedFd::from_raw_fd(fd);
+        if sys::is_listening(fd)? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("inherited descriptor {} is a listening socket", fd),
+            ));
+        }
+        sys::set_inheritable(fd, false)?;
+        let unix = UnixStream::from(owned);
+        if unix.local_addr().is_ok() {
+            return Ok(unix.into());
+        }
+        let tcp = TcpStream::from(OwnedFd::from(unix));
+        tcp.local_addr()?;
+        Ok(tcp.into())
+    }
+}
+
+pub(crate) fn pass_fd(fd: RawFd, cmd: &mut Command, child_fd: RawFd) {
+    // Safety: only async-signal-safe calls are made in the child
+    unsafe {
+        cmd.pre_exec(move || sys::move_fd_for_exec(fd, child_fd));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn child_sees_fd() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("test -e /proc/self/fd/7 || test -e /dev/fd/7");
+        listener.pass_to_child(&mut cmd, 7);
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn round_trip() {
+        use std::os::unix::io::IntoRawFd;
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = match listener {
+            AbstractListener::Tcp(l) => l.into_raw_fd(),
+            _ => unreachable!(),
+        };
+        let listener = unsafe { AbstractListener::from_inherited(fd) }.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let (a, _b) = UnixStream::pair().unwrap();
+        let fd = a.into_raw_fd();
+        let e = unsafe { AbstractListener::from_inherited(fd) }.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let fd = UnixStream::pair().unwrap().0.into_raw_fd();
+        assert!(matches!(
+            unsafe { AbstractStream::from_inherited(fd) },
+            Ok(AbstractStream::Unix(_))
+        ));
+    }
+}
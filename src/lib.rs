@@ -1,7 +1,11 @@
 use std::io::Result;
+use std::net::IpAddr;
 use std::net::SocketAddr as IpSocketAddr;
+use std::net::SocketAddrV4;
+use std::net::SocketAddrV6;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::net::ToSocketAddrs;
 #[cfg(unix)]
 use std::os::unix::net::SocketAddr as UnixSocketAddr;
 #[cfg(unix)]
@@ -9,114 +13,899 @@ use std::os::unix::net::UnixListener;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod ancillary;
+mod builder;
+mod chaos;
+mod connect;
+mod datagram;
+mod dns;
+mod error;
+mod forward;
+mod framed;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod handover;
+mod heartbeat;
+mod idle;
+mod idna;
+#[cfg(unix)]
+mod inherit;
+mod lines;
+#[cfg(unix)]
+mod listener_set;
+mod measure;
+mod oob;
+#[cfg(unix)]
+mod pipe;
+mod pool;
+mod proxy;
+mod race;
+mod ready;
+mod retry;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod reuseport;
+mod serve;
+mod shaping;
+#[cfg(all(feature = "shm", target_os = "linux"))]
+mod shm;
+#[cfg(feature = "signals")]
+mod signal;
+mod sockopt;
+#[cfg(unix)]
+mod srv;
+#[cfg(unix)]
+mod sys;
+mod tcp_info;
+mod transform;
+mod ws;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use ancillary::{ControlMessage, Credentials, ReceivedControl, ReceivedMsg};
+pub use builder::{ConfiguredListener, ListenerBuilder, ListenerConfig};
+pub use chaos::Chaos;
+pub use connect::ConnectBuilder;
+pub use datagram::AbstractDatagram;
+pub use dns::DnsCache;
+pub use error::{Error, Operation};
+pub use forward::proxy;
+pub use framed::Framed;
+pub use heartbeat::Heartbeat;
+pub use idle::IdleTimeout;
+pub use lines::Lines;
+#[cfg(unix)]
+pub use listener_set::{AbstractListenerSet, InterfaceAddr};
+pub use measure::{Measured, Measurement, Ops};
+#[cfg(unix)]
+pub use pipe::PipeStream;
+pub use pool::{AbstractPool, PooledStream};
+pub use proxy::{Proxy, Via};
+pub use race::Race;
+pub use ready::{select, Selectable};
+pub use retry::RetryPolicy;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use reuseport::BpfInstruction;
+pub use serve::{Drain, Handler};
+pub use shaping::RateLimited;
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub use shm::ShmStream;
+#[cfg(unix)]
+pub use sockopt::SockRef;
+pub use tcp_info::TcpInfo;
+pub use transform::{Transform, Transformed};
+pub use ws::WebSocketStream;
+
 /// Like ToSocketAddrs
 pub trait AbstractToSocketAddrs {
     /// Like TcpListener::bind
     fn bind_any(&self) -> Result<AbstractListener>;
     /// Like TcpStream::connect
     fn connect_any(&self) -> Result<AbstractStream>;
+    /// Like connect_any, but tries again after failures according to `policy`
+    fn connect_any_retry(&self, policy: &RetryPolicy) -> Result<AbstractStream> {
+        policy.retry(|| self.connect_any())
+    }
+    /// Binds every address this stands for, which for a port range
+    /// such as `0.0.0.0:8000-8100` is each port that's free
+    fn bind_all(&self) -> Result<Vec<AbstractListener>> {
+        self.bind_any().map(|l| vec![l])
+    }
+    /// The IP addresses this stands for, for binding sockets that need
+    /// options set before `bind`; anything that isn't TCP gives
+    /// `Unsupported`
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "not a TCP address",
+        ))
+    }
 }
 
 impl AbstractToSocketAddrs for IpSocketAddr {
     fn bind_any(&self) -> Result<AbstractListener> {
-        TcpListener::bind(self).map(Into::into)
+        error::context(
+            Operation::Bind,
+            self,
+            TcpListener::bind(self).map(Into::into),
+        )
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        error::context(
+            Operation::Connect,
+            self,
+            TcpStream::connect(self).map(Into::into),
+        )
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Ok(vec![*self])
+    }
+}
+
+impl AbstractToSocketAddrs for SocketAddrV4 {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        error::context(
+            Operation::Bind,
+            self,
+            TcpListener::bind(self).map(Into::into),
+        )
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        error::context(
+            Operation::Connect,
+            self,
+            TcpStream::connect(self).map(Into::into),
+        )
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Ok(vec![(*self).into()])
+    }
+}
+
+impl AbstractToSocketAddrs for SocketAddrV6 {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        error::context(
+            Operation::Bind,
+            self,
+            TcpListener::bind(self).map(Into::into),
+        )
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        error::context(
+            Operation::Connect,
+            self,
+            TcpStream::connect(self).map(Into::into),
+        )
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Ok(vec![(*self).into()])
+    }
+}
+
+impl AbstractToSocketAddrs for (IpAddr, u16) {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        error::context(
+            Operation::Bind,
+            &format_args!("{}:{}", self.0, self.1),
+            TcpListener::bind(self).map(Into::into),
+        )
     }
 
     fn connect_any(&self) -> Result<AbstractStream> {
-        TcpStream::connect(self).map(Into::into)
+        error::context(
+            Operation::Connect,
+            &format_args!("{}:{}", self.0, self.1),
+            TcpStream::connect(self).map(Into::into),
+        )
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Ok(vec![(*self).into()])
+    }
+}
+
+/// Adapts anything that implements
+/// [`ToSocketAddrs`](https://doc.rust-lang.org/std/net/trait.ToSocketAddrs.html)
+///
+/// This always produces a Tcp socket, for example
+/// `Tcp(("example.com".to_string(), 80)).connect_any()`.
+#[derive(Debug, Clone, Copy)]
+pub struct Tcp<T>(pub T);
+
+impl<T: ToSocketAddrs> AbstractToSocketAddrs for Tcp<T> {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        let addrs = self.to_ip_addrs()?;
+        tcp_context(Operation::Bind, &addrs, TcpListener::bind(&addrs[..])).map(Into::into)
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        let addrs = self.to_ip_addrs()?;
+        tcp_context(Operation::Connect, &addrs, TcpStream::connect(&addrs[..])).map(Into::into)
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        Ok(self.0.to_socket_addrs()?.collect())
+    }
+}
+
+/// Says which of `addrs` a [`Tcp`] was trying, since what it was made
+/// from can't be shown
+fn tcp_context<T>(operation: Operation, addrs: &[IpSocketAddr], r: Result<T>) -> Result<T> {
+    match addrs {
+        [] => r,
+        [one] => error::addr_context(operation, &AbstractAddr::Ip(*one), r),
+        _ => {
+            let all: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+            error::context(operation, &all.join(", "), r)
+        }
     }
 }
 
 impl AbstractToSocketAddrs for (&str, u16) {
     fn bind_any(&self) -> Result<AbstractListener> {
-        TcpListener::bind(self).map(Into::into)
+        error::context(
+            Operation::Bind,
+            &format_args!("{}:{}", self.0, self.1),
+            idna::host_port(self.0)
+                .and_then(|host| TcpListener::bind((&host[..], self.1)))
+                .map(Into::into),
+        )
     }
 
     fn connect_any(&self) -> Result<AbstractStream> {
-        TcpStream::connect(self).map(Into::into)
+        error::context(
+            Operation::Connect,
+            &format_args!("{}:{}", self.0, self.1),
+            idna::host_port(self.0)
+                .and_then(|host| TcpStream::connect((&host[..], self.1)))
+                .map(Into::into),
+        )
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        let host = idna::host_port(self.0)?;
+        Ok((&host[..], self.1).to_socket_addrs()?.collect())
+    }
+}
+
+impl AbstractToSocketAddrs for (String, u16) {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        (self.0.as_str(), self.1).bind_any()
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        (self.0.as_str(), self.1).connect_any()
+    }
+
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        (self.0.as_str(), self.1).to_ip_addrs()
     }
 }
 
 #[cfg(unix)]
 impl AbstractToSocketAddrs for UnixSocketAddr {
     fn bind_any(&self) -> Result<AbstractListener> {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::AlreadyExists,
-            "cannot bind to an existing address",
-        ))
+        error::addr_context(
+            Operation::Bind,
+            &self.clone().into(),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "cannot bind to an existing address",
+            )),
+        )
     }
 
     fn connect_any(&self) -> Result<AbstractStream> {
-        if let Some(p) = self.as_pathname() {
-            UnixStream::connect(p).map(Into::into)
-        } else {
+        let r = if let UnixAddrKey::Unnamed = unix_addr_key(self) {
             Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
                 "cannot connect to unnamed address",
             ))
-        }
+        } else {
+            UnixStream::connect_addr(self).map(Into::into)
+        };
+        error::addr_context(Operation::Connect, &self.clone().into(), r)
     }
 }
 
+/// Parses `unix:/path/to/socket`, `unix:@abstract` (Linux only),
+/// `local:NAME`, `ws://host:port/path` (connecting only), `env:VARIABLE`
+/// or anything accepted by `TcpListener::bind`, optionally prefixed
+/// with `tcp:`.
+///
+/// In a `unix:` path or abstract name, `%` followed by two hex digits
+/// stands for that byte, so `unix:/tmp/my%20app%3Fv2.sock` is the path
+/// `/tmp/my app?v2.sock`. A `%` without them is left as it is.
+///
+/// A TCP address can give a range of ports to bind, as in
+/// `0.0.0.0:8000-8100`: `bind_any` takes the first that's free, which
+/// `local_addr` then tells, and `bind_all` takes every one that is.
+///
+/// A `local:` address is the Unix socket `NAME.sock` in
+/// `$XDG_RUNTIME_DIR`, or the temporary directory if that isn't set.
+/// Windows has no such sockets, so there it fails with `Unsupported`.
+///
+/// With the `shm` feature on Linux, connecting to `shm:NAME` pairs up
+/// with whichever other process connects to the same name, over
+/// shared memory; see `ShmStream`.
+///
+/// On Unix, connecting to `fifo:IN,OUT` reads from the named pipe `IN`
+/// and writes to `OUT`, as a [`PipeStream`]. `file:IN,OUT` does the
+/// same with plain files, creating `OUT`, and `file:IN` throws writes
+/// away, for replaying a captured session. `pty:` is the master side
+/// of a new pseudo-terminal, see [`PipeStream::open_pty`], and
+/// `exec:PROGRAM ARGS...` runs a program, split at whitespace with no
+/// quoting, to talk to over its stdin and stdout. With the `serial`
+/// feature, `serial:/dev/ttyUSB0?baud=115200` is a serial port in raw
+/// mode, the baud rate left as it is without `?baud=`.
+///
+/// On Unix, connecting to `srv:_service._proto.example.com` looks up
+/// its SRV records, asking the nameservers in `/etc/resolv.conf`, and
+/// tries the targets by priority, picking among equals by weight.
+///
+/// `either:ADDR|ADDR...` tries each address in turn, like a list of
+/// [`AbstractAddr`]s, for example `either:unix:/run/daemon.sock|localhost:7000`.
+///
+/// Host names that aren't ASCII are converted to punycode before
+/// they're resolved, as in `bücher.example:80`; errors still show
+/// them as given.
+///
+/// `connect_any` resolves host names each time, unless a [`DnsCache`]
+/// is installed. With [`Proxy::use_env`] on, TCP connections to
+/// `host:port` go through the proxy the environment names.
+///
+/// An `env:` address is replaced by the value of the environment variable,
+/// which is then parsed the same way. If the value is only a port number,
+/// `bind_any` listens on that port on all interfaces and `connect_any`
+/// connects to it on localhost.
 impl AbstractToSocketAddrs for str {
     fn bind_any(&self) -> Result<AbstractListener> {
-        #[cfg(unix)]
-        if self.starts_with("unix:") {
-            return UnixListener::bind(&self["unix:".len()..]).map(Into::into);
-        }
-        TcpListener::bind(self).map(Into::into)
+        error::context(Operation::Bind, &self, bind_str(self))
     }
     fn connect_any(&self) -> Result<AbstractStream> {
-        #[cfg(unix)]
-        if self.starts_with("unix:") {
-            return UnixStream::connect(&self["unix:".len()..]).map(Into::into);
+        error::context(Operation::Connect, &self, connect_str(self))
+    }
+    fn bind_all(&self) -> Result<Vec<AbstractListener>> {
+        if let Some(var) = self.strip_prefix("env:") {
+            return addr_from_env(var, "0.0.0.0")?.bind_all();
+        }
+        let tcp = self.strip_prefix("tcp:").unwrap_or(self);
+        let (host, ports) = match port_range(tcp) {
+            Some(range) => range?,
+            None => return self.bind_any().map(|l| vec![l]),
+        };
+        let mut bound = vec![];
+        for port in ports.clone() {
+            match TcpListener::bind(format!("{}:{}", host, port)) {
+                Ok(l) => bound.push(l.into()),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {}
+                Err(e) => return error::context(Operation::Bind, &self, Err(e)),
+            }
+        }
+        if bound.is_empty() {
+            return error::context(Operation::Bind, &self, Err(no_free_port(&ports)));
+        }
+        Ok(bound)
+    }
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        if let Some(var) = self.strip_prefix("env:") {
+            return addr_from_env(var, "0.0.0.0")?.to_ip_addrs();
+        }
+        let addr = idna::host_port(self.strip_prefix("tcp:").unwrap_or(self))?;
+        Ok(addr.to_socket_addrs()?.collect())
+    }
+}
+
+/// Splits `host:FIRST-LAST` into the host and its ports, or gives
+/// `None` for anything without a range, including other schemes
+fn port_range(addr: &str) -> Option<Result<(&str, std::ops::RangeInclusive<u16>)>> {
+    let (host, ports) = addr.rsplit_once(':')?;
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return None;
+    }
+    let (first, last) = ports.split_once('-')?;
+    let (first, last) = match (first.parse::<u16>(), last.parse::<u16>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last),
+        _ => {
+            return Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid port range {:?}", ports),
+            )))
+        }
+    };
+    Some(Ok((host, first..=last)))
+}
+
+fn no_free_port(ports: &std::ops::RangeInclusive<u16>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        format!("no free port in {}-{}", ports.start(), ports.end()),
+    )
+}
+
+fn bind_str(addr: &str) -> Result<AbstractListener> {
+    if let Some(list) = addr.strip_prefix("either:") {
+        return first_success(list.split('|'), "bind", |a| a.bind_any());
+    }
+    if let Some(var) = addr.strip_prefix("env:") {
+        return addr_from_env(var, "0.0.0.0")?.bind_any();
+    }
+    if addr.starts_with("ws://") || addr.starts_with("wss://") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "cannot listen on a websocket url",
+        ));
+    }
+    if addr.starts_with("shm:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "shared memory streams pair up by connecting, not listening",
+        ));
+    }
+    if ["fifo:", "file:", "pty:", "exec:", "serial:"]
+        .iter()
+        .any(|scheme| addr.starts_with(scheme))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "can only connect to pipes, files, terminals and commands",
+        ));
+    }
+    if addr.starts_with("srv:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "cannot listen on a service found through DNS",
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return UnixListener::bind_addr(&unix_addr_from_str(path)?).map(Into::into);
+    }
+    #[cfg(unix)]
+    if let Some(name) = addr.strip_prefix("local:") {
+        return UnixListener::bind(local_path(name)?).map(Into::into);
+    }
+    #[cfg(not(unix))]
+    if addr.starts_with("unix:") || addr.starts_with("local:") {
+        return Err(unix_unsupported());
+    }
+    let addr = idna::host_port(addr.strip_prefix("tcp:").unwrap_or(addr))?;
+    let addr = &addr[..];
+    if let Some(range) = port_range(addr) {
+        let (host, ports) = range?;
+        for port in ports.clone() {
+            match TcpListener::bind(format!("{}:{}", host, port)) {
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {}
+                r => return r.map(Into::into),
+            }
         }
-        TcpStream::connect(self).map(Into::into)
+        return Err(no_free_port(&ports));
     }
+    TcpListener::bind(addr).map(Into::into)
+}
+
+fn connect_str(addr: &str) -> Result<AbstractStream> {
+    if let Some(list) = addr.strip_prefix("either:") {
+        return first_success(list.split('|'), "connect", |a| a.connect_any());
+    }
+    if let Some(var) = addr.strip_prefix("env:") {
+        return addr_from_env(var, "127.0.0.1")?.connect_any();
+    }
+    if addr.starts_with("ws://") || addr.starts_with("wss://") {
+        return ws::connect(addr).map(Into::into);
+    }
+    #[cfg(all(feature = "shm", target_os = "linux"))]
+    if let Some(name) = addr.strip_prefix("shm:") {
+        return ShmStream::connect(name).map(Into::into);
+    }
+    #[cfg(not(all(feature = "shm", target_os = "linux")))]
+    if addr.starts_with("shm:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "shm: streams need the shm feature, on Linux",
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(paths) = addr.strip_prefix("fifo:") {
+        let (input, output) = paths.split_once(',').ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a fifo: address needs an input and an output path",
+            )
+        })?;
+        return PipeStream::open_fifos(input, output).map(Into::into);
+    }
+    #[cfg(unix)]
+    if let Some(paths) = addr.strip_prefix("file:") {
+        let (input, output) = match paths.split_once(',') {
+            Some((input, output)) => (input, Some(std::path::Path::new(output))),
+            None => (paths, None),
+        };
+        return PipeStream::open_files(input, output).map(Into::into);
+    }
+    #[cfg(unix)]
+    if addr == "pty:" {
+        return PipeStream::open_pty().map(Into::into);
+    }
+    #[cfg(all(unix, feature = "serial"))]
+    if let Some(port) = addr.strip_prefix("serial:") {
+        let (path, baud) = match port.split_once("?baud=") {
+            Some((path, baud)) => {
+                let baud = baud.parse().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("bad baud rate {:?}", baud),
+                    )
+                })?;
+                (path, Some(baud))
+            }
+            None => (port, None),
+        };
+        return PipeStream::open_serial(path, baud).map(Into::into);
+    }
+    #[cfg(not(all(unix, feature = "serial")))]
+    if addr.starts_with("serial:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "serial: ports need the serial feature, on Unix",
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(command) = addr.strip_prefix("exec:") {
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "an exec: address needs a command",
+            )
+        })?;
+        let mut cmd = std::process::Command::new(program);
+        cmd.args(words);
+        return PipeStream::spawn(&mut cmd).map(Into::into);
+    }
+    #[cfg(unix)]
+    if let Some(name) = addr.strip_prefix("srv:") {
+        return srv::connect(name);
+    }
+    #[cfg(not(unix))]
+    if addr.starts_with("srv:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "SRV lookups are only supported on Unix",
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return UnixStream::connect_addr(&unix_addr_from_str(path)?).map(Into::into);
+    }
+    #[cfg(unix)]
+    if let Some(name) = addr.strip_prefix("local:") {
+        return UnixStream::connect(local_path(name)?).map(Into::into);
+    }
+    #[cfg(not(unix))]
+    if addr.starts_with("unix:") || addr.starts_with("local:") {
+        return Err(unix_unsupported());
+    }
+    let addr = idna::host_port(addr.strip_prefix("tcp:").unwrap_or(addr))?;
+    let addr = &addr[..];
+    if let Some(proxy) = proxy::for_target(addr)? {
+        return proxy.connect(addr).map(Into::into);
+    }
+    if let Some(cache) = dns::global() {
+        let r = TcpStream::connect(&cache.resolve(addr)?[..]);
+        if r.is_err() {
+            cache.forget(addr);
+        }
+        return r.map(Into::into);
+    }
+    TcpStream::connect(addr).map(Into::into)
 }
 
 impl AbstractToSocketAddrs for &str {
     fn bind_any(&self) -> Result<AbstractListener> {
-        #[cfg(unix)]
-        if self.starts_with("unix:") {
-            return UnixListener::bind(&self["unix:".len()..]).map(Into::into);
-        }
-        TcpListener::bind(self).map(Into::into)
+        (**self).bind_any()
     }
     fn connect_any(&self) -> Result<AbstractStream> {
-        #[cfg(unix)]
-        if self.starts_with("unix:") {
-            return UnixStream::connect(&self["unix:".len()..]).map(Into::into);
+        (**self).connect_any()
+    }
+    fn bind_all(&self) -> Result<Vec<AbstractListener>> {
+        (**self).bind_all()
+    }
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        (**self).to_ip_addrs()
+    }
+}
+
+/// The address stored in the environment variable `var`, with a bare
+/// port number turned into an address on `host`
+fn addr_from_env(var: &str, host: &str) -> Result<String> {
+    let value = std::env::var(var).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("environment variable {}: {}", var, e),
+        )
+    })?;
+    if value.starts_with("env:") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("environment variable {} refers to another variable", var),
+        ));
+    }
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(format!("{}:{}", host, value));
+    }
+    Ok(value)
+}
+
+#[cfg(unix)]
+fn no_ttl() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets have no time-to-live",
+    )
+}
+
+/// Windows and WASI have Tcp sockets but no Unix sockets through std
+#[cfg(not(unix))]
+fn unix_unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    )
+}
+
+/// A path, or an abstract name if it starts with `@`
+#[cfg(unix)]
+fn unix_addr_from_str(path: &str) -> Result<UnixSocketAddr> {
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        return UnixSocketAddr::from_abstract_name(percent_decode(name));
+    }
+    UnixSocketAddr::from_pathname(std::ffi::OsStr::from_bytes(&percent_decode(path)))
+}
+
+/// Replaces each `%XX` in a `unix:` address with the byte it gives in
+/// hex, leaving a `%` without two hex digits after it as it is
+#[cfg(unix)]
+fn percent_decode(s: &str) -> Vec<u8> {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        // from_str_radix alone would take a sign, as in "%+1"
+        let hex = b
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if b[i] == b'%' => {
+                out.push(byte);
+                i += 3;
+            }
+            _ => {
+                out.push(b[i]);
+                i += 1;
+            }
         }
-        TcpStream::connect(self).map(Into::into)
+    }
+    out
+}
+
+/// Writes a Unix path or abstract name so that [`percent_decode`] gives
+/// it back, and so that it can't be mistaken for the rest of an address:
+/// `%`, whitespace and control characters, `:`, `?`, `|`, a leading `@`
+/// and bytes that aren't UTF-8 are escaped
+#[cfg(unix)]
+fn percent_encode(b: &[u8], f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (i, chunk) in b.utf8_chunks().enumerate() {
+        for (j, c) in chunk.valid().char_indices() {
+            let first = i == 0 && j == 0;
+            if c.is_control() || c.is_whitespace() || "%:?|".contains(c) || (first && c == '@') {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    write!(f, "%{:02X}", byte)?;
+                }
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(f, "%{:02X}", byte)?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the `local:` socket called `name` lives
+#[cfg(unix)]
+fn local_path(name: &str) -> Result<std::path::PathBuf> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid local socket name {:?}", name),
+        ));
+    }
+    Ok(runtime_dir().join(format!("{}.sock", name)))
+}
+
+/// `$XDG_RUNTIME_DIR`, or the temporary directory if that isn't set
+#[cfg(unix)]
+fn runtime_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|d| !d.is_empty())
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+impl AbstractToSocketAddrs for String {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        self.as_str().bind_any()
+    }
+    fn connect_any(&self) -> Result<AbstractStream> {
+        self.as_str().connect_any()
+    }
+    fn bind_all(&self) -> Result<Vec<AbstractListener>> {
+        self.as_str().bind_all()
+    }
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
+        self.as_str().to_ip_addrs()
     }
 }
 
 #[cfg(unix)]
 impl AbstractToSocketAddrs for dyn AsRef<std::path::Path> {
     fn bind_any(&self) -> Result<AbstractListener> {
-        UnixListener::bind(self).map(Into::into)
+        let target = format!("unix:{}", self.as_ref().display());
+        error::context(
+            Operation::Bind,
+            &target,
+            UnixListener::bind(self).map(Into::into),
+        )
     }
     fn connect_any(&self) -> Result<AbstractStream> {
-        UnixStream::connect(self).map(Into::into)
+        let target = format!("unix:{}", self.as_ref().display());
+        error::context(
+            Operation::Connect,
+            &target,
+            UnixStream::connect(self).map(Into::into),
+        )
     }
 }
 
 impl AbstractToSocketAddrs for AbstractAddr {
     fn bind_any(&self) -> Result<AbstractListener> {
-        match self {
-            AbstractAddr::Ip(a) => (*a).bind_any().map(Into::into),
+        let r = match self {
+            AbstractAddr::Ip(a) => TcpListener::bind(a).map(Into::into),
             #[cfg(unix)]
-            AbstractAddr::Unix(a) => (*a).bind_any().map(Into::into),
-        }
+            AbstractAddr::Unix(a) => a.bind_any(),
+            AbstractAddr::Name(..) => bind_str(&format!("tcp:{}", self)),
+        };
+        error::addr_context(Operation::Bind, self, r)
     }
     fn connect_any(&self) -> Result<AbstractStream> {
+        let r = match self {
+            AbstractAddr::Ip(a) => TcpStream::connect(a).map(Into::into),
+            #[cfg(unix)]
+            AbstractAddr::Unix(a) => a.connect_any(),
+            AbstractAddr::Name(..) => connect_str(&format!("tcp:{}", self)),
+        };
+        error::addr_context(Operation::Connect, self, r)
+    }
+    fn to_ip_addrs(&self) -> Result<Vec<IpSocketAddr>> {
         match self {
-            AbstractAddr::Ip(a) => a.connect_any().map(Into::into),
+            AbstractAddr::Ip(a) => Ok(vec![*a]),
             #[cfg(unix)]
-            AbstractAddr::Unix(a) => a.connect_any().map(Into::into),
+            AbstractAddr::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "not a TCP address",
+            )),
+            AbstractAddr::Name(host, port) => (host.as_str(), *port).to_ip_addrs(),
+        }
+    }
+}
+
+/// Tries each address in order, `connect_any` returns the first connection
+/// that succeeds and `bind_any` the first listener that could be bound.
+///
+/// If every address fails, the error lists the reason each one failed.
+impl AbstractToSocketAddrs for [AbstractAddr] {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        first_success(self, "bind", |a| a.bind_any())
+    }
+    fn connect_any(&self) -> Result<AbstractStream> {
+        first_success(self, "connect", |a| a.connect_any())
+    }
+}
+
+/// Tries `.0` and falls back to `.1` if that fails
+///
+/// For example `Fallback("unix:/run/daemon.sock", "localhost:7000")`
+/// talks to a local daemon over its Unix socket if it exists and
+/// accepts the connection, otherwise over TCP.
+#[derive(Debug, Clone, Copy)]
+pub struct Fallback<A, B>(pub A, pub B);
+
+impl<A: AbstractToSocketAddrs, B: AbstractToSocketAddrs> AbstractToSocketAddrs for Fallback<A, B> {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        let both: [&dyn AbstractToSocketAddrs; 2] = [&self.0, &self.1];
+        first_success_by(both, "bind", |_| None, |a| a.bind_any())
+    }
+    fn connect_any(&self) -> Result<AbstractStream> {
+        let both: [&dyn AbstractToSocketAddrs; 2] = [&self.0, &self.1];
+        first_success_by(both, "connect", |_| None, |a| a.connect_any())
+    }
+}
+
+impl AbstractToSocketAddrs for Vec<AbstractAddr> {
+    fn bind_any(&self) -> Result<AbstractListener> {
+        self.as_slice().bind_any()
+    }
+    fn connect_any(&self) -> Result<AbstractStream> {
+        self.as_slice().connect_any()
+    }
+}
+
+fn first_success<A: std::fmt::Display, T>(
+    addrs: impl IntoIterator<Item = A>,
+    what: &str,
+    f: impl FnMut(A) -> Result<T>,
+) -> Result<T> {
+    first_success_by(addrs, what, |a| Some(a.to_string()), f)
+}
+
+/// Like [`first_success`], for candidates that can only say what they
+/// are through `label`, or through the context on their errors
+fn first_success_by<A, T>(
+    addrs: impl IntoIterator<Item = A>,
+    what: &str,
+    label: impl Fn(&A) -> Option<String>,
+    mut f: impl FnMut(A) -> Result<T>,
+) -> Result<T> {
+    let mut failures = vec![];
+    let mut kind = std::io::ErrorKind::InvalidInput;
+    for addr in addrs {
+        let label = label(&addr);
+        match f(addr) {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                kind = e.kind();
+                failures.push(failure(label.as_deref(), &e));
+            }
         }
     }
+    Err(none_succeeded(kind, what, &failures))
+}
+
+/// How one candidate failed, starting with which it was
+fn failure(candidate: Option<&str>, e: &std::io::Error) -> String {
+    // the context says what was being done, which the summary already has
+    let (target, reason): (Option<&str>, &dyn std::fmt::Display) = match Error::from_io(e) {
+        Some(ctx) => (candidate.or(Some(ctx.target())), ctx.io_error()),
+        None => (candidate, e),
+    };
+    match target {
+        Some(target) => format!("{}: {}", target, reason),
+        None => reason.to_string(),
+    }
+}
+
+/// The error for when every one of a list of addresses failed
+fn none_succeeded(kind: std::io::ErrorKind, what: &str, failures: &[String]) -> std::io::Error {
+    if failures.is_empty() {
+        return std::io::Error::new(kind, "no addresses given");
+    }
+    std::io::Error::new(
+        kind,
+        format!("could not {} any address ({})", what, failures.join("; ")),
+    )
 }
 
 /// Like TcpListener
@@ -125,34 +914,57 @@ impl AbstractToSocketAddrs for AbstractAddr {
 /// or [`UnixListener`](https://doc.rust-lang.org/std/os/unix/net/struct.UnixListener.html)
 ///
 /// Instead of calling `TcpListener::bind(address)`, you would call `address.bind_any`.
+#[derive(Debug)]
 pub enum AbstractListener {
     Tcp(TcpListener),
     #[cfg(unix)]
     Unix(UnixListener),
 }
 
-impl Into<AbstractListener> for TcpListener {
-    fn into(self) -> AbstractListener {
-        AbstractListener::Tcp(self)
+impl From<TcpListener> for AbstractListener {
+    fn from(l: TcpListener) -> Self {
+        AbstractListener::Tcp(l)
     }
 }
 
 #[cfg(unix)]
-impl Into<AbstractListener> for UnixListener {
-    fn into(self) -> AbstractListener {
-        AbstractListener::Unix(self)
+impl From<UnixListener> for AbstractListener {
+    fn from(l: UnixListener) -> Self {
+        AbstractListener::Unix(l)
+    }
+}
+
+impl std::convert::TryFrom<AbstractListener> for TcpListener {
+    type Error = AbstractListener;
+    fn try_from(l: AbstractListener) -> std::result::Result<Self, AbstractListener> {
+        l.into_tcp()
+    }
+}
+
+#[cfg(unix)]
+impl std::convert::TryFrom<AbstractListener> for UnixListener {
+    type Error = AbstractListener;
+    fn try_from(l: AbstractListener) -> std::result::Result<Self, AbstractListener> {
+        l.into_unix()
     }
 }
 
 /// Like SocketAddr
 ///
 /// Either a [`SocketAddr`](https://doc.rust-lang.org/std/net/struct.SocketAddr.html)
-/// or [`std::os::unix::net::SocketAddr`](https://doc.rust-lang.org/std/os/unix/net/struct.SocketAddr.html)
+/// or [`std::os::unix::net::SocketAddr`](https://doc.rust-lang.org/std/os/unix/net/struct.SocketAddr.html),
+/// or a host name and port yet to be resolved
 #[derive(Debug, Clone)]
 pub enum AbstractAddr {
     Ip(IpSocketAddr),
     #[cfg(unix)]
     Unix(UnixSocketAddr),
+    /// A host name that's only looked up when connecting or binding,
+    /// each time, so it follows changes in DNS
+    ///
+    /// Connecting goes through [`DnsCache`] and proxies as a
+    /// `host:port` string would.
+    Name(String, u16),
 }
 
 impl AbstractAddr {
@@ -161,60 +973,388 @@ impl AbstractAddr {
             AbstractAddr::Ip(a) => Some(a.port()),
             #[cfg(unix)]
             AbstractAddr::Unix(_) => None,
+            AbstractAddr::Name(_, port) => Some(*port),
+        }
+    }
+
+    /// The IP address, which a [`Name`](Self::Name) doesn't have until
+    /// it's resolved
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            AbstractAddr::Ip(a) => Some(a.ip()),
+            #[cfg(unix)]
+            AbstractAddr::Unix(_) => None,
+            AbstractAddr::Name(..) => None,
+        }
+    }
+
+    /// The host name of a [`Name`](Self::Name)
+    pub fn host_name(&self) -> Option<&str> {
+        match self {
+            AbstractAddr::Name(host, _) => Some(host),
+            _ => None,
+        }
+    }
+
+    /// The path of a Unix address bound to the filesystem
+    pub fn unix_path(&self) -> Option<&std::path::Path> {
+        match self {
+            #[cfg(unix)]
+            AbstractAddr::Unix(a) => a.as_pathname(),
+            _ => None,
+        }
+    }
+
+    /// Whether the address can only be reached from this machine
+    ///
+    /// That's a loopback IP address (including IPv4 loopback mapped into
+    /// IPv6), `localhost`, or any Unix address.
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            AbstractAddr::Ip(a) => match a.ip() {
+                IpAddr::V4(ip) => ip.is_loopback(),
+                IpAddr::V6(ip) => {
+                    ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback())
+                }
+            },
+            #[cfg(unix)]
+            AbstractAddr::Unix(_) => true,
+            AbstractAddr::Name(host, _) => host.eq_ignore_ascii_case("localhost"),
+        }
+    }
+
+    /// Looks up the host name of an IP address, through its PTR record
+    /// (or the hosts file), blocking until the resolver answers
+    ///
+    /// `None` for an address with no name, and for Unix addresses. A
+    /// [`Name`](Self::Name) gives its own host name back. Whoever runs the
+    /// reverse zone can put any name there, so a policy relying on it
+    /// should check that the name resolves back to the address.
+    pub fn lookup_host_name(&self) -> Result<Option<String>> {
+        match self {
+            #[cfg(unix)]
+            AbstractAddr::Ip(a) => sys::host_name(a),
+            #[cfg(not(unix))]
+            AbstractAddr::Ip(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "reverse lookups are only supported on Unix",
+            )),
+            #[cfg(unix)]
+            AbstractAddr::Unix(_) => Ok(None),
+            AbstractAddr::Name(host, _) => Ok(Some(host.clone())),
+        }
+    }
+
+    /// Whether this is a Unix address with neither a path nor an
+    /// abstract name, like that of most connecting clients
+    pub fn is_unnamed(&self) -> bool {
+        match self {
+            #[cfg(unix)]
+            AbstractAddr::Unix(a) => unix_addr_key(a) == UnixAddrKey::Unnamed,
+            _ => false,
         }
     }
 }
 
+/// The part of a Unix address that identifies it
+///
+/// `UnixSocketAddr` has neither `PartialEq` nor `Hash`, so equality
+/// is defined on what it's bound to: a path, an abstract name (on Linux),
+/// or nothing at all.
+#[cfg(unix)]
+#[derive(PartialEq, Eq, Hash)]
+enum UnixAddrKey<'a> {
+    Pathname(&'a std::path::Path),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Abstract(&'a [u8]),
+    Unnamed,
+}
+
+#[cfg(unix)]
+fn unix_addr_key(a: &UnixSocketAddr) -> UnixAddrKey<'_> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    if let Some(p) = a.as_pathname() {
+        return UnixAddrKey::Pathname(p);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(n) = a.as_abstract_name() {
+        return UnixAddrKey::Abstract(n);
+    }
+    UnixAddrKey::Unnamed
+}
+
+/// Unix addresses are equal if they have the same path (or abstract name).
+///
+/// All unnamed Unix addresses, such as those of connecting clients, are
+/// equal to each other, so they can't be used to tell peers apart.
+///
+/// Host names are compared ignoring ASCII case, and never equal an IP
+/// address, even one they resolve to.
+impl PartialEq for AbstractAddr {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AbstractAddr::Ip(a), AbstractAddr::Ip(b)) => a == b,
+            #[cfg(unix)]
+            (AbstractAddr::Unix(a), AbstractAddr::Unix(b)) => unix_addr_key(a) == unix_addr_key(b),
+            (AbstractAddr::Name(a, p), AbstractAddr::Name(b, q)) => {
+                p == q && a.eq_ignore_ascii_case(b)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AbstractAddr {}
+
+impl std::hash::Hash for AbstractAddr {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            AbstractAddr::Ip(a) => a.hash(state),
+            #[cfg(unix)]
+            AbstractAddr::Unix(a) => unix_addr_key(a).hash(state),
+            AbstractAddr::Name(host, port) => {
+                for b in host.bytes() {
+                    b.to_ascii_lowercase().hash(state);
+                }
+                port.hash(state);
+            }
+        }
+    }
+}
+
+/// Formats the address the way the string API parses it,
+/// such as `127.0.0.1:80`, `[::1]:80`, `example.com:80`, `unix:/run/x.sock`
+/// or `unix:@name`
+///
+/// Unix paths and names are percent-encoded where they need to be to
+/// parse back the same, and unnamed Unix addresses are written as
+/// `unix:`.
 impl std::fmt::Display for AbstractAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             AbstractAddr::Ip(a) => write!(f, "{}", a),
             #[cfg(unix)]
-            AbstractAddr::Unix(a) => write!(f, "{:?}", a),
+            AbstractAddr::Unix(a) => match unix_addr_key(a) {
+                UnixAddrKey::Pathname(p) => {
+                    use std::os::unix::ffi::OsStrExt;
+                    write!(f, "unix:")?;
+                    percent_encode(p.as_os_str().as_bytes(), f)
+                }
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                UnixAddrKey::Abstract(n) => {
+                    write!(f, "unix:@")?;
+                    percent_encode(n, f)
+                }
+                UnixAddrKey::Unnamed => write!(f, "unix:"),
+            },
+            AbstractAddr::Name(host, port) if host.contains(':') => {
+                write!(f, "[{}]:{}", host, port)
+            }
+            AbstractAddr::Name(host, port) => write!(f, "{}:{}", host, port),
         }
     }
 }
 
-impl Into<AbstractAddr> for IpSocketAddr {
-    fn into(self) -> AbstractAddr {
-        AbstractAddr::Ip(self)
+/// Parses a single address without resolving it: `IP:PORT`,
+/// `[IPv6]:PORT`, `unix:PATH` or `unix:@NAME` (with the escapes that
+/// `Display` writes), `local:NAME`, or `HOST:PORT`, which becomes a
+/// [`Name`](AbstractAddr::Name), each optionally prefixed with `tcp:`
+///
+/// This lets command line parsers that go through `FromStr`, such as
+/// clap's `value_parser!`, take addresses like `--listen unix:/run/x.sock`
+/// directly. Addresses that aren't a single socket, such as `either:`,
+/// `env:` or `ws://` ones, are rejected; pass those as strings instead.
+impl std::str::FromStr for AbstractAddr {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<AbstractAddr> {
+        let invalid = |why: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid address {:?}: {}; expected IP:PORT, HOST:PORT, unix:PATH or local:NAME",
+                    s, why
+                ),
+            )
+        };
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return unix_addr_from_str(path).map(Into::into);
+        }
+        #[cfg(unix)]
+        if let Some(name) = s.strip_prefix("local:") {
+            return UnixSocketAddr::from_pathname(local_path(name)?).map(Into::into);
+        }
+        #[cfg(not(unix))]
+        if s.starts_with("unix:") || s.starts_with("local:") {
+            return Err(unix_unsupported());
+        }
+        let schemes = [
+            "either:", "env:", "srv:", "shm:", "fifo:", "file:", "pty:", "exec:", "serial:",
+        ];
+        if s.contains("://") || schemes.iter().any(|scheme| s.starts_with(scheme)) {
+            return Err(invalid("not a single socket address"));
+        }
+        let tcp = s.strip_prefix("tcp:").unwrap_or(s);
+        if let Ok(a) = tcp.parse::<IpSocketAddr>() {
+            return Ok(a.into());
+        }
+        let (host, port) = tcp.rsplit_once(':').ok_or_else(|| invalid("no port"))?;
+        let host = match host.strip_prefix('[') {
+            Some(h) => h.strip_suffix(']').ok_or_else(|| invalid("unclosed ["))?,
+            None => host,
+        };
+        if host.is_empty() || host.contains(':') && !tcp.starts_with('[') {
+            return Err(invalid("not a single socket address"));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| invalid(&format!("bad port {:?}", port)))?;
+        Ok(AbstractAddr::Name(host.to_owned(), port))
+    }
+}
+
+impl From<IpSocketAddr> for AbstractAddr {
+    fn from(l: IpSocketAddr) -> Self {
+        AbstractAddr::Ip(l)
+    }
+}
+#[cfg(unix)]
+impl From<UnixSocketAddr> for AbstractAddr {
+    fn from(l: UnixSocketAddr) -> Self {
+        AbstractAddr::Unix(l)
+    }
+}
+impl From<SocketAddrV4> for AbstractAddr {
+    fn from(l: SocketAddrV4) -> Self {
+        AbstractAddr::Ip(l.into())
+    }
+}
+impl From<SocketAddrV6> for AbstractAddr {
+    fn from(l: SocketAddrV6) -> Self {
+        AbstractAddr::Ip(l.into())
+    }
+}
+
+impl std::convert::TryFrom<AbstractAddr> for IpSocketAddr {
+    type Error = AbstractAddr;
+    fn try_from(a: AbstractAddr) -> std::result::Result<Self, AbstractAddr> {
+        match a {
+            AbstractAddr::Ip(a) => Ok(a),
+            other => Err(other),
+        }
     }
 }
 #[cfg(unix)]
-impl Into<AbstractAddr> for UnixSocketAddr {
-    fn into(self) -> AbstractAddr {
-        AbstractAddr::Unix(self)
+impl std::convert::TryFrom<AbstractAddr> for UnixSocketAddr {
+    type Error = AbstractAddr;
+    fn try_from(a: AbstractAddr) -> std::result::Result<Self, AbstractAddr> {
+        match a {
+            AbstractAddr::Unix(a) => Ok(a),
+            other => Err(other),
+        }
     }
 }
 
 /// Like TcpStream
 ///
 /// Either a [`TcpStream`](https://doc.rust-lang.org/std/net/struct.TcpStream.html)
-/// or an [`UnixStream`](https://doc.rust-lang.org/std/os/unix/net/struct.UnixStream.html)
+/// or an [`UnixStream`](https://doc.rust-lang.org/std/os/unix/net/struct.UnixStream.html),
+/// or one of the other transports this crate can connect with. Which of
+/// those exist depends on the platform and enabled features, and more
+/// may be added, so matches on it need a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum AbstractStream {
     Tcp(TcpStream),
     #[cfg(unix)]
     Unix(UnixStream),
+    WebSocket(WebSocketStream),
+    #[cfg(all(feature = "shm", target_os = "linux"))]
+    Shm(ShmStream),
+    #[cfg(unix)]
+    Pipe(PipeStream),
+}
+
+#[cfg(unix)]
+impl From<PipeStream> for AbstractStream {
+    fn from(l: PipeStream) -> Self {
+        AbstractStream::Pipe(l)
+    }
+}
+
+#[cfg(all(feature = "shm", target_os = "linux"))]
+impl From<ShmStream> for AbstractStream {
+    fn from(l: ShmStream) -> Self {
+        AbstractStream::Shm(l)
+    }
+}
+
+impl From<WebSocketStream> for AbstractStream {
+    fn from(l: WebSocketStream) -> Self {
+        AbstractStream::WebSocket(l)
+    }
+}
+
+impl From<TcpStream> for AbstractStream {
+    fn from(l: TcpStream) -> Self {
+        AbstractStream::Tcp(l)
+    }
+}
+#[cfg(unix)]
+impl From<UnixStream> for AbstractStream {
+    fn from(l: UnixStream) -> Self {
+        AbstractStream::Unix(l)
+    }
 }
 
-impl Into<AbstractStream> for TcpStream {
-    fn into(self) -> AbstractStream {
-        AbstractStream::Tcp(self)
+impl std::convert::TryFrom<AbstractStream> for TcpStream {
+    type Error = AbstractStream;
+    fn try_from(s: AbstractStream) -> std::result::Result<Self, AbstractStream> {
+        s.into_tcp()
     }
 }
+
 #[cfg(unix)]
-impl Into<AbstractStream> for UnixStream {
-    fn into(self) -> AbstractStream {
-        AbstractStream::Unix(self)
+impl std::convert::TryFrom<AbstractStream> for UnixStream {
+    type Error = AbstractStream;
+    fn try_from(s: AbstractStream) -> std::result::Result<Self, AbstractStream> {
+        s.into_unix()
     }
 }
 
 impl AbstractStream {
+    /// The TcpStream, or `self` back if this is a different kind of stream
+    pub fn into_tcp(self) -> std::result::Result<TcpStream, Self> {
+        match self {
+            Self::Tcp(l) => Ok(l),
+            other => Err(other),
+        }
+    }
+
+    /// The UnixStream, or `self` back if this is a different kind of stream
+    #[cfg(unix)]
+    pub fn into_unix(self) -> std::result::Result<UnixStream, Self> {
+        match self {
+            Self::Unix(l) => Ok(l),
+            other => Err(other),
+        }
+    }
+
     pub fn shutdown(&self, how: std::net::Shutdown) -> Result<()> {
         match self {
             Self::Tcp(l) => l.shutdown(how),
             #[cfg(unix)]
             Self::Unix(l) => l.shutdown(how),
+            Self::WebSocket(l) => l.shutdown(how),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.shutdown(how),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.shutdown(how),
         }
     }
     pub fn try_clone(&self) -> Result<AbstractStream> {
@@ -222,6 +1362,17 @@ impl AbstractStream {
             Self::Tcp(l) => l.try_clone().map(Into::into),
             #[cfg(unix)]
             Self::Unix(l) => l.try_clone().map(Into::into),
+            Self::WebSocket(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "websocket streams cannot be cloned",
+            )),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "shared memory streams cannot be cloned",
+            )),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.try_clone().map(Into::into),
         }
     }
     pub fn peer_addr(&self) -> Result<AbstractAddr> {
@@ -229,6 +1380,130 @@ impl AbstractStream {
             Self::Tcp(l) => l.peer_addr().map(Into::into),
             #[cfg(unix)]
             Self::Unix(l) => l.peer_addr().map(Into::into),
+            Self::WebSocket(l) => l.get_ref().peer_addr().map(Into::into),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "shared memory streams have no address",
+            )),
+            #[cfg(unix)]
+            Self::Pipe(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "pipe streams have no address",
+            )),
+        }
+    }
+    /// Like TcpStream::peek
+    ///
+    /// Not supported on websocket streams.
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::Tcp(l) => l.peek(buf),
+            #[cfg(unix)]
+            Self::Unix(l) => {
+                use std::os::unix::io::AsRawFd;
+                sys::peek(l.as_raw_fd(), buf)
+            }
+            Self::WebSocket(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot peek a websocket stream",
+            )),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.peek(buf),
+            #[cfg(unix)]
+            Self::Pipe(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot peek a pipe stream",
+            )),
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Self::Tcp(l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(l) => l.set_nonblocking(nonblocking),
+            Self::WebSocket(l) => l.get_ref().set_nonblocking(nonblocking),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+    /// Whether the stream is nonblocking now
+    pub(crate) fn is_nonblocking(&self) -> Result<bool> {
+        #[cfg(all(feature = "shm", target_os = "linux"))]
+        if let Self::Shm(s) = self {
+            return Ok(s.is_nonblocking());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            crate::sys::fd_nonblocking(self.as_raw_fd())
+        }
+        // Windows has no way to ask, and std's sockets start out blocking
+        #[cfg(not(unix))]
+        {
+            Ok(false)
+        }
+    }
+    pub fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> Result<()> {
+        match self {
+            Self::Tcp(l) => l.set_read_timeout(dur),
+            #[cfg(unix)]
+            Self::Unix(l) => l.set_read_timeout(dur),
+            Self::WebSocket(l) => l.get_ref().set_read_timeout(dur),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.set_read_timeout(dur),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.set_read_timeout(dur),
+        }
+    }
+    pub fn set_write_timeout(&self, dur: Option<std::time::Duration>) -> Result<()> {
+        match self {
+            Self::Tcp(l) => l.set_write_timeout(dur),
+            #[cfg(unix)]
+            Self::Unix(l) => l.set_write_timeout(dur),
+            Self::WebSocket(l) => l.get_ref().set_write_timeout(dur),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.set_write_timeout(dur),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.set_write_timeout(dur),
+        }
+    }
+    pub fn read_timeout(&self) -> Result<Option<std::time::Duration>> {
+        match self {
+            Self::Tcp(l) => l.read_timeout(),
+            #[cfg(unix)]
+            Self::Unix(l) => l.read_timeout(),
+            Self::WebSocket(l) => l.get_ref().read_timeout(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read_timeout(),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read_timeout(),
+        }
+    }
+    pub fn write_timeout(&self) -> Result<Option<std::time::Duration>> {
+        match self {
+            Self::Tcp(l) => l.write_timeout(),
+            #[cfg(unix)]
+            Self::Unix(l) => l.write_timeout(),
+            Self::WebSocket(l) => l.get_ref().write_timeout(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.write_timeout(),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.write_timeout(),
+        }
+    }
+    pub fn take_error(&self) -> Result<Option<std::io::Error>> {
+        match self {
+            Self::Tcp(l) => l.take_error(),
+            #[cfg(unix)]
+            Self::Unix(l) => l.take_error(),
+            Self::WebSocket(l) => l.get_ref().take_error(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(_) => Ok(None),
+            #[cfg(unix)]
+            Self::Pipe(_) => Ok(None),
         }
     }
 }
@@ -239,6 +1514,11 @@ impl std::convert::AsRef<dyn std::io::Read> for AbstractStream {
             Self::Tcp(l) => l,
             #[cfg(unix)]
             Self::Unix(l) => l,
+            Self::WebSocket(l) => l,
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l,
+            #[cfg(unix)]
+            Self::Pipe(l) => l,
         }
     }
 }
@@ -249,6 +1529,11 @@ impl std::convert::AsRef<dyn std::io::Write> for AbstractStream {
             Self::Tcp(l) => l,
             #[cfg(unix)]
             Self::Unix(l) => l,
+            Self::WebSocket(l) => l,
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l,
+            #[cfg(unix)]
+            Self::Pipe(l) => l,
         }
     }
 }
@@ -259,6 +1544,11 @@ impl std::io::Read for AbstractStream {
             Self::Tcp(l) => l.read(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.read(buf),
+            Self::WebSocket(l) => l.read(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read(buf),
         }
     }
     fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut]) -> Result<usize> {
@@ -266,6 +1556,11 @@ impl std::io::Read for AbstractStream {
             Self::Tcp(l) => l.read_vectored(bufs),
             #[cfg(unix)]
             Self::Unix(l) => l.read_vectored(bufs),
+            Self::WebSocket(l) => l.read_vectored(bufs),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read_vectored(bufs),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read_vectored(bufs),
         }
     }
 
@@ -274,6 +1569,11 @@ impl std::io::Read for AbstractStream {
             Self::Tcp(l) => l.read_to_end(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.read_to_end(buf),
+            Self::WebSocket(l) => l.read_to_end(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read_to_end(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read_to_end(buf),
         }
     }
 
@@ -282,6 +1582,11 @@ impl std::io::Read for AbstractStream {
             Self::Tcp(l) => l.read_to_string(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.read_to_string(buf),
+            Self::WebSocket(l) => l.read_to_string(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read_to_string(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read_to_string(buf),
         }
     }
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
@@ -289,6 +1594,11 @@ impl std::io::Read for AbstractStream {
             Self::Tcp(l) => l.read_exact(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.read_exact(buf),
+            Self::WebSocket(l) => l.read_exact(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.read_exact(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.read_exact(buf),
         }
     }
 }
@@ -299,6 +1609,11 @@ impl std::io::Write for AbstractStream {
             Self::Tcp(l) => l.write(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.write(buf),
+            Self::WebSocket(l) => l.write(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.write(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.write(buf),
         }
     }
     fn flush(&mut self) -> Result<()> {
@@ -306,6 +1621,11 @@ impl std::io::Write for AbstractStream {
             Self::Tcp(l) => l.flush(),
             #[cfg(unix)]
             Self::Unix(l) => l.flush(),
+            Self::WebSocket(l) => l.flush(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.flush(),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.flush(),
         }
     }
     fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> Result<usize> {
@@ -313,6 +1633,11 @@ impl std::io::Write for AbstractStream {
             Self::Tcp(l) => l.write_vectored(bufs),
             #[cfg(unix)]
             Self::Unix(l) => l.write_vectored(bufs),
+            Self::WebSocket(l) => l.write_vectored(bufs),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.write_vectored(bufs),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.write_vectored(bufs),
         }
     }
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
@@ -320,6 +1645,11 @@ impl std::io::Write for AbstractStream {
             Self::Tcp(l) => l.write_all(buf),
             #[cfg(unix)]
             Self::Unix(l) => l.write_all(buf),
+            Self::WebSocket(l) => l.write_all(buf),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.write_all(buf),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.write_all(buf),
         }
     }
     fn write_fmt(&mut self, fmt: std::fmt::Arguments) -> Result<()> {
@@ -327,6 +1657,11 @@ impl std::io::Write for AbstractStream {
             Self::Tcp(l) => l.write_fmt(fmt),
             #[cfg(unix)]
             Self::Unix(l) => l.write_fmt(fmt),
+            Self::WebSocket(l) => l.write_fmt(fmt),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(l) => l.write_fmt(fmt),
+            #[cfg(unix)]
+            Self::Pipe(l) => l.write_fmt(fmt),
         }
     }
 }
@@ -336,6 +1671,94 @@ impl std::io::Write for AbstractStream {
 /// Either a [`TcpListener`](https://doc.rust-lang.org/std/net/struct.TcpListener.html)
 /// or an [`UnixListener`](https://doc.rust-lang.org/std/os/unix/net/struct.UnixListener.html)
 impl AbstractListener {
+    /// The TcpListener, or `self` back if this is a different kind of listener
+    pub fn into_tcp(self) -> std::result::Result<TcpListener, Self> {
+        match self {
+            Self::Tcp(l) => Ok(l),
+            #[cfg(unix)]
+            other => Err(other),
+        }
+    }
+
+    /// The UnixListener, or `self` back if this is a different kind of listener
+    #[cfg(unix)]
+    pub fn into_unix(self) -> std::result::Result<UnixListener, Self> {
+        match self {
+            Self::Unix(l) => Ok(l),
+            other => Err(other),
+        }
+    }
+
+    /// Binds to a port on `ip` chosen by the operating system
+    ///
+    /// Returns the address that was bound as well, which has
+    /// the actual port number.
+    pub fn bind_ephemeral(ip: impl Into<IpAddr>) -> Result<(AbstractListener, AbstractAddr)> {
+        let listener: AbstractListener = TcpListener::bind((ip.into(), 0))?.into();
+        let addr = listener.local_addr()?;
+        Ok((listener, addr))
+    }
+
+    /// Binds a Unix socket with a unique name starting with `prefix`
+    ///
+    /// The socket is `sock` in a new directory, only accessible by the
+    /// current user, in `$XDG_RUNTIME_DIR` or the temporary directory.
+    /// Remove the directory (the socket's parent) when you're done with it.
+    #[cfg(unix)]
+    pub fn bind_temp_unix(prefix: &str) -> Result<(AbstractListener, AbstractAddr)> {
+        use std::os::unix::fs::DirBuilderExt;
+        let base = runtime_dir();
+        let mut tries = 0;
+        let dir = loop {
+            let dir = base.join(format!("{}-{:016x}", prefix, random_u64()));
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && tries < 8 => tries += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let listener: AbstractListener = UnixListener::bind(dir.join("sock"))?.into();
+        let addr = listener.local_addr()?;
+        Ok((listener, addr))
+    }
+
+    /// Like TcpListener::set_nonblocking, which makes `accept`
+    /// fail with `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Self::Tcp(l) => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// The IP time-to-live of accepted connections
+    ///
+    /// Unix listeners have none, and fail with `Unsupported`.
+    pub fn ttl(&self) -> Result<u32> {
+        match self {
+            Self::Tcp(l) => l.ttl(),
+            #[cfg(unix)]
+            Self::Unix(_) => Err(no_ttl()),
+        }
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        match self {
+            Self::Tcp(l) => l.set_ttl(ttl),
+            #[cfg(unix)]
+            Self::Unix(_) => Err(no_ttl()),
+        }
+    }
+
+    pub fn take_error(&self) -> Result<Option<std::io::Error>> {
+        match self {
+            Self::Tcp(l) => l.take_error(),
+            #[cfg(unix)]
+            Self::Unix(l) => l.take_error(),
+        }
+    }
+
     pub fn local_addr(&self) -> Result<AbstractAddr> {
         match self {
             Self::Tcp(l) => l.local_addr().map(|m| m.into()),
@@ -345,6 +1768,19 @@ impl AbstractListener {
     }
 
     pub fn accept(&self) -> Result<(AbstractStream, AbstractAddr)> {
+        self.accept_context(self.accept_inner())
+    }
+
+    fn accept_context<T>(&self, r: Result<T>) -> Result<T> {
+        if r.is_err() {
+            if let Ok(addr) = self.local_addr() {
+                return error::addr_context(Operation::Accept, &addr, r);
+            }
+        }
+        r
+    }
+
+    fn accept_inner(&self) -> Result<(AbstractStream, AbstractAddr)> {
         match self {
             Self::Tcp(l) => l
                 .accept()
@@ -355,6 +1791,131 @@ impl AbstractListener {
                 .map(|(s, a)| (AbstractStream::Unix(s), AbstractAddr::Unix(a))),
         }
     }
+
+    pub(crate) fn accept_with(
+        &self,
+        opts: &builder::AcceptOptions,
+    ) -> Result<(AbstractStream, AbstractAddr)> {
+        self.accept_context(self.accept_with_inner(opts))
+    }
+
+    fn accept_with_inner(
+        &self,
+        opts: &builder::AcceptOptions,
+    ) -> Result<(AbstractStream, AbstractAddr)> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::unix::io::AsRawFd;
+            let mut flags = 0;
+            if !opts.inheritable {
+                flags |= sys::SOCK_CLOEXEC;
+            }
+            if opts.nonblocking {
+                flags |= sys::SOCK_NONBLOCK;
+            }
+            match self {
+                Self::Tcp(l) => {
+                    let s = TcpStream::from(sys::accept4_fd(l.as_raw_fd(), flags)?);
+                    let a = s.peer_addr()?;
+                    Ok((s.into(), a.into()))
+                }
+                Self::Unix(l) => {
+                    let s = UnixStream::from(sys::accept4_fd(l.as_raw_fd(), flags)?);
+                    let a = s.peer_addr()?;
+                    Ok((s.into(), a.into()))
+                }
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let (s, a) = self.accept()?;
+            #[cfg(unix)]
+            if opts.inheritable {
+                use std::os::unix::io::AsRawFd;
+                sys::set_inheritable(s.as_raw_fd(), true)?;
+            }
+            if opts.nonblocking {
+                s.set_nonblocking(true)?;
+            }
+            Ok((s, a))
+        }
+    }
+}
+
+/// The socket handle, for registering with a Windows readiness poller
+/// (such as wepoll or the `polling` crate) alongside `set_nonblocking`
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for AbstractStream {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        match self {
+            Self::Tcp(l) => l.as_raw_socket(),
+            Self::WebSocket(l) => l.get_ref().as_raw_socket(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsSocket for AbstractStream {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        match self {
+            Self::Tcp(l) => l.as_socket(),
+            Self::WebSocket(l) => l.get_ref().as_socket(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for AbstractListener {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        match self {
+            Self::Tcp(l) => l.as_raw_socket(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsSocket for AbstractListener {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        match self {
+            Self::Tcp(l) => l.as_socket(),
+        }
+    }
+}
+
+/// Random enough for jitter and unique names, neither of which need
+/// to be unpredictable; see [`os_random`] for what does
+pub(crate) fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+    h.finish()
+}
+
+/// Fills `buf` from the operating system's random number generator,
+/// for what an attacker mustn't predict, such as websocket masking keys
+///
+/// Where there's no `/dev/urandom` to read, this falls back to
+/// [`random_u64`].
+pub(crate) fn os_random(buf: &mut [u8]) {
+    #[cfg(unix)]
+    {
+        use std::io::Read;
+        static URANDOM: std::sync::OnceLock<Option<std::fs::File>> = std::sync::OnceLock::new();
+        let urandom = URANDOM.get_or_init(|| std::fs::File::open("/dev/urandom").ok());
+        if let Some(mut f) = urandom.as_ref() {
+            if f.read_exact(buf).is_ok() {
+                return;
+            }
+        }
+    }
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_ne_bytes()[..chunk.len()]);
+    }
 }
 
 #[cfg(test)]
@@ -364,4 +1925,370 @@ mod tests {
     fn parse1() {
         let _b = "unix:abc".bind_any();
     }
+
+    #[test]
+    fn tcp_adapter() {
+        let listener = Tcp(("127.0.0.1".to_string(), 0)).bind_any().unwrap();
+        let port = listener.local_addr().unwrap().port().unwrap();
+        let addrs: Vec<IpSocketAddr> = vec![([127, 0, 0, 1], port).into()];
+        Tcp(addrs.as_slice()).connect_any().unwrap();
+    }
+
+    #[test]
+    fn owned_strings() {
+        let listener = String::from("127.0.0.1:0").bind_any().unwrap();
+        let port = listener.local_addr().unwrap().port().unwrap();
+        format!("127.0.0.1:{}", port).connect_any().unwrap();
+        ("127.0.0.1".to_string(), port).connect_any().unwrap();
+    }
+
+    #[test]
+    fn fallback_connect() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let closed = "127.0.0.1:0".bind_any().unwrap().local_addr().unwrap();
+        let addrs = vec![closed.clone(), listener.local_addr().unwrap()];
+        addrs.connect_any().unwrap();
+
+        let err = vec![closed.clone(), closed.clone()]
+            .connect_any()
+            .unwrap_err();
+        let entry = format!("{}: Connection refused", closed);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("could not connect any address ({}", entry)));
+        assert_eq!(err.to_string().matches(&entry).count(), 2);
+        let err = Vec::new().connect_any().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let addr = listener.local_addr().unwrap().to_string();
+        let missing = format!("unix:/nonexistent/anysocket-{}", random_u64());
+        Fallback(missing.as_str(), addr.as_str())
+            .connect_any()
+            .unwrap();
+        format!("either:{}|{}", missing, addr)
+            .connect_any()
+            .unwrap();
+        let err = Fallback(missing.as_str(), missing.as_str())
+            .connect_any()
+            .unwrap_err();
+        assert!(err.to_string().contains("could not connect any address"));
+        assert_eq!(
+            err.to_string().matches(&format!("{}: ", missing)).count(),
+            2
+        );
+        let err = format!("either:{}|{}", missing, closed)
+            .connect_any()
+            .unwrap_err();
+        assert!(err.to_string().contains(&format!("({}: ", missing)));
+        assert!(err.to_string().contains(&format!("; {}: ", closed)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn addr_eq_hash() {
+        use std::collections::HashSet;
+        let dir = std::env::temp_dir().join(format!("anysocket-eq-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let listener = format!("unix:{}", dir.display()).bind_any().unwrap();
+        let (client, _) = UnixStream::pair().unwrap();
+
+        let mut set = HashSet::new();
+        set.insert(listener.local_addr().unwrap());
+        set.insert(listener.local_addr().unwrap());
+        set.insert(AbstractAddr::Unix(client.local_addr().unwrap()));
+        set.insert(AbstractAddr::Unix(client.peer_addr().unwrap()));
+        set.insert(AbstractAddr::Ip(([127, 0, 0, 1], 80).into()));
+        assert_eq!(set.len(), 3);
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[test]
+    fn unresolved_name() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let port = listener.local_addr().unwrap().port().unwrap();
+        let name = AbstractAddr::Name("LocalHost".into(), port);
+        assert_eq!(name, AbstractAddr::Name("localhost".into(), port));
+        assert_ne!(name, AbstractAddr::Ip(([127, 0, 0, 1], port).into()));
+        assert!(name.is_loopback());
+        assert_eq!(name.ip(), None);
+        assert_eq!(name.to_string(), format!("LocalHost:{}", port));
+        assert_eq!(AbstractAddr::Name("::1".into(), 80).to_string(), "[::1]:80");
+        name.connect_any().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reverse_lookup() {
+        let (a, _) = UnixStream::pair().unwrap();
+        let unix = AbstractAddr::Unix(a.local_addr().unwrap());
+        assert_eq!(unix.lookup_host_name().unwrap(), None);
+        let loopback = AbstractAddr::Ip(([127, 0, 0, 1], 80).into());
+        loopback.lookup_host_name().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn percent_encoded_paths() {
+        let dir = std::env::temp_dir().join(format!("anysocket pct:{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a?b|c%d.sock");
+        let listener = AbstractListener::from(UnixListener::bind(&path).unwrap());
+        let shown = listener.local_addr().unwrap().to_string();
+        assert!(shown.ends_with("/a%3Fb%7Cc%25d.sock"), "{}", shown);
+        assert!(!shown["unix:".len()..].contains([' ', ':']));
+        format!("either:{}|127.0.0.1:1", shown)
+            .connect_any()
+            .unwrap();
+        assert_eq!(
+            unix_addr_from_str("/x%2").unwrap().as_pathname(),
+            Some(std::path::Path::new("/x%2"))
+        );
+        assert_eq!(
+            unix_addr_from_str("/x%+1").unwrap().as_pathname(),
+            Some(std::path::Path::new("/x%+1"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn from_str() {
+        let parse = |s: &str| s.parse::<AbstractAddr>();
+        let ip = AbstractAddr::Ip(([127, 0, 0, 1], 80).into());
+        assert_eq!(parse("127.0.0.1:80").unwrap(), ip);
+        assert_eq!(parse("tcp:127.0.0.1:80").unwrap(), ip);
+        assert_eq!(
+            parse("example.com:443").unwrap(),
+            AbstractAddr::Name("example.com".into(), 443)
+        );
+        assert_eq!(parse("[::1]:80").unwrap().to_string(), "[::1]:80");
+        #[cfg(unix)]
+        assert_eq!(
+            parse("unix:/run/my%20app.sock").unwrap().unix_path(),
+            Some(std::path::Path::new("/run/my app.sock"))
+        );
+        for bad in [
+            "example.com",
+            "host:http",
+            "env:PORT",
+            "ws://x/",
+            "either:a:1|b:2",
+        ] {
+            let e = parse(bad).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let listener = "[::1]:0"
+            .bind_any()
+            .or_else(|_| "127.0.0.1:0".bind_any())
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        addr.connect_any().unwrap();
+
+        #[cfg(unix)]
+        {
+            let path = std::env::temp_dir().join(format!("anysocket-disp-{}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let listener = format!("unix:{}", path.display()).bind_any().unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            assert_eq!(addr, format!("unix:{}", path.display()));
+            addr.connect_any().unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let (a, _) = UnixStream::pair().unwrap();
+            assert_eq!(
+                AbstractAddr::Unix(a.local_addr().unwrap()).to_string(),
+                "unix:"
+            );
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("unix:@anysocket-disp-{}", std::process::id());
+            let listener = name.bind_any().unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            assert_eq!(addr, name);
+            addr.connect_any().unwrap();
+        }
+    }
+
+    #[test]
+    fn env_addrs() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let port = listener.local_addr().unwrap().port().unwrap();
+        std::env::set_var("ANYSOCKET_TEST_ADDR", format!("127.0.0.1:{}", port));
+        std::env::set_var("ANYSOCKET_TEST_PORT", port.to_string());
+        std::env::set_var("ANYSOCKET_TEST_LOOP", "env:ANYSOCKET_TEST_LOOP");
+        "env:ANYSOCKET_TEST_ADDR".connect_any().unwrap();
+        "env:ANYSOCKET_TEST_PORT".connect_any().unwrap();
+
+        let e = "env:ANYSOCKET_TEST_UNSET".connect_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        let e = "env:ANYSOCKET_TEST_LOOP".connect_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_names() {
+        let name = format!("anysocket-local-{}", random_u64());
+        let listener = format!("local:{}", name).bind_any().unwrap();
+        format!("local:{}", name).connect_any().unwrap();
+        let path = local_path(&name).unwrap();
+        let addr = UnixSocketAddr::from_pathname(&path).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr.into());
+        std::fs::remove_file(path).unwrap();
+
+        let e = "local:../x".connect_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_unix() {
+        use std::os::unix::fs::PermissionsExt;
+        let (listener, addr) = AbstractListener::bind_temp_unix("anysocket-test").unwrap();
+        let (_, other) = AbstractListener::bind_temp_unix("anysocket-test").unwrap();
+        assert_ne!(addr, other);
+        addr.connect_any().unwrap();
+        listener.accept().unwrap();
+        for a in [addr, other] {
+            let dir = a.unix_path().unwrap().parent().unwrap();
+            let mode = std::fs::metadata(dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn ephemeral() {
+        let (listener, addr) = AbstractListener::bind_ephemeral([127, 0, 0, 1]).unwrap();
+        assert_ne!(addr.port(), Some(0));
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        format!("tcp:{}", addr).connect_any().unwrap();
+
+        let listener = "tcp:127.0.0.1:0".bind_any().unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), Some(0));
+    }
+
+    #[test]
+    fn escape_conversions() {
+        use std::convert::TryFrom;
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let stream = listener.local_addr().unwrap().connect_any().unwrap();
+        #[cfg(unix)]
+        let stream = UnixStream::try_from(stream).unwrap_err();
+        TcpStream::try_from(stream).unwrap();
+        #[cfg(unix)]
+        let listener = listener.into_unix().unwrap_err();
+        TcpListener::try_from(listener).unwrap();
+    }
+
+    #[test]
+    fn from_conversions() {
+        use std::convert::TryFrom;
+        fn generic<T: From<TcpStream>>(s: TcpStream) -> T {
+            T::from(s)
+        }
+        let listener = AbstractListener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let _: AbstractStream = generic(TcpStream::connect(addr.to_string()).unwrap());
+
+        let v4 = SocketAddrV4::new([127, 0, 0, 1].into(), 80);
+        let addr = AbstractAddr::from(v4);
+        assert_eq!(IpSocketAddr::try_from(addr).unwrap(), v4.into());
+    }
+
+    #[test]
+    #[cfg(not(unix))]
+    fn unix_unsupported() {
+        let e = "unix:/tmp/x.sock".connect_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn listener_ttl() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        listener.set_ttl(42).unwrap();
+        assert_eq!(listener.ttl().unwrap(), 42);
+        assert!(listener.take_error().unwrap().is_none());
+
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("unix:@anysocket-ttl-{}", random_u64());
+            let listener = name.bind_any().unwrap();
+            let e = listener.ttl().unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+            assert!(listener.take_error().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn addr_accessors() {
+        let ip: AbstractAddr = IpSocketAddr::from(([127, 0, 0, 1], 80)).into();
+        assert_eq!(ip.ip(), Some(IpAddr::from([127, 0, 0, 1])));
+        assert!(ip.is_loopback());
+        assert!(!ip.is_unnamed());
+        assert_eq!(ip.unix_path(), None);
+        let mapped: AbstractAddr = "[::ffff:127.0.0.1]:80"
+            .parse::<IpSocketAddr>()
+            .unwrap()
+            .into();
+        assert!(mapped.is_loopback());
+        let remote: AbstractAddr = IpSocketAddr::from(([192, 0, 2, 1], 80)).into();
+        assert!(!remote.is_loopback());
+
+        #[cfg(unix)]
+        {
+            let path: AbstractAddr = UnixSocketAddr::from_pathname("/run/x.sock").unwrap().into();
+            assert_eq!(path.unix_path(), Some(std::path::Path::new("/run/x.sock")));
+            assert_eq!(path.ip(), None);
+            assert!(path.is_loopback());
+            assert!(!path.is_unnamed());
+            let (a, _) = UnixStream::pair().unwrap();
+            let unnamed: AbstractAddr = a.local_addr().unwrap().into();
+            assert!(unnamed.is_unnamed());
+        }
+    }
+
+    #[test]
+    fn nonblocking_accept() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let e = listener.accept().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn typed_ip_addrs() {
+        let listener = SocketAddrV4::new([127, 0, 0, 1].into(), 0)
+            .bind_any()
+            .unwrap();
+        let port = listener.local_addr().unwrap().port().unwrap();
+        (IpAddr::from([127, 0, 0, 1]), port).connect_any().unwrap();
+        SocketAddrV4::new([127, 0, 0, 1].into(), port)
+            .connect_any()
+            .unwrap();
+    }
+
+    #[test]
+    fn port_ranges() {
+        let taken = "127.0.0.1:0".bind_any().unwrap();
+        let port = taken.local_addr().unwrap().port().unwrap();
+        let range = format!("tcp:127.0.0.1:{}-{}", port, port);
+        let e = range.bind_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+        assert_eq!(
+            range.bind_all().unwrap_err().kind(),
+            std::io::ErrorKind::AddrInUse
+        );
+        drop(taken);
+        let all = range.bind_all().unwrap();
+        assert_eq!(all[0].local_addr().unwrap().port(), Some(port));
+        drop(all);
+        let e = "127.0.0.1:9-1".bind_any().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,165 @@
+//! Line-delimited text
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Reads and writes lines of text, for protocols like SMTP, Redis
+/// or the headers of HTTP/1
+///
+/// `read_line` accepts lines ending in `\n` or `\r\n` and `write_line`
+/// ends them with `\r\n`. A line longer than `max_len` bytes (8KiB by
+/// default) is an error rather than an unbounded allocation.
+///
+/// Reading from a `Lines` directly returns whatever was buffered past
+/// the last line first, so it's possible to switch to reading a
+/// binary body after the headers.
+#[derive(Debug)]
+pub struct Lines<S = AbstractStream> {
+    inner: S,
+    max_len: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: Read + Write> Lines<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_len(inner, 8192)
+    }
+
+    pub fn with_max_len(inner: S, max_len: usize) -> Self {
+        Lines {
+            inner,
+            max_len,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Reads the next line, without its line ending
+    ///
+    /// Returns `None` at the end of the stream. A final line without
+    /// a line ending is still returned.
+    pub fn read_line(&mut self) -> Result<Option<String>> {
+        let mut searched = self.pos;
+        loop {
+            if let Some(i) = self.buf[searched..].iter().position(|&b| b == b'\n') {
+                let end = searched + i;
+                let line = self.take_line(end, end + 1)?;
+                return Ok(Some(line));
+            }
+            searched = self.buf.len();
+            if searched - self.pos > self.max_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line longer than {} bytes", self.max_len),
+                ));
+            }
+
+            if self.pos > 0 {
+                self.buf.drain(..self.pos);
+                searched -= self.pos;
+                self.pos = 0;
+            }
+            let mut chunk = [0u8; 1024];
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                if self.buf.len() == self.pos {
+                    return Ok(None);
+                }
+                let end = self.buf.len();
+                return self.take_line(end, end).map(Some);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn take_line(&mut self, end: usize, next: usize) -> Result<String> {
+        let mut line = &self.buf[self.pos..end];
+        if let Some(l) = line.strip_suffix(b"\r") {
+            line = l;
+        }
+        if line.len() > self.max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("line longer than {} bytes", self.max_len),
+            ));
+        }
+        let line = std::str::from_utf8(line)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+            .to_string();
+        self.pos = next;
+        Ok(line)
+    }
+
+    /// Writes `line` followed by `\r\n`
+    ///
+    /// Fails if `line` itself contains a line break.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        if line.contains(['\r', '\n']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "line contains a line break",
+            ));
+        }
+        let mut out = Vec::with_capacity(line.len() + 2);
+        out.extend_from_slice(line.as_bytes());
+        out.extend_from_slice(b"\r\n");
+        self.inner.write_all(&out)?;
+        self.inner.flush()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The stream and whatever was read from it but not yet consumed
+    pub fn into_parts(mut self) -> (S, Vec<u8>) {
+        self.buf.drain(..self.pos);
+        (self.inner, self.buf)
+    }
+}
+
+impl<S: Read> Read for Lines<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos < self.buf.len() {
+            let n = buf.len().min(self.buf.len() - self.pos);
+            buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_lines() {
+        let data = b"HELO there\r\nplain\n\r\nbody".to_vec();
+        let mut l = Lines::new(Cursor::new(data));
+        assert_eq!(l.read_line().unwrap().unwrap(), "HELO there");
+        assert_eq!(l.read_line().unwrap().unwrap(), "plain");
+        assert_eq!(l.read_line().unwrap().unwrap(), "");
+        let mut rest = String::new();
+        l.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "body");
+        assert!(l.read_line().unwrap().is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let mut l = Lines::with_max_len(Cursor::new(vec![b'a'; 5000]), 100);
+        assert_eq!(l.read_line().unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut l = Lines::new(Cursor::new(vec![]));
+        assert!(l.write_line("a\nb").is_err());
+        l.write_line("PING").unwrap();
+        assert_eq!(l.get_ref().get_ref(), b"PING\r\n");
+    }
+}
@@ -0,0 +1,150 @@
+//! Several listeners accepted from as one
+
+use crate::{sys, AbstractAddr, AbstractListener, AbstractStream, AbstractToSocketAddrs};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr as IpSocketAddr};
+
+/// An address of one of this host's network interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    /// The interface's name, like `eth0`
+    pub name: String,
+    pub addr: IpAddr,
+    /// Which interface an IPv6 link-local address is on
+    scope_id: u32,
+}
+
+impl InterfaceAddr {
+    /// The addresses of every interface that is up, loopback included
+    pub fn all() -> Result<Vec<InterfaceAddr>> {
+        Ok(sys::interface_addrs()?
+            .into_iter()
+            .map(|(name, addr)| InterfaceAddr {
+                name,
+                addr: addr.ip(),
+                scope_id: match addr {
+                    IpSocketAddr::V6(a) => a.scope_id(),
+                    IpSocketAddr::V4(_) => 0,
+                },
+            })
+            .collect())
+    }
+
+    /// The address with `port`, ready to bind
+    pub fn with_port(&self, port: u16) -> IpSocketAddr {
+        match self.addr {
+            IpAddr::V6(ip) => std::net::SocketAddrV6::new(ip, port, 0, self.scope_id).into(),
+            IpAddr::V4(ip) => (ip, port).into(),
+        }
+    }
+}
+
+/// Listeners that are accepted from together, for binding to
+/// particular addresses rather than the wildcard
+///
+/// ```no_run
+/// # use anysocket::AbstractListenerSet;
+/// // every IPv4 address of eth0, but nothing else
+/// let set = AbstractListenerSet::bind_interfaces(8080, |a| {
+///     a.name == "eth0" && a.addr.is_ipv4()
+/// })?;
+/// let (stream, peer) = set.accept()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct AbstractListenerSet {
+    listeners: Vec<AbstractListener>,
+}
+
+impl AbstractListenerSet {
+    /// Binds each of `addrs`, failing if any can't be
+    pub fn bind<A: AbstractToSocketAddrs>(addrs: impl IntoIterator<Item = A>) -> Result<Self> {
+        let listeners = addrs
+            .into_iter()
+            .map(|a| a.bind_any())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(listeners.into())
+    }
+
+    /// Binds `port` on every interface address `filter` accepts
+    ///
+    /// Fails with `NotFound` if it accepts none. Port 0 gives each
+    /// listener a different port.
+    pub fn bind_interfaces(port: u16, filter: impl Fn(&InterfaceAddr) -> bool) -> Result<Self> {
+        let addrs: Vec<_> = InterfaceAddr::all()?
+            .into_iter()
+            .filter(|a| filter(a))
+            .map(|a| a.with_port(port))
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "no interface addresses to bind to",
+            ));
+        }
+        Self::bind(addrs)
+    }
+
+    pub fn listeners(&self) -> &[AbstractListener] {
+        &self.listeners
+    }
+
+    pub fn into_inner(self) -> Vec<AbstractListener> {
+        self.listeners
+    }
+
+    /// Where each listener is bound
+    pub fn local_addrs(&self) -> Result<Vec<AbstractAddr>> {
+        self.listeners.iter().map(|l| l.local_addr()).collect()
+    }
+
+    /// Waits for a connection on any of the listeners
+    ///
+    /// If other threads accept from the same listeners, this can block
+    /// on one that they emptied first; make the listeners nonblocking
+    /// to get `WouldBlock` instead.
+    pub fn accept(&self) -> Result<(AbstractStream, AbstractAddr)> {
+        let fds: Vec<_> = self.listeners.iter().map(|l| l.raw_fd()).collect();
+        loop {
+            let ready = sys::wait_readable(&fds)?;
+            for (listener, ready) in self.listeners.iter().zip(ready) {
+                if !ready {
+                    continue;
+                }
+                match listener.accept() {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    other => return other,
+                }
+            }
+        }
+    }
+}
+
+impl From<Vec<AbstractListener>> for AbstractListenerSet {
+    fn from(listeners: Vec<AbstractListener>) -> Self {
+        AbstractListenerSet { listeners }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_only() {
+        let all = InterfaceAddr::all().unwrap();
+        assert!(all.iter().any(|a| a.addr.is_loopback()));
+
+        let set =
+            AbstractListenerSet::bind_interfaces(0, |a| a.addr == IpAddr::from([127, 0, 0, 1]))
+                .unwrap();
+        let addrs = set.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 1);
+        let _c = addrs[0].connect_any().unwrap();
+        let (_, peer) = set.accept().unwrap();
+        assert!(peer.is_loopback());
+
+        let e = AbstractListenerSet::bind_interfaces(0, |_| false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+}
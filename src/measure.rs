@@ -0,0 +1,158 @@
+//! Measuring a stream's throughput and how long its calls take
+
+use crate::AbstractStream;
+use std::io::{Read, Result, Write};
+use std::time::{Duration, Instant};
+
+/// Records every successful read and write on the stream it wraps, for
+/// [`window`](Self::window) to sum up
+///
+/// Failed calls, such as those that would block, aren't counted.
+#[derive(Debug)]
+pub struct Measured<S = AbstractStream> {
+    inner: S,
+    started: Instant,
+    read: Ops,
+    write: Ops,
+}
+
+/// How one direction of a stream did over a window
+#[derive(Debug, Clone, Default)]
+pub struct Ops {
+    pub bytes: u64,
+    /// How long each call took, shortest first once in a [`Measurement`]
+    latencies: Vec<Duration>,
+}
+
+impl Ops {
+    fn record(&mut self, n: usize, took: Duration) {
+        self.bytes += n as u64;
+        self.latencies.push(took);
+    }
+
+    pub fn calls(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// The latency that `p` percent of calls took no longer than, or
+    /// `None` if there were none
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let at = (p.clamp(0.0, 100.0) / 100.0 * last as f64).round() as usize;
+        Some(self.latencies[at])
+    }
+}
+
+/// What a [`Measured`] stream did between two calls to
+/// [`window`](Measured::window)
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub elapsed: Duration,
+    pub read: Ops,
+    pub write: Ops,
+}
+
+impl Measurement {
+    /// Bytes read per second over the window
+    pub fn read_throughput(&self) -> f64 {
+        self.read.bytes as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Bytes written per second over the window
+    pub fn write_throughput(&self) -> f64 {
+        self.write.bytes as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+impl<S> Measured<S> {
+    pub fn new(inner: S) -> Self {
+        Measured {
+            inner,
+            started: Instant::now(),
+            read: Ops::default(),
+            write: Ops::default(),
+        }
+    }
+
+    /// Sums up what happened since the last call, or since the stream
+    /// was wrapped, and starts a new window
+    pub fn window(&mut self) -> Measurement {
+        let now = Instant::now();
+        let mut read = std::mem::take(&mut self.read);
+        let mut write = std::mem::take(&mut self.write);
+        read.latencies.sort_unstable();
+        write.latencies.sort_unstable();
+        let elapsed = now - std::mem::replace(&mut self.started, now);
+        Measurement {
+            elapsed,
+            read,
+            write,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Measured<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.read(buf)?;
+        self.read.record(n, start.elapsed());
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Measured<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.write(buf)?;
+        self.write.record(n, start.elapsed());
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Slow;
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            std::thread::sleep(Duration::from_millis(5));
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn measures() {
+        let mut m = Measured::new(Slow);
+        for _ in 0..4 {
+            m.write_all(&[0u8; 100]).unwrap();
+        }
+        let window = m.window();
+        assert_eq!(window.write.bytes, 400);
+        assert_eq!(window.write.calls(), 4);
+        assert_eq!(window.read.percentile(50.0), None);
+        assert!(window.write.percentile(0.0).unwrap() >= Duration::from_millis(5));
+        assert!(window.write_throughput() > 0.0);
+        assert!(window.write_throughput() <= 400.0 / 0.02);
+
+        assert_eq!(m.window().write.calls(), 0);
+    }
+}
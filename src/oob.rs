@@ -0,0 +1,124 @@
+//! TCP urgent data, for the old protocols that still use it
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Result};
+
+fn no_oob() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "urgent data needs a plain TCP connection",
+    )
+}
+
+impl AbstractStream {
+    #[cfg(unix)]
+    fn oob_fd(&self) -> Result<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        match self {
+            Self::Tcp(s) => Ok(s.as_raw_fd()),
+            _ => Err(no_oob()),
+        }
+    }
+
+    /// Sends `buf` as urgent data (`MSG_OOB`), as FTP's `ABOR` and
+    /// telnet's interrupt do
+    ///
+    /// TCP marks only the last byte as urgent; the rest are sent as
+    /// ordinary data. Fails with `Unsupported` on Unix domain sockets,
+    /// websockets and platforms other than Unix.
+    pub fn send_oob(&self, buf: &[u8]) -> Result<usize> {
+        #[cfg(unix)]
+        return crate::sys::send_oob(self.oob_fd()?, buf);
+        #[cfg(not(unix))]
+        {
+            let _ = buf;
+            Err(no_oob())
+        }
+    }
+
+    /// Receives the urgent byte the peer sent, out of line with the
+    /// rest of the data
+    ///
+    /// Fails with `InvalidInput` if there is none waiting, or if
+    /// [`set_oob_inline`](Self::set_oob_inline) is on.
+    pub fn recv_oob(&self, buf: &mut [u8]) -> Result<usize> {
+        #[cfg(unix)]
+        return crate::sys::recv_oob(self.oob_fd()?, buf);
+        #[cfg(not(unix))]
+        {
+            let _ = buf;
+            Err(no_oob())
+        }
+    }
+
+    /// Whether urgent bytes are left in the ordinary data
+    /// (`SO_OOBINLINE`) rather than kept aside for `recv_oob`
+    pub fn set_oob_inline(&self, inline: bool) -> Result<()> {
+        #[cfg(unix)]
+        return crate::sys::setsockopt_raw(
+            self.oob_fd()?,
+            crate::sys::SOL_SOCKET,
+            crate::sys::SO_OOBINLINE,
+            &std::os::raw::c_int::from(inline),
+        );
+        #[cfg(not(unix))]
+        {
+            let _ = inline;
+            Err(no_oob())
+        }
+    }
+
+    pub fn oob_inline(&self) -> Result<bool> {
+        #[cfg(unix)]
+        return crate::sys::getsockopt_int(
+            self.oob_fd()?,
+            crate::sys::SOL_SOCKET,
+            crate::sys::SO_OOBINLINE,
+        )
+        .map(|v| v != 0);
+        #[cfg(not(unix))]
+        Err(no_oob())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn urgent() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut c = listener.local_addr().unwrap().connect_any().unwrap();
+        let (mut s, _) = listener.accept().unwrap();
+        assert!(!s.oob_inline().unwrap());
+
+        c.write_all(b"ab").unwrap();
+        c.send_oob(b"!").unwrap();
+        // the normal data stops at the urgent mark
+        let mut buf = [0u8; 2];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ab");
+        let mut urgent = [0u8];
+        let start = std::time::Instant::now();
+        loop {
+            match s.recv_oob(&mut urgent) {
+                Ok(1) => break,
+                // not arrived yet
+                Err(e)
+                    if e.kind() == ErrorKind::InvalidInput || e.kind() == ErrorKind::WouldBlock =>
+                {
+                    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(&urgent, b"!");
+
+        let (u, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let e = AbstractStream::from(u).send_oob(b"!").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+}
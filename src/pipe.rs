@@ -0,0 +1,380 @@
+//! Streams made of one descriptor to read and another to write, such
+//! as a pair of named pipes (Unix only)
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A stream that reads from one file and writes to another
+///
+/// There are no socket options, so timeouts are kept here and waited
+/// for with `poll`, and a shut down half is swapped for `/dev/null`
+/// so the other end sees it closed.
+#[derive(Debug)]
+pub struct PipeStream {
+    read: File,
+    write: File,
+    read_shut: AtomicBool,
+    write_shut: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    /// Reads fail with `EIO` once a pseudo-terminal's other side has
+    /// closed, which is its end of file
+    pty: bool,
+    /// The process at the other end, for [`spawn`](Self::spawn)
+    child: Option<Child>,
+}
+
+impl PipeStream {
+    /// Reads from `read` and writes to `write`, which may be
+    /// duplicates of the same descriptor
+    pub fn new(read: impl Into<OwnedFd>, write: impl Into<OwnedFd>) -> PipeStream {
+        PipeStream {
+            read: File::from(read.into()),
+            write: File::from(write.into()),
+            read_shut: AtomicBool::new(false),
+            write_shut: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+            pty: false,
+            child: None,
+        }
+    }
+
+    /// Runs `cmd` with its stdin and stdout as the other end of the
+    /// stream, leaving its stderr as it was set
+    ///
+    /// Dropping the stream closes the pipes, which most programs take
+    /// as their cue to exit, and the process is then waited for in the
+    /// background.
+    pub fn spawn(cmd: &mut Command) -> Result<PipeStream> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let (stdin, stdout) = (child.stdin.take(), child.stdout.take());
+        let mut stream = PipeStream::new(stdout.unwrap(), stdin.unwrap());
+        stream.child = Some(child);
+        Ok(stream)
+    }
+
+    /// The process from [`spawn`](Self::spawn)
+    pub fn child_id(&self) -> Option<u32> {
+        self.child.as_ref().map(Child::id)
+    }
+
+    /// The master side of a new pseudo-terminal
+    ///
+    /// The program to run on it opens [`pty_path`](Self::pty_path) as
+    /// its terminal. Reading gives end of file once it has closed that.
+    pub fn open_pty() -> Result<PipeStream> {
+        let master = crate::sys::open_pty()?;
+        let mut stream = PipeStream::new(master.try_clone()?, master);
+        stream.pty = true;
+        Ok(stream)
+    }
+
+    /// Opens the serial port at `path` in raw mode, 8N1 without modem
+    /// control, at `baud` or whatever speed it was already set to
+    #[cfg(feature = "serial")]
+    pub fn open_serial(path: impl AsRef<Path>, baud: Option<u32>) -> Result<PipeStream> {
+        // without waiting for carrier detect
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(crate::sys::O_NONBLOCK)
+            .open(path)?;
+        crate::sys::set_fd_nonblocking(port.as_raw_fd(), false)?;
+        crate::sys::configure_serial(port.as_raw_fd(), baud)?;
+        Ok(PipeStream::new(port.try_clone()?, port))
+    }
+
+    /// The terminal side of a stream from [`open_pty`](Self::open_pty)
+    pub fn pty_path(&self) -> Result<std::path::PathBuf> {
+        crate::sys::pty_path(self.read.as_raw_fd())
+    }
+
+    /// Opens the named pipe `input` to read from and `output` to write
+    /// to, waiting until something has `output` open for reading
+    ///
+    /// The reading end is opened first without waiting, so two
+    /// processes opening the same pair the opposite way round meet
+    /// rather than each waiting for the other.
+    pub fn open_fifos(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<PipeStream> {
+        let read = OpenOptions::new()
+            .read(true)
+            .custom_flags(crate::sys::O_NONBLOCK)
+            .open(input)?;
+        let write = OpenOptions::new().write(true).open(output)?;
+        crate::sys::set_fd_nonblocking(read.as_raw_fd(), false)?;
+        Ok(PipeStream::new(read, write))
+    }
+
+    /// Reads from the file `input`, ending where it does, and writes to
+    /// `output`, created or truncated, or throws writes away without it
+    ///
+    /// For replaying a captured session through code that takes a
+    /// stream.
+    pub fn open_files(input: impl AsRef<Path>, output: Option<&Path>) -> Result<PipeStream> {
+        let read = File::open(input)?;
+        let write = match output {
+            Some(path) => File::create(path)?,
+            None => OpenOptions::new().write(true).open("/dev/null")?,
+        };
+        Ok(PipeStream::new(read, write))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        if how != Shutdown::Write && !self.read_shut.swap(true, Ordering::SeqCst) {
+            crate::sys::close_in_place(self.read.as_raw_fd())?;
+        }
+        if how != Shutdown::Read && !self.write_shut.swap(true, Ordering::SeqCst) {
+            crate::sys::close_in_place(self.write.as_raw_fd())?;
+        }
+        Ok(())
+    }
+
+    /// Duplicates both descriptors, copying the timeouts
+    ///
+    /// Unlike a socket's, shutting one down leaves the other's copy of
+    /// the pipe open, so the far end only sees it closed once both are.
+    pub fn try_clone(&self) -> Result<PipeStream> {
+        let mut clone = PipeStream::new(self.read.try_clone()?, self.write.try_clone()?);
+        clone.pty = self.pty;
+        clone
+            .read_shut
+            .store(self.read_shut.load(Ordering::SeqCst), Ordering::SeqCst);
+        clone
+            .write_shut
+            .store(self.write_shut.load(Ordering::SeqCst), Ordering::SeqCst);
+        *clone.read_timeout.lock().unwrap() = *self.read_timeout.lock().unwrap();
+        *clone.write_timeout.lock().unwrap() = *self.write_timeout.lock().unwrap();
+        Ok(clone)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        crate::sys::set_fd_nonblocking(self.read.as_raw_fd(), nonblocking)?;
+        crate::sys::set_fd_nonblocking(self.write.as_raw_fd(), nonblocking)
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        check_timeout(dur)?;
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        check_timeout(dur)?;
+        *self.write_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
+    /// The descriptor written to, which `AsFd` doesn't give
+    pub fn write_fd(&self) -> BorrowedFd<'_> {
+        self.write.as_fd()
+    }
+
+    /// Waits as long as the timeout allows for `fd` to be ready
+    fn wait(fd: &File, events: i16, timeout: &Mutex<Option<Duration>>) -> Result<()> {
+        let timeout = *timeout.lock().unwrap();
+        if timeout.is_none() {
+            return Ok(());
+        }
+        let ready = crate::sys::poll_fds(&[(fd.as_raw_fd(), events)], timeout)?;
+        if ready[0] == 0 {
+            return Err(Error::new(ErrorKind::WouldBlock, "timed out"));
+        }
+        Ok(())
+    }
+}
+
+/// Like std, which refuses a zero timeout
+fn check_timeout(dur: Option<Duration>) -> Result<()> {
+    if dur == Some(Duration::ZERO) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+impl Drop for PipeStream {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = self.shutdown(Shutdown::Both);
+            if let Ok(None) = child.try_wait() {
+                let _ = std::thread::Builder::new().spawn(move || child.wait());
+            }
+        }
+    }
+}
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.read_shut.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        Self::wait(&self.read, crate::sys::POLLIN, &self.read_timeout)?;
+        match self.read.read(buf) {
+            // EIO
+            Err(e) if self.pty && e.raw_os_error() == Some(5) => Ok(0),
+            r => r,
+        }
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.write_shut.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::BrokenPipe, "stream shut down"));
+        }
+        Self::wait(&self.write, crate::sys::POLLOUT, &self.write_timeout)?;
+        self.write.write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.write.flush()
+    }
+}
+
+/// The descriptor read from
+impl AsFd for PipeStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.read.as_fd()
+    }
+}
+
+impl AsRawFd for PipeStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AbstractStream, AbstractToSocketAddrs};
+
+    #[test]
+    fn fifos() {
+        let dir = std::env::temp_dir().join(format!("anysocket-fifo-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let (x, y) = (dir.join("x"), dir.join("y"));
+        for p in [&x, &y] {
+            let made = std::process::Command::new("mkfifo")
+                .arg(p)
+                .status()
+                .unwrap();
+            assert!(made.success());
+        }
+
+        let addr = format!("fifo:{},{}", y.display(), x.display());
+        let other = std::thread::spawn(move || addr.connect_any().unwrap());
+        let mut a = format!("fifo:{},{}", x.display(), y.display())
+            .connect_any()
+            .unwrap();
+        let mut b = other.join().unwrap();
+        assert!(matches!(a, AbstractStream::Pipe(_)));
+
+        a.write_all(b"ping").unwrap();
+        let mut got = [0u8; 4];
+        b.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"ping");
+
+        b.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let e = b.read(&mut got).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+
+        a.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(b.read(&mut got).unwrap(), 0);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files() {
+        let dir = std::env::temp_dir().join(format!("anysocket-file-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        std::fs::write(&input, b"captured").unwrap();
+
+        let mut s = format!("file:{},{}", input.display(), output.display())
+            .connect_any()
+            .unwrap();
+        let mut got = String::new();
+        s.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "captured");
+        s.write_all(b"reply").unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"reply");
+
+        let mut s = format!("file:{}", input.display()).connect_any().unwrap();
+        s.write_all(b"dropped").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pty() {
+        let mut master = match "pty:".connect_any().unwrap() {
+            AbstractStream::Pipe(p) => p,
+            _ => unreachable!(),
+        };
+        let mut terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(master.pty_path().unwrap())
+            .unwrap();
+        terminal.write_all(b"hi").unwrap();
+        let mut got = [0u8; 2];
+        master.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"hi");
+        drop(terminal);
+        assert_eq!(master.read(&mut got).unwrap(), 0);
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    fn serial() {
+        let mut master = PipeStream::open_pty().unwrap();
+        let addr = format!(
+            "serial:{}?baud=115200",
+            master.pty_path().unwrap().display()
+        );
+        let mut port = addr.connect_any().unwrap();
+        // raw, so the newline isn't turned into \r\n on the way out
+        port.write_all(b"at\n").unwrap();
+        let mut got = [0u8; 3];
+        master.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"at\n");
+        let e = "serial:/dev/null?baud=7".connect_any().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(not(feature = "serial"))]
+    #[test]
+    fn serial_needs_feature() {
+        let e = "serial:/dev/ttyUSB0?baud=9600".connect_any().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn exec() {
+        let mut s = "exec:tr a-z A-Z".connect_any().unwrap();
+        s.write_all(b"shout").unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        let mut got = String::new();
+        s.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "SHOUT");
+    }
+}
@@ -0,0 +1,301 @@
+//! Reusing idle connections
+
+use crate::{AbstractAddr, AbstractStream, AbstractToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A pool of connections, keyed by the address they connect to
+///
+/// `get` hands out an idle connection to the address if a live one is
+/// available, otherwise it connects a new one. The connection goes back
+/// into the pool when the [`PooledStream`] is dropped.
+///
+/// Cloning an `AbstractPool` gives another handle to the same pool.
+#[derive(Clone)]
+pub struct AbstractPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    entries: Mutex<HashMap<AbstractAddr, Entry>>,
+}
+
+#[derive(Default)]
+struct Entry {
+    idle: Vec<(AbstractStream, Instant)>,
+    checked_out: usize,
+}
+
+impl Default for AbstractPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AbstractPool {
+    /// A pool of at most 16 connections per address that
+    /// closes connections left idle for 90 seconds
+    pub fn new() -> Self {
+        Self::with_limits(16, Some(Duration::from_secs(90)))
+    }
+
+    /// `max_size` limits the connections (idle or checked out)
+    /// to each address, and idle connections are closed after `idle_timeout`
+    pub fn with_limits(max_size: usize, idle_timeout: Option<Duration>) -> Self {
+        AbstractPool {
+            shared: Arc::new(Shared {
+                max_size,
+                idle_timeout,
+                entries: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Checks out a connection to `addr`
+    ///
+    /// Idle connections are checked before being reused; ones that
+    /// the peer has closed, that have a pending error or that have
+    /// unread data are thrown away, as are websocket and pipe streams,
+    /// which can't be peeked to check.
+    ///
+    /// Fails with `WouldBlock` if `max_size` connections to `addr`
+    /// are already checked out.
+    pub fn get(&self, addr: &AbstractAddr) -> Result<PooledStream> {
+        {
+            let mut entries = self.shared.entries.lock().unwrap();
+            let entry = entries.entry(addr.clone()).or_default();
+            let now = Instant::now();
+            while let Some((stream, since)) = entry.idle.pop() {
+                let expired = self
+                    .shared
+                    .idle_timeout
+                    .is_some_and(|t| now.duration_since(since) > t);
+                if !expired && is_reusable(&stream) {
+                    entry.checked_out += 1;
+                    return Ok(self.wrap(addr, stream));
+                }
+            }
+            if entry.checked_out >= self.shared.max_size {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("connection pool for {} is full", addr),
+                ));
+            }
+            entry.checked_out += 1;
+        }
+
+        match addr.connect_any() {
+            Ok(stream) => Ok(self.wrap(addr, stream)),
+            Err(e) => {
+                self.shared.check_in(addr, None);
+                Err(e)
+            }
+        }
+    }
+
+    /// The number of idle connections to `addr`
+    pub fn idle(&self, addr: &AbstractAddr) -> usize {
+        let entries = self.shared.entries.lock().unwrap();
+        entries.get(addr).map_or(0, |e| e.idle.len())
+    }
+
+    /// Closes all idle connections
+    pub fn clear(&self) {
+        let mut entries = self.shared.entries.lock().unwrap();
+        entries.retain(|_, e| {
+            e.idle.clear();
+            e.checked_out > 0
+        });
+    }
+
+    fn wrap(&self, addr: &AbstractAddr, stream: AbstractStream) -> PooledStream {
+        PooledStream {
+            stream: Some(stream),
+            addr: addr.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Shared {
+    fn check_in(&self, addr: &AbstractAddr, stream: Option<AbstractStream>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(addr) {
+            entry.checked_out -= 1;
+            if let Some(stream) = stream {
+                entry.idle.push((stream, Instant::now()));
+            }
+            if let Some(timeout) = self.idle_timeout {
+                entry.idle.retain(|(_, since)| since.elapsed() <= timeout);
+            }
+            if entry.checked_out == 0 && entry.idle.is_empty() {
+                entries.remove(addr);
+            }
+        }
+    }
+}
+
+/// Peeks without waiting to see that the peer is still there and has
+/// said nothing, leaving the stream as blocking as it was
+fn is_reusable(stream: &AbstractStream) -> bool {
+    if !matches!(stream.take_error(), Ok(None)) {
+        return false;
+    }
+    let was_nonblocking = match stream.is_nonblocking() {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+    if !was_nonblocking && stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut b = [0u8];
+    // Ok is either closed or the peer saying something nobody is going
+    // to read, and a stream that can't be peeked can't be checked
+    let alive = matches!(stream.peek(&mut b), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    let restored = was_nonblocking || stream.set_nonblocking(false).is_ok();
+    alive && restored
+}
+
+/// A connection checked out from an [`AbstractPool`]
+///
+/// Dereferences to the [`AbstractStream`]. Dropping it returns the
+/// connection to the pool; call [`discard`](Self::discard) instead if
+/// it is no longer in a usable state, such as after an error.
+pub struct PooledStream {
+    stream: Option<AbstractStream>,
+    addr: AbstractAddr,
+    shared: Arc<Shared>,
+}
+
+impl PooledStream {
+    /// The address this connection is pooled under
+    pub fn addr(&self) -> &AbstractAddr {
+        &self.addr
+    }
+
+    /// Closes the connection instead of returning it to the pool
+    pub fn discard(mut self) {
+        self.stream = None;
+    }
+
+    /// Takes the connection out of the pool's control
+    pub fn detach(mut self) -> AbstractStream {
+        self.stream.take().unwrap()
+    }
+}
+
+impl std::ops::Deref for PooledStream {
+    type Target = AbstractStream;
+    fn deref(&self) -> &AbstractStream {
+        self.stream.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for PooledStream {
+    fn deref_mut(&mut self) -> &mut AbstractStream {
+        self.stream.as_mut().unwrap()
+    }
+}
+
+impl Read for PooledStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+}
+
+impl Write for PooledStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl std::fmt::Debug for PooledStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledStream")
+            .field("stream", &self.stream)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        self.shared.check_in(&self.addr, self.stream.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = AbstractPool::with_limits(1, None);
+
+        let first = pool.get(&addr).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        assert_eq!(pool.get(&addr).unwrap_err().kind(), ErrorKind::WouldBlock);
+        drop(first);
+        assert_eq!(pool.idle(&addr), 1);
+
+        // the same connection comes back out
+        let mut again = pool.get(&addr).unwrap();
+        again.write_all(b"x").unwrap();
+        let mut b = [0u8];
+        server.read_exact(&mut b).unwrap();
+        drop(again);
+
+        // and is thrown away once the server closes it
+        drop(server);
+        std::thread::sleep(Duration::from_millis(50));
+        let _fresh = pool.get(&addr).unwrap();
+        assert_eq!(pool.idle(&addr), 0);
+        listener.accept().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_blocking_mode() {
+        use std::os::unix::io::AsRawFd;
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = AbstractPool::new();
+        for nonblocking in [true, false] {
+            let stream = pool.get(&addr).unwrap();
+            stream.set_nonblocking(nonblocking).unwrap();
+            drop(stream);
+            let again = pool.get(&addr).unwrap();
+            assert_eq!(
+                crate::sys::fd_nonblocking(again.as_raw_fd()).unwrap(),
+                nonblocking
+            );
+        }
+        listener.accept().unwrap();
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = AbstractPool::with_limits(4, Some(Duration::from_millis(20)));
+        drop(pool.get(&addr).unwrap());
+        let (_first, first_addr) = listener.accept().unwrap();
+        assert_eq!(pool.idle(&addr), 1);
+        std::thread::sleep(Duration::from_millis(40));
+
+        // the expired connection is dropped and a new one made
+        let _second = pool.get(&addr).unwrap();
+        assert_eq!(pool.idle(&addr), 0);
+        let (_, second_addr) = listener.accept().unwrap();
+        assert_ne!(second_addr, first_addr);
+    }
+}
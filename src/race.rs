@@ -0,0 +1,188 @@
+//! Connecting to several candidates at once
+
+use crate::{
+    failure, first_success_by, none_succeeded, AbstractListener, AbstractStream,
+    AbstractToSocketAddrs,
+};
+use std::io::{ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// Connects to the candidates in `.0` with staggered, parallel attempts
+///
+/// The first candidate is tried straight away and each following one
+/// once `.1` has passed without a connection, or as soon as an earlier
+/// attempt fails. The first connection made wins, and no more
+/// candidates are started.
+///
+/// On Unix, candidates that come down to IP addresses, with neither
+/// [`Proxy::use_env`](crate::Proxy::use_env) nor a [`DnsCache`](crate::DnsCache)
+/// in the way, connect without blocking, so attempts still running when
+/// another wins are closed straight away. Other attempts carry on in the
+/// background and their connections are closed as they finish.
+///
+/// `bind_any` binds the candidates in order, like a list of addresses.
+///
+/// ```no_run
+/// # use anysocket::{AbstractToSocketAddrs, Race};
+/// # use std::time::Duration;
+/// let stream = Race(vec!["10.0.0.1:80", "10.0.0.2:80"], Duration::from_millis(250))
+///     .connect_any()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Race<A>(pub Vec<A>, pub Duration);
+
+impl<A> AbstractToSocketAddrs for Race<A>
+where
+    A: AbstractToSocketAddrs + Clone + Send + 'static,
+{
+    fn bind_any(&self) -> Result<AbstractListener> {
+        first_success_by(&self.0, "bind", |_| None, |a| a.bind_any())
+    }
+
+    fn connect_any(&self) -> Result<AbstractStream> {
+        let (tx, rx) = mpsc::channel();
+        let mut next = self.0.iter();
+        let mut running = 0;
+        let mut failures = vec![];
+        let mut kind = ErrorKind::InvalidInput;
+        let won = Arc::new(AtomicBool::new(false));
+        loop {
+            if let Some(addr) = next.next() {
+                let addr = addr.clone();
+                let tx = tx.clone();
+                let won = won.clone();
+                std::thread::spawn(move || {
+                    // the receiver is gone once someone else has won
+                    let _ = tx.send(attempt(&addr, &won));
+                });
+                running += 1;
+            } else if running == 0 {
+                break;
+            }
+            let r = if next.len() > 0 {
+                match rx.recv_timeout(self.1) {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                }
+            } else {
+                rx.recv().expect("sender kept alive")
+            };
+            running -= 1;
+            match r {
+                Ok(stream) => {
+                    won.store(true, Ordering::SeqCst);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    kind = e.kind();
+                    failures.push(failure(None, &e));
+                }
+            }
+        }
+        Err(none_succeeded(kind, "connect", &failures))
+    }
+}
+
+/// Connects to `addr`, giving up early once `won` is set if it can
+fn attempt<A: AbstractToSocketAddrs>(addr: &A, won: &AtomicBool) -> Result<AbstractStream> {
+    #[cfg(unix)]
+    if !crate::Proxy::env_in_use() && crate::dns::global().is_none() {
+        if let Ok(ips) = addr.to_ip_addrs() {
+            let mut last = None;
+            for ip in ips {
+                let r = crate::sys::connect_unless(&ip, won);
+                match crate::error::context(crate::Operation::Connect, &ip, r) {
+                    Ok(s) => return Ok(s.into()),
+                    Err(e) => last = Some(e),
+                }
+            }
+            return Err(last.unwrap_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidInput, "no addresses to connect to")
+            }));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = won;
+    addr.connect_any()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractAddr;
+    use std::time::Instant;
+
+    #[test]
+    fn first_wins() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let closed = "127.0.0.1:0".bind_any().unwrap().local_addr().unwrap();
+        let good = listener.local_addr().unwrap();
+
+        let start = Instant::now();
+        let race = Race(vec![closed.clone(), good.clone()], Duration::from_secs(10));
+        let stream = race.connect_any().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        // a failure starts the next attempt without waiting for the stagger
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let e = Race(vec![closed.clone()], Duration::ZERO)
+            .connect_any()
+            .unwrap_err();
+        assert!(e.to_string().starts_with("could not connect any address"));
+        assert!(e.to_string().contains(&format!("({}: ", closed)));
+        let e = Race(Vec::<AbstractAddr>::new(), Duration::ZERO)
+            .connect_any()
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// How many of this process's TCP connections to `port` are still
+    /// waiting for the handshake
+    #[cfg(target_os = "linux")]
+    fn connecting_to(port: u16) -> usize {
+        let tcp = std::fs::read_to_string("/proc/net/tcp").unwrap();
+        let remote = format!(":{:04X}", port);
+        tcp.lines()
+            .filter_map(|l| {
+                let fields: Vec<_> = l.split_whitespace().collect();
+                Some((*fields.get(2)?, *fields.get(3)?))
+            })
+            .filter(|(rem, state)| rem.ends_with(&remote) && *state == "02")
+            .count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn losers_give_up() {
+        // an installed DnsCache would take away Race's cancellable connects
+        let _lock = crate::dns::test_lock();
+        // with its backlog full, connecting to this doesn't finish
+        let full = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        crate::sys::set_backlog(std::os::unix::io::AsRawFd::as_raw_fd(&full), Some(0)).unwrap();
+        let stuck = full.local_addr().unwrap();
+        let mut queued = vec![];
+        while let Ok(s) = std::net::TcpStream::connect_timeout(&stuck, Duration::from_millis(100)) {
+            queued.push(s);
+        }
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let good = listener.local_addr().unwrap();
+
+        let race = Race(
+            vec![AbstractAddr::Ip(stuck), good.clone()],
+            Duration::from_millis(50),
+        );
+        let stream = race.connect_any().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        let start = Instant::now();
+        while connecting_to(stuck.port()) > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "loser still connecting"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
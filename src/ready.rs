@@ -0,0 +1,225 @@
+//! Waiting for nonblocking sockets to be ready, without an event loop
+
+use crate::{AbstractAddr, AbstractListener, AbstractStream};
+use std::io::Result;
+use std::time::Duration;
+
+#[cfg(unix)]
+mod imp {
+    use crate::{AbstractListener, AbstractStream};
+    use std::io::Result;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    pub(crate) type Handle = RawFd;
+    pub(crate) const READ: i16 = crate::sys::POLLIN;
+    pub(crate) const WRITE: i16 = crate::sys::POLLOUT;
+
+    /// What to poll for `s` to be readable, or writable if `write`
+    pub(crate) fn stream(s: &AbstractStream, write: bool) -> Handle {
+        match s {
+            AbstractStream::Pipe(p) if write => {
+                std::os::unix::io::AsRawFd::as_raw_fd(&p.write_fd())
+            }
+            _ => s.raw_fd(),
+        }
+    }
+
+    pub(crate) fn listener(l: &AbstractListener) -> Handle {
+        l.raw_fd()
+    }
+
+    pub(crate) fn poll(fds: &[(Handle, i16)], timeout: Option<Duration>) -> Result<Vec<bool>> {
+        let ready = crate::sys::poll_fds(fds, timeout)?;
+        Ok(ready.into_iter().map(|r| r != 0).collect())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use crate::{AbstractListener, AbstractStream};
+    use std::io::{Error, Result};
+    use std::os::windows::io::{AsRawSocket, RawSocket};
+    use std::time::Duration;
+
+    pub(crate) type Handle = RawSocket;
+    pub(crate) const READ: i16 = 0x100;
+    pub(crate) const WRITE: i16 = 0x10;
+
+    #[repr(C)]
+    struct WsaPollFd {
+        fd: usize,
+        events: i16,
+        revents: i16,
+    }
+
+    extern "system" {
+        fn WSAPoll(fds: *mut WsaPollFd, nfds: u32, timeout: i32) -> i32;
+    }
+
+    pub(crate) fn stream(s: &AbstractStream, _write: bool) -> Handle {
+        match s {
+            AbstractStream::Tcp(s) => s.as_raw_socket(),
+            AbstractStream::WebSocket(s) => s.get_ref().as_raw_socket(),
+        }
+    }
+
+    pub(crate) fn listener(l: &AbstractListener) -> Handle {
+        match l {
+            AbstractListener::Tcp(l) => l.as_raw_socket(),
+        }
+    }
+
+    pub(crate) fn poll(fds: &[(Handle, i16)], timeout: Option<Duration>) -> Result<Vec<bool>> {
+        let mut polled: Vec<_> = fds
+            .iter()
+            .map(|&(fd, events)| WsaPollFd {
+                fd: fd as usize,
+                events,
+                revents: 0,
+            })
+            .collect();
+        // rounded up, so as not to wake just before the timeout
+        let ms = timeout.map_or(-1, |t| {
+            t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+        });
+        if unsafe { WSAPoll(polled.as_mut_ptr(), polled.len() as u32, ms) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(polled.iter().map(|p| p.revents != 0).collect())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use crate::{AbstractListener, AbstractStream};
+    use std::io::{Error, ErrorKind, Result};
+    use std::time::Duration;
+
+    pub(crate) type Handle = ();
+    pub(crate) const READ: i16 = 0;
+    pub(crate) const WRITE: i16 = 0;
+
+    pub(crate) fn stream(_: &AbstractStream, _: bool) -> Handle {}
+
+    pub(crate) fn listener(_: &AbstractListener) -> Handle {}
+
+    pub(crate) fn poll(_: &[(Handle, i16)], _: Option<Duration>) -> Result<Vec<bool>> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "waiting for readiness is not supported on this platform",
+        ))
+    }
+}
+
+/// Something for [`select`] to wait on
+#[derive(Debug, Clone, Copy)]
+pub enum Selectable<'a> {
+    /// Ready once it can be read from
+    Read(&'a AbstractStream),
+    /// Ready once it can be written to
+    Write(&'a AbstractStream),
+    /// Ready once a connection is waiting to be accepted
+    Accept(&'a AbstractListener),
+}
+
+/// Waits until at least one of `items` is ready, or `timeout` passes,
+/// giving the indices of those that are, in order
+///
+/// Nothing is ready after a timeout, so the list is then empty.
+pub fn select(items: &[Selectable<'_>], timeout: Option<Duration>) -> Result<Vec<usize>> {
+    let fds: Vec<_> = items
+        .iter()
+        .map(|item| match item {
+            Selectable::Read(s) => (imp::stream(s, false), imp::READ),
+            Selectable::Write(s) => (imp::stream(s, true), imp::WRITE),
+            Selectable::Accept(l) => (imp::listener(l), imp::READ),
+        })
+        .collect();
+    let ready = imp::poll(&fds, timeout)?;
+    Ok(ready
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, r)| r.then_some(i))
+        .collect())
+}
+
+impl AbstractStream {
+    /// Blocks until the stream can be read from without blocking, or
+    /// `timeout` passes, giving whether it can
+    ///
+    /// A closed or failed connection counts as readable, since reading
+    /// then returns straight away. For a WebSocket, this only says that
+    /// bytes of the connection have arrived, which may not yet make a
+    /// whole frame header.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool> {
+        Ok(!select(&[Selectable::Read(self)], timeout)?.is_empty())
+    }
+
+    /// Blocks until the stream can be written to without blocking, or
+    /// `timeout` passes, giving whether it can
+    pub fn wait_writable(&self, timeout: Option<Duration>) -> Result<bool> {
+        Ok(!select(&[Selectable::Write(self)], timeout)?.is_empty())
+    }
+}
+
+impl AbstractListener {
+    /// Accepts a connection as [`accept`](Self::accept) does, then up
+    /// to `max` in all of those already waiting, without blocking again
+    ///
+    /// An error after the first connection ends the batch early rather
+    /// than losing what was accepted; it comes up again on the next
+    /// call if it lasts. Another thread accepting on the same listener
+    /// can take a connection between checking for it and accepting it,
+    /// leaving the call blocked until the next one arrives.
+    pub fn accept_multiple(&self, max: usize) -> Result<Vec<(AbstractStream, AbstractAddr)>> {
+        let mut accepted = vec![self.accept()?];
+        while accepted.len() < max {
+            match select(&[Selectable::Accept(self)], Some(Duration::ZERO)) {
+                Ok(ready) if !ready.is_empty() => {}
+                _ => break,
+            }
+            match self.accept() {
+                Ok(a) => accepted.push(a),
+                Err(_) => break,
+            }
+        }
+        Ok(accepted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+    use std::io::Write;
+
+    #[test]
+    fn waits() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut client = listener.local_addr().unwrap().connect_any().unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let short = Some(Duration::from_millis(20));
+        assert!(!server.wait_readable(short).unwrap());
+        assert!(server.wait_writable(short).unwrap());
+        client.write_all(b"x").unwrap();
+        assert!(server.wait_readable(None).unwrap());
+
+        let other = "127.0.0.1:0".bind_any().unwrap();
+        let items = [
+            Selectable::Accept(&listener),
+            Selectable::Read(&server),
+            Selectable::Accept(&other),
+        ];
+        assert_eq!(select(&items, None).unwrap(), [1]);
+        let _c = other.local_addr().unwrap().connect_any().unwrap();
+        assert_eq!(select(&items, short).unwrap(), [1, 2]);
+        assert!(select(&items[..1], short).unwrap().is_empty());
+
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<_> = (0..3).map(|_| addr.connect_any().unwrap()).collect();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(listener.accept_multiple(2).unwrap().len(), 2);
+        assert_eq!(listener.accept_multiple(8).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,108 @@
+//! Trying again after failing to connect
+
+use std::io::{ErrorKind, Result};
+use std::time::Duration;
+
+/// How [`connect_any_retry`](crate::AbstractToSocketAddrs::connect_any_retry)
+/// tries again
+///
+/// The delay before each retry starts at `initial_delay` and doubles
+/// after every attempt, up to `max_delay`. A `jitter` between 0 and 1
+/// randomly shortens each delay by up to that fraction, so that many
+/// clients restarting at once don't retry in lockstep.
+///
+/// Errors of kind `InvalidInput` and `Unsupported` mean the address
+/// itself is wrong, so they are returned without retrying.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The total number of attempts, including the first
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// 5 attempts, starting at 100ms between them, with half jitter
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Calls `f` until it succeeds or the policy gives up,
+    /// returning the last error in that case
+    pub fn retry<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(t) => return Ok(t),
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported) => {
+                    return Err(e)
+                }
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(_) => {}
+            }
+            std::thread::sleep(self.delay(attempt));
+            attempt += 1;
+        }
+    }
+
+    /// The delay after failed attempt number `attempt` (starting at 1)
+    fn delay(&self, attempt: u32) -> Duration {
+        let exp = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial_delay
+            .checked_mul(exp)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let r = (crate::random_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - jitter * r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(10));
+        assert_eq!(policy.delay(3), Duration::from_millis(40));
+        assert_eq!(policy.delay(40), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn gives_up() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let r: Result<()> = policy.retry(|| {
+            calls += 1;
+            Err(ErrorKind::ConnectionRefused.into())
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 3);
+
+        let closed = "127.0.0.1:0".bind_any().unwrap().local_addr().unwrap();
+        assert!(closed.connect_any_retry(&policy).is_err());
+        let e = "not an address".connect_any_retry(&policy).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
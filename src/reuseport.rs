@@ -0,0 +1,133 @@
+//! Choosing which of the listeners sharing a port gets each connection
+//! (Linux only)
+//!
+//! Listeners bound with [`ListenerBuilder::bind_reuseport`] form a group,
+//! and the kernel normally picks one by hashing each connection's
+//! addresses. A BPF program attached to any member replaces that choice
+//! for the whole group: it returns the index of the listener to use, in
+//! the order they were bound, and out of range answers fall back to the
+//! hash.
+//!
+//! [`ListenerBuilder::bind_reuseport`]: crate::ListenerBuilder::bind_reuseport
+
+use crate::{sys, AbstractListener};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+const SO_ATTACH_REUSEPORT_CBPF: i32 = 51;
+const SO_ATTACH_REUSEPORT_EBPF: i32 = 52;
+
+/// One instruction of a classic BPF program, laid out as `struct sock_filter`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BpfInstruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+struct Program {
+    len: u16,
+    filter: *const BpfInstruction,
+}
+
+/// Loads the number of the CPU handling the packet and returns it
+const BY_CPU: [BpfInstruction; 2] = [
+    // BPF_LD | BPF_W | BPF_ABS, from SKF_AD_OFF + SKF_AD_CPU
+    BpfInstruction {
+        code: 0x20,
+        jt: 0,
+        jf: 0,
+        k: 0xfffff000 + 36,
+    },
+    // BPF_RET | BPF_A
+    BpfInstruction {
+        code: 0x16,
+        jt: 0,
+        jf: 0,
+        k: 0,
+    },
+];
+
+impl AbstractListener {
+    fn reuseport_fd(&self) -> Result<i32> {
+        match self {
+            Self::Tcp(l) => Ok(l.as_raw_fd()),
+            Self::Unix(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unix listeners can't share an address",
+            )),
+        }
+    }
+
+    /// Attaches a classic BPF program choosing the listener in this
+    /// one's `SO_REUSEPORT` group that gets each connection
+    pub fn attach_reuseport_cbpf(&self, program: &[BpfInstruction]) -> Result<()> {
+        let len = u16::try_from(program.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "BPF program too long"))?;
+        let program = Program {
+            len,
+            filter: program.as_ptr(),
+        };
+        sys::setsockopt_raw(
+            self.reuseport_fd()?,
+            sys::SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_CBPF,
+            &program,
+        )
+    }
+
+    /// Like [`attach_reuseport_cbpf`](Self::attach_reuseport_cbpf),
+    /// with an eBPF program loaded elsewhere, of type
+    /// `BPF_PROG_TYPE_SOCKET_FILTER`
+    pub fn attach_reuseport_ebpf(&self, program: BorrowedFd<'_>) -> Result<()> {
+        sys::setsockopt_raw(
+            self.reuseport_fd()?,
+            sys::SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_EBPF,
+            &program.as_raw_fd(),
+        )
+    }
+
+    /// Gives each connection to the listener whose index in the group is
+    /// the CPU it arrived on, so with one listener per CPU and each
+    /// accepting thread pinned to its CPU, connections stay CPU-local
+    pub fn reuseport_by_cpu(&self) -> Result<()> {
+        self.attach_reuseport_cbpf(&BY_CPU)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AbstractToSocketAddrs, ListenerBuilder};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shared_port() {
+        let a = ListenerBuilder::new()
+            .bind_reuseport("127.0.0.1:0")
+            .unwrap();
+        let addr = a.local_addr().unwrap();
+        let b = ListenerBuilder::new()
+            .bind_reuseport(addr.to_string().as_str())
+            .unwrap();
+        assert_eq!(b.local_addr().unwrap(), addr);
+        b.reuseport_by_cpu().unwrap();
+
+        let _c = addr.connect_any().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        let start = Instant::now();
+        while a.accept().is_err() && b.accept().is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        // without SO_REUSEPORT, the address is taken
+        assert!(addr.bind_any().is_err());
+    }
+}
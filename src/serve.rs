@@ -0,0 +1,387 @@
+//! An accept loop that runs each connection on its own thread
+
+use crate::{AbstractAddr, AbstractListener, AbstractStream, AbstractToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::net::Shutdown;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// What [`AbstractListener::serve`] does with connections and errors
+pub trait Handler: Send + Sync + 'static {
+    /// Handles one connection, on a thread of its own
+    fn on_connection(&self, stream: AbstractStream, addr: AbstractAddr);
+
+    /// Hears about failed accepts and panicking connections, and
+    /// gives whether to keep serving
+    ///
+    /// The answer is ignored for panics, which only end their own
+    /// connection. Defaults to carrying on.
+    fn on_error(&self, error: &Error) -> bool {
+        let _ = error;
+        true
+    }
+
+    /// Called once serving has stopped and every connection has finished
+    fn on_shutdown(&self) {}
+}
+
+/// Stops [`AbstractListener::serve_until`] from accepting, then gives
+/// its connections until a deadline to finish
+///
+/// Clones share the same state, so one can be kept by whatever decides
+/// when to shut down while the other is passed to `serve_until`.
+#[derive(Debug, Clone, Default)]
+pub struct Drain {
+    shared: Arc<DrainState>,
+}
+
+#[derive(Debug, Default)]
+struct DrainState {
+    deadline: Mutex<Option<Instant>>,
+    /// Where to connect to wake up a blocked accept
+    wake: Mutex<Option<AbstractAddr>>,
+    /// Whose wait for a free slot to wake up
+    slots: Mutex<Option<Arc<Slots>>>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops accepting and lets connections run for up to `deadline`
+    /// more, after which the rest are shut down
+    pub fn start(&self, deadline: Duration) {
+        *self.shared.deadline.lock().unwrap() = Some(Instant::now() + deadline);
+        if let Some(addr) = self.shared.wake.lock().unwrap().as_ref() {
+            // a failure here means the listener is no longer accepting
+            let _ = addr.connect_any();
+        }
+        if let Some(slots) = self.shared.slots.lock().unwrap().as_ref() {
+            // taken so the wakeup can't fall between acquire's check
+            // and its wait
+            let _active = slots.active.lock().unwrap();
+            slots.freed.notify_all();
+        }
+    }
+
+    /// Whether [`start`](Self::start) has been called
+    pub fn is_draining(&self) -> bool {
+        self.deadline().is_some()
+    }
+
+    #[cfg(feature = "signals")]
+    pub(crate) fn same(&self, other: &Drain) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        *self.shared.deadline.lock().unwrap()
+    }
+}
+
+/// Tracks running connections so accepting can wait for a free slot,
+/// and so whatever is left at a drain's deadline can be shut down
+#[derive(Debug)]
+struct Slots {
+    active: Mutex<Active>,
+    freed: Condvar,
+}
+
+#[derive(Debug)]
+struct Active {
+    count: usize,
+    next: u64,
+    streams: HashMap<u64, AbstractStream>,
+}
+
+impl Slots {
+    /// Waits for a free slot and takes it, or gives `false` once
+    /// `drain` has started
+    fn acquire(&self, max: usize, drain: &Drain) -> bool {
+        let mut active = self.active.lock().unwrap();
+        while active.count >= max {
+            if drain.is_draining() {
+                return false;
+            }
+            active = self.freed.wait(active).unwrap();
+        }
+        active.count += 1;
+        true
+    }
+
+    /// Keeps a handle on `stream` to shut it down with
+    fn track(&self, stream: &AbstractStream) -> Option<u64> {
+        let clone = stream.try_clone().ok()?;
+        let mut active = self.active.lock().unwrap();
+        let id = active.next;
+        active.next += 1;
+        active.streams.insert(id, clone);
+        Some(id)
+    }
+
+    fn release(&self, id: Option<u64>) {
+        let mut active = self.active.lock().unwrap();
+        active.count -= 1;
+        if let Some(id) = id {
+            active.streams.remove(&id);
+        }
+        self.freed.notify_all();
+    }
+
+    /// Waits for every connection to finish, shutting down those
+    /// still running at `deadline`
+    fn wait_idle(&self, deadline: Option<Instant>) {
+        let mut active = self.active.lock().unwrap();
+        while active.count > 0 {
+            let left = match deadline {
+                Some(d) => d.saturating_duration_since(Instant::now()),
+                None => {
+                    active = self.freed.wait(active).unwrap();
+                    continue;
+                }
+            };
+            if left.is_zero() {
+                for stream in active.streams.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                while active.count > 0 {
+                    active = self.freed.wait(active).unwrap();
+                }
+                break;
+            }
+            active = self.freed.wait_timeout(active, left).unwrap().0;
+        }
+    }
+}
+
+/// Accept errors that mean the process or system is out of something,
+/// and won't go away by accepting again straight away
+fn exhausted(e: &Error) -> bool {
+    // EMFILE, ENFILE, ENOBUFS and ENOMEM
+    #[cfg(target_os = "linux")]
+    const CODES: [i32; 4] = [24, 23, 105, 12];
+    #[cfg(all(unix, not(target_os = "linux")))]
+    const CODES: [i32; 4] = [24, 23, 55, 12];
+    // WSAEMFILE and WSAENOBUFS
+    #[cfg(windows)]
+    const CODES: [i32; 2] = [10024, 10055];
+    #[cfg(not(any(unix, windows)))]
+    const CODES: [i32; 0] = [];
+    crate::error::os_code(e).is_some_and(|c| CODES.contains(&c))
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+impl AbstractListener {
+    /// Accepts connections and gives each to `handler` on a new thread,
+    /// with at most `max_connections` running at once
+    ///
+    /// Once that many are running, nothing more is accepted until one
+    /// finishes, leaving new connections in the kernel's backlog. When
+    /// accepting fails because descriptors or memory ran out, serving
+    /// pauses for a moment before trying again, backing off up to a
+    /// second while the failures continue, rather than spinning.
+    ///
+    /// Only returns once [`Handler::on_error`] says to stop, giving
+    /// that error after the remaining connections have finished and
+    /// [`Handler::on_shutdown`] has run.
+    pub fn serve<H: Handler>(&self, handler: H, max_connections: usize) -> Result<()> {
+        self.serve_until(handler, max_connections, &Drain::new())
+    }
+
+    /// Like [`serve`](Self::serve), but also returns `Ok` once `drain`
+    /// is started and the connections have finished or been shut down
+    ///
+    /// A blocked accept is woken by connecting to the listener, so the
+    /// listener's address must be one that can be connected to.
+    pub fn serve_until<H: Handler>(
+        &self,
+        handler: H,
+        max_connections: usize,
+        drain: &Drain,
+    ) -> Result<()> {
+        *drain.shared.wake.lock().unwrap() = self.local_addr().ok();
+        let handler = Arc::new(handler);
+        let slots = Arc::new(Slots {
+            active: Mutex::new(Active {
+                count: 0,
+                next: 0,
+                streams: HashMap::new(),
+            }),
+            freed: Condvar::new(),
+        });
+        *drain.shared.slots.lock().unwrap() = Some(slots.clone());
+        let mut backoff = MIN_BACKOFF;
+        let result = loop {
+            if drain.is_draining() {
+                break Ok(());
+            }
+            if !slots.acquire(max_connections.max(1), drain) {
+                break Ok(());
+            }
+            let accepted = self.accept();
+            if drain.is_draining() {
+                slots.release(None);
+                break Ok(());
+            }
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    slots.release(None);
+                    if !handler.on_error(&e) {
+                        break Err(e);
+                    }
+                    if exhausted(&e) {
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            let id = slots.track(&stream);
+            let (h, s) = (handler.clone(), slots.clone());
+            let spawned = std::thread::Builder::new().spawn(move || {
+                let result = catch_unwind(AssertUnwindSafe(|| {
+                    h.on_connection(stream, addr);
+                }));
+                if let Err(payload) = result {
+                    h.on_error(&Error::other(format!(
+                        "connection handler panicked: {}",
+                        panic_message(&*payload)
+                    )));
+                }
+                s.release(id);
+            });
+            if let Err(e) = spawned {
+                slots.release(id);
+                if !handler.on_error(&e) {
+                    break Err(e);
+                }
+            }
+        };
+        *drain.shared.wake.lock().unwrap() = None;
+        *drain.shared.slots.lock().unwrap() = None;
+        slots.wait_idle(drain.deadline());
+        handler.on_shutdown();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counting {
+        errors: AtomicUsize,
+        shutdown: AtomicUsize,
+    }
+
+    impl Handler for Arc<Counting> {
+        fn on_connection(&self, mut stream: AbstractStream, _addr: AbstractAddr) {
+            let mut b = [0u8];
+            stream.read_exact(&mut b).unwrap();
+            if b[0] == b'p' {
+                panic!("asked to");
+            }
+            stream.write_all(&b).unwrap();
+        }
+
+        fn on_error(&self, error: &Error) -> bool {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+            error.kind() != ErrorKind::WouldBlock
+        }
+
+        fn on_shutdown(&self) {
+            self.shutdown.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn serves() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let counts = Arc::new(Counting::default());
+
+        let mut c = addr.connect_any().unwrap();
+        c.write_all(b"a").unwrap();
+        let mut p = addr.connect_any().unwrap();
+        p.write_all(b"p").unwrap();
+
+        // stops once the backlog is empty
+        listener.set_nonblocking(true).unwrap();
+        let e = listener.serve(counts.clone(), 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        // the panic, then the WouldBlock
+        assert_eq!(counts.errors.load(Ordering::SeqCst), 2);
+        assert_eq!(counts.shutdown.load(Ordering::SeqCst), 1);
+
+        let mut b = [0u8];
+        c.read_exact(&mut b).unwrap();
+        assert_eq!(&b, b"a");
+        assert_eq!(p.read(&mut b).unwrap(), 0);
+    }
+
+    #[test]
+    fn drains() {
+        struct Sleepy;
+        impl Handler for Sleepy {
+            fn on_connection(&self, mut stream: AbstractStream, _addr: AbstractAddr) {
+                // only returns once the drain shuts the stream down
+                let _ = stream.read(&mut [0u8]);
+            }
+        }
+
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let drain = Drain::new();
+        let server = {
+            let drain = drain.clone();
+            std::thread::spawn(move || listener.serve_until(Sleepy, 4, &drain))
+        };
+        let c = addr.connect_any().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        drain.start(Duration::from_millis(100));
+        server.join().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        drop(c);
+
+        // with every slot taken, the loop waits for one rather than
+        // accepting, and starting the drain has to wake that wait
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let drain = Drain::new();
+        let (done, finished) = std::sync::mpsc::channel();
+        {
+            let drain = drain.clone();
+            std::thread::spawn(move || {
+                let _ = done.send(listener.serve_until(Sleepy, 1, &drain));
+            });
+        }
+        let _c = addr.connect_any().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        drain.start(Duration::from_millis(100));
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("serve_until didn't return after the deadline")
+            .unwrap();
+    }
+}
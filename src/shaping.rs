@@ -0,0 +1,117 @@
+//! Limiting a stream's throughput
+
+use crate::AbstractStream;
+use std::io::{Read, Result, Write};
+use std::time::{Duration, Instant};
+
+/// Caps reads and writes each to `bytes_per_sec`, with bursts of up
+/// to a second's worth
+///
+/// Calls sleep until there's allowance for at least one byte, and are
+/// then cut short to the allowance.
+#[derive(Debug)]
+pub struct RateLimited<S = AbstractStream> {
+    inner: S,
+    read: Bucket,
+    write: Bucket,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        Bucket {
+            rate: bytes_per_sec.max(1) as f64,
+            tokens: bytes_per_sec.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Waits until at least a byte may pass and gives how many may
+    fn take(&mut self, want: usize) -> usize {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.last = now;
+            if self.tokens >= 1.0 {
+                return want.min(self.tokens as usize);
+            }
+            std::thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+    }
+
+    fn used(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+impl<S> RateLimited<S> {
+    pub fn new(inner: S, bytes_per_sec: u64) -> Self {
+        RateLimited {
+            inner,
+            read: Bucket::new(bytes_per_sec),
+            write: Bucket::new(bytes_per_sec),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for RateLimited<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = self.read.take(buf.len());
+        let n = self.inner.read(&mut buf[..allowed])?;
+        self.read.used(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for RateLimited<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = self.write.take(buf.len());
+        let n = self.inner.write(&buf[..allowed])?;
+        self.write.used(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles() {
+        let mut w = RateLimited::new(std::io::sink(), 1000);
+        let start = Instant::now();
+        // a second's burst, then another 500 bytes at the limited rate
+        w.write_all(&[0u8; 1500]).unwrap();
+        let took = start.elapsed();
+        assert!(took >= Duration::from_millis(400), "{:?}", took);
+        assert!(took < Duration::from_secs(3), "{:?}", took);
+    }
+}
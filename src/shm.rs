@@ -0,0 +1,486 @@
+//! Streams between processes on the same host over shared memory
+//! (Linux only, with the `shm` feature)
+//!
+//! The two ends meet by name: the first to connect to `shm:NAME`
+//! creates `/dev/shm/anysocket-NAME`, and the second maps it and
+//! removes the name, so the next connection makes a new pair. Each
+//! direction is a ring buffer in the mapping, and a side that has to
+//! wait for data or room sleeps on a futex, which the other side only
+//! wakes when someone is actually waiting.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MAGIC: u32 = 0x616e_7973;
+/// Bytes each direction can hold before writes have to wait
+const CAPACITY: u32 = 1 << 16;
+/// Room for the header, ahead of the rings
+const HEADER: usize = 4096;
+const LEN: usize = HEADER + 2 * CAPACITY as usize;
+
+/// The reading end has shut down or gone away
+const READER_GONE: u32 = 1;
+/// The writing end has shut down or gone away
+const WRITER_GONE: u32 = 2;
+
+#[repr(C)]
+struct Header {
+    magic: AtomicU32,
+    /// Set once the second end has mapped the segment
+    joined: AtomicU32,
+    rings: [Ring; 2],
+}
+
+#[repr(C)]
+struct Ring {
+    /// Bytes ever written, wrapping
+    head: AtomicU32,
+    /// Bytes ever read, wrapping
+    tail: AtomicU32,
+    state: AtomicU32,
+    /// Changes whenever either side makes progress, for futexes
+    seq: AtomicU32,
+    waiters: AtomicU32,
+}
+
+/// One end of a shared memory stream
+pub struct ShmStream {
+    map: *mut u8,
+    file: File,
+    /// The ring this end writes to; it reads from the other
+    side: usize,
+    /// Where the creator published the segment, until it was joined
+    path: Option<PathBuf>,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+}
+
+// the mapping is only touched through atomics, and each ring's data
+// only by one reader and one writer, which &mut self keeps to one each
+unsafe impl Send for ShmStream {}
+unsafe impl Sync for ShmStream {}
+
+impl std::fmt::Debug for ShmStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShmStream")
+            .field("fd", &self.file.as_raw_fd())
+            .field("side", &self.side)
+            .finish()
+    }
+}
+
+fn segment_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a valid shared memory stream name", name),
+        ));
+    }
+    Ok(PathBuf::from(format!("/dev/shm/anysocket-{}", name)))
+}
+
+impl ShmStream {
+    /// Creates the stream called `name`, or joins it if the other end
+    /// has already created it
+    pub fn connect(name: &str) -> Result<ShmStream> {
+        let path = segment_path(name)?;
+        loop {
+            match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => return Self::join(file, &path),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            match Self::create(&path) {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Sets the segment up under a name of its own, so it's never seen
+    /// half done, then publishes it, failing with `AlreadyExists` if
+    /// another pair got there first
+    fn create(path: &std::path::Path) -> Result<ShmStream> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let temp = PathBuf::from(format!(
+            "{}.{}-{}.new",
+            path.display(),
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        // only this user can map it to read the stream or join as the peer
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        let stream = file.set_len(LEN as u64).and_then(|()| Self::map(file, 0));
+        let published = stream.and_then(|stream| {
+            stream.header().magic.store(MAGIC, Ordering::Release);
+            std::fs::hard_link(&temp, path)?;
+            Ok(stream)
+        });
+        let _ = std::fs::remove_file(&temp);
+        let mut stream = published?;
+        stream.path = Some(path.to_owned());
+        Ok(stream)
+    }
+
+    fn join(file: File, path: &std::path::Path) -> Result<ShmStream> {
+        if file.metadata()?.len() < LEN as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "not an anysocket shared memory segment",
+            ));
+        }
+        let stream = Self::map(file, 1)?;
+        let header = stream.header();
+        if header.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "not an anysocket shared memory segment",
+            ));
+        }
+        if header.joined.swap(1, Ordering::SeqCst) != 0 {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                "shared memory stream already has both ends",
+            ));
+        }
+        let _ = std::fs::remove_file(path);
+        Ok(stream)
+    }
+
+    fn map(file: File, side: usize) -> Result<ShmStream> {
+        let map = crate::sys::map_shared(file.as_raw_fd(), LEN)?;
+        Ok(ShmStream {
+            map,
+            file,
+            side,
+            path: None,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        })
+    }
+
+    fn header(&self) -> &Header {
+        // the mapping starts with the header and lives as long as self
+        unsafe { &*(self.map as *const Header) }
+    }
+
+    fn data(&self, ring: usize) -> *mut u8 {
+        unsafe { self.map.add(HEADER + ring * CAPACITY as usize) }
+    }
+
+    fn incoming(&self) -> (&Ring, *mut u8) {
+        (
+            &self.header().rings[1 - self.side],
+            self.data(1 - self.side),
+        )
+    }
+
+    fn outgoing(&self) -> (&Ring, *mut u8) {
+        (&self.header().rings[self.side], self.data(self.side))
+    }
+
+    /// How many bytes are in `ring` between `tail` and `head`, which the
+    /// peer can write anything to, so checked before they're trusted
+    fn filled(head: u32, tail: u32) -> Result<usize> {
+        match head.wrapping_sub(tail) {
+            n if n <= CAPACITY => Ok(n as usize),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "shared memory stream's ring is corrupt",
+            )),
+        }
+    }
+
+    fn notify(ring: &Ring) {
+        ring.seq.fetch_add(1, Ordering::SeqCst);
+        if ring.waiters.load(Ordering::SeqCst) != 0 {
+            crate::sys::futex_wake(&ring.seq);
+        }
+    }
+
+    /// Sleeps until `ring` changes from how it was at `seq`, giving
+    /// `WouldBlock` if nonblocking or once `deadline` passes
+    fn wait(&self, ring: &Ring, seq: u32, deadline: Option<Instant>) -> Result<()> {
+        let would_block = || Error::new(ErrorKind::WouldBlock, "shared memory stream not ready");
+        if self.nonblocking.load(Ordering::Relaxed) {
+            return Err(would_block());
+        }
+        let timeout = match deadline {
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Some(left),
+                _ => return Err(would_block()),
+            },
+            None => None,
+        };
+        ring.waiters.fetch_add(1, Ordering::SeqCst);
+        let r = if ring.seq.load(Ordering::SeqCst) == seq {
+            crate::sys::futex_wait(&ring.seq, seq, timeout)
+        } else {
+            Ok(false)
+        };
+        ring.waiters.fetch_sub(1, Ordering::SeqCst);
+        r.map(drop)
+    }
+
+    fn receive(&self, buf: &mut [u8], consume: bool) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (ring, data) = self.incoming();
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|t| Instant::now() + t);
+        loop {
+            let seq = ring.seq.load(Ordering::SeqCst);
+            // before head, so that anything written before a shutdown
+            // is seen along with it
+            let state = ring.state.load(Ordering::Acquire);
+            let head = ring.head.load(Ordering::Acquire);
+            let tail = ring.tail.load(Ordering::Relaxed);
+            let available = Self::filled(head, tail)?;
+            if available > 0 {
+                let n = available.min(buf.len());
+                copy_out(data, tail, &mut buf[..n]);
+                if consume {
+                    ring.tail
+                        .store(tail.wrapping_add(n as u32), Ordering::Release);
+                    Self::notify(ring);
+                }
+                return Ok(n);
+            }
+            if state != 0 {
+                return Ok(0);
+            }
+            self.wait(ring, seq, deadline)?;
+        }
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        self.receive(buf, false)
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (ring, data) = self.outgoing();
+        let deadline = self
+            .write_timeout
+            .lock()
+            .unwrap()
+            .map(|t| Instant::now() + t);
+        loop {
+            let seq = ring.seq.load(Ordering::SeqCst);
+            if ring.state.load(Ordering::Acquire) != 0 {
+                return Err(Error::new(
+                    ErrorKind::BrokenPipe,
+                    "shared memory stream closed",
+                ));
+            }
+            let head = ring.head.load(Ordering::Relaxed);
+            let tail = ring.tail.load(Ordering::Acquire);
+            let room = CAPACITY as usize - Self::filled(head, tail)?;
+            if room > 0 {
+                let n = room.min(buf.len());
+                copy_in(data, head, &buf[..n]);
+                ring.head
+                    .store(head.wrapping_add(n as u32), Ordering::Release);
+                Self::notify(ring);
+                return Ok(n);
+            }
+            self.wait(ring, seq, deadline)?;
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        if how != Shutdown::Write {
+            let (ring, _) = self.incoming();
+            ring.state.fetch_or(READER_GONE, Ordering::AcqRel);
+            Self::notify(ring);
+        }
+        if how != Shutdown::Read {
+            let (ring, _) = self.outgoing();
+            ring.state.fetch_or(WRITER_GONE, Ordering::AcqRel);
+            Self::notify(ring);
+        }
+        Ok(())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn is_nonblocking(&self) -> bool {
+        self.nonblocking.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        check_timeout(dur)?;
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        check_timeout(dur)?;
+        *self.write_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+}
+
+/// Like std, which refuses a zero timeout
+fn check_timeout(dur: Option<Duration>) -> Result<()> {
+    if dur == Some(Duration::ZERO) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+fn copy_out(data: *mut u8, from: u32, buf: &mut [u8]) {
+    let at = (from % CAPACITY) as usize;
+    let first = buf.len().min(CAPACITY as usize - at);
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.add(at), buf.as_mut_ptr(), first);
+        std::ptr::copy_nonoverlapping(data, buf.as_mut_ptr().add(first), buf.len() - first);
+    }
+}
+
+fn copy_in(data: *mut u8, to: u32, buf: &[u8]) {
+    let at = (to % CAPACITY) as usize;
+    let first = buf.len().min(CAPACITY as usize - at);
+    unsafe {
+        std::ptr::copy_nonoverlapping(buf.as_ptr(), data.add(at), first);
+        std::ptr::copy_nonoverlapping(buf.as_ptr().add(first), data, buf.len() - first);
+    }
+}
+
+impl Read for ShmStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.receive(buf, true)
+    }
+}
+
+impl Write for ShmStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ShmStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+        if let Some(path) = &self.path {
+            // nobody joined, so don't leave the name for anyone to
+            if self.header().joined.swap(1, Ordering::SeqCst) == 0 {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        unsafe { crate::sys::unmap(self.map, LEN) };
+    }
+}
+
+/// The segment's descriptor, which is always ready to poll
+impl AsFd for ShmStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for ShmStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AbstractStream, AbstractToSocketAddrs};
+
+    #[test]
+    fn pairs_up() {
+        let name = format!("shm:test-{}", std::process::id());
+        let mut a = name.connect_any().unwrap();
+        let meta = std::fs::metadata(segment_path(&name[4..]).unwrap()).unwrap();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777,
+            0o600
+        );
+        let mut b = name.connect_any().unwrap();
+        assert!(matches!(a, AbstractStream::Shm(_)));
+
+        // more than a ring holds, so the writer has to wait for room
+        let big = vec![7u8; CAPACITY as usize * 3];
+        let writer = std::thread::spawn(move || {
+            a.write_all(&big).unwrap();
+            a
+        });
+        let mut got = vec![0u8; CAPACITY as usize * 3];
+        b.read_exact(&mut got).unwrap();
+        assert!(got.iter().all(|&b| b == 7));
+        let mut a = writer.join().unwrap();
+
+        b.write_all(b"hi").unwrap();
+        let mut two = [0u8; 2];
+        assert_eq!(a.peek(&mut two).unwrap(), 2);
+        a.read_exact(&mut two).unwrap();
+        assert_eq!(&two, b"hi");
+
+        a.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let e = a.read(&mut two).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+
+        // a peer that scribbles on the ring gets an error, not a copy
+        // past the end of the mapping
+        if let AbstractStream::Shm(shm) = &a {
+            let (ring, _) = shm.outgoing();
+            let head = ring.head.load(Ordering::SeqCst);
+            let tail = ring
+                .tail
+                .swap(head.wrapping_sub(CAPACITY + 1), Ordering::SeqCst);
+            let mut big = vec![0u8; CAPACITY as usize * 2];
+            assert_eq!(b.read(&mut big).unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(shm.send(b"x").unwrap_err().kind(), ErrorKind::InvalidData);
+            ring.tail.store(tail, Ordering::SeqCst);
+        }
+
+        drop(b);
+        assert_eq!(a.read(&mut two).unwrap(), 0);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        // the pair used the name up, so this one waits for a partner
+        let lonely = name.connect_any().unwrap();
+        drop(lonely);
+        assert!(!segment_path(&name[4..]).unwrap().exists());
+    }
+}
@@ -0,0 +1,199 @@
+//! Draining [`AbstractListener::serve_until_signal`] on SIGINT and
+//! SIGTERM, or Ctrl-C on Windows
+
+use crate::serve::{Drain, Handler};
+use crate::AbstractListener;
+use std::io::Result;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The drains to start on a signal, with their grace periods
+static DRAINS: Mutex<Vec<(Drain, Duration)>> = Mutex::new(Vec::new());
+/// What to put back once the last drain is gone, while the handler is
+/// installed; taken before `DRAINS` when both are needed
+static INSTALLED: Mutex<Option<imp::Previous>> = Mutex::new(None);
+
+fn terminated() {
+    for (drain, grace) in DRAINS.lock().unwrap().iter() {
+        drain.start(*grace);
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use crate::sys;
+    use std::io::{ErrorKind, Read, Result};
+    use std::os::raw::c_int;
+    use std::os::unix::io::{IntoRawFd, RawFd};
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    pub(super) type Previous = sys::Dispositions;
+
+    /// The write end of the pipe that wakes the watcher thread
+    static WAKE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(_: c_int) {
+        sys::write_byte(WAKE.load(Ordering::Relaxed) as RawFd);
+    }
+
+    /// Signal handlers can't take locks, so they only write to a pipe,
+    /// and a thread does the rest
+    ///
+    /// The pipe and thread outlive the handler, so one still running
+    /// after [`uninstall`] never writes to a closed descriptor.
+    pub(super) fn install() -> Result<Previous> {
+        if WAKE.load(Ordering::Relaxed) < 0 {
+            watch()?;
+        }
+        sys::on_terminate(on_signal)
+    }
+
+    pub(super) fn uninstall(previous: &Previous) -> Result<()> {
+        sys::restore_terminate(previous)
+    }
+
+    fn watch() -> Result<()> {
+        let (read, write) = sys::pipe_fds()?;
+        let mut read = std::fs::File::from(read);
+        std::thread::Builder::new()
+            .name("anysocket-signals".into())
+            .spawn(move || loop {
+                match read.read(&mut [0u8]) {
+                    Ok(1) => super::terminated(),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    _ => break,
+                }
+            })?;
+        // kept open for as long as the handler might run
+        WAKE.store(write.into_raw_fd(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io::{Error, Result};
+
+    type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+
+    /// Runs on a thread of its own, so can do the work itself
+    unsafe extern "system" fn on_ctrl(_event: u32) -> i32 {
+        super::terminated();
+        1
+    }
+
+    pub(super) type Previous = ();
+
+    pub(super) fn install() -> Result<Previous> {
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn uninstall(_: &Previous) -> Result<()> {
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 0) } == 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::io::{Error, ErrorKind, Result};
+
+    pub(super) type Previous = ();
+
+    pub(super) fn install() -> Result<Previous> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "signals are not supported on this platform",
+        ))
+    }
+
+    pub(super) fn uninstall(_: &Previous) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl AbstractListener {
+    /// Like [`serve_until`](Self::serve_until), draining with `grace`
+    /// to finish once the process is asked to terminate
+    ///
+    /// Takes over SIGINT and SIGTERM, or Ctrl-C and closing the console
+    /// on Windows, while serving; once the last listener serving this
+    /// way returns, they do whatever they did before.
+    pub fn serve_until_signal<H: Handler>(
+        &self,
+        handler: H,
+        max_connections: usize,
+        grace: Duration,
+    ) -> Result<()> {
+        let drain = Drain::new();
+        {
+            let mut installed = INSTALLED.lock().unwrap();
+            if installed.is_none() {
+                *installed = Some(imp::install()?);
+            }
+            DRAINS.lock().unwrap().push((drain.clone(), grace));
+        }
+        let result = self.serve_until(handler, max_connections, &drain);
+        let mut installed = INSTALLED.lock().unwrap();
+        let mut drains = DRAINS.lock().unwrap();
+        drains.retain(|(d, _)| !d.same(&drain));
+        if drains.is_empty() {
+            // kept if this fails, so a later attempt can still restore it
+            if let Some(previous) = &*installed {
+                imp::uninstall(previous)?;
+                *installed = None;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{AbstractAddr, AbstractStream, AbstractToSocketAddrs};
+
+    struct Nothing;
+    impl Handler for Nothing {
+        fn on_connection(&self, _stream: AbstractStream, _addr: AbstractAddr) {}
+    }
+
+    #[test]
+    fn drains_on_sigterm() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let before = dispositions();
+        let server = std::thread::spawn(move || {
+            listener.serve_until_signal(Nothing, 4, Duration::from_secs(1))
+        });
+        // wait until the server's drain is there for the signal to start
+        while DRAINS.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let status = std::process::Command::new("kill")
+            .arg("-TERM")
+            .arg(std::process::id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        server.join().unwrap().unwrap();
+        assert!(INSTALLED.lock().unwrap().is_none());
+        assert_eq!(dispositions(), before);
+    }
+
+    /// What SIGINT and SIGTERM currently do, found by briefly changing them
+    fn dispositions() -> crate::sys::Dispositions {
+        extern "C" fn ignore(_: std::os::raw::c_int) {}
+        let previous = crate::sys::on_terminate(ignore).unwrap();
+        crate::sys::restore_terminate(&previous).unwrap();
+        previous
+    }
+}
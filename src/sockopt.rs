@@ -0,0 +1,580 @@
+//! TCP and IP options that std doesn't expose
+
+use crate::{AbstractDatagram, AbstractListener, AbstractStream};
+#[cfg(unix)]
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::net::SocketAddr as IpSocketAddr;
+
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+#[cfg(unix)]
+fn not_ip() -> Error {
+    Error::new(ErrorKind::Unsupported, "Unix sockets have no IP options")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn no_congestion() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "choosing congestion control needs Linux or FreeBSD",
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn no_md5() -> Error {
+    Error::new(ErrorKind::Unsupported, "TCP MD5 signatures need Linux")
+}
+
+/// Sets `peer`'s key on `fd`, mapping an IPv4 peer into IPv6 on an
+/// IPv6 socket, since that's how the kernel sees its address
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_md5_key(fd: RawFd, ipv6: bool, peer: std::net::IpAddr, key: &[u8]) -> Result<()> {
+    let peer = match peer {
+        std::net::IpAddr::V4(v4) if ipv6 => std::net::IpAddr::V6(v4.to_ipv6_mapped()),
+        _ => peer,
+    };
+    crate::sys::set_md5sig(fd, &IpSocketAddr::new(peer, 0), key)
+}
+
+#[cfg(not(unix))]
+fn no_ip_options() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "IP options are not supported on this platform",
+    )
+}
+
+/// Options that only IPv6 sockets have
+#[cfg(unix)]
+fn ipv6_fd(fd: RawFd, ipv6: bool) -> Result<RawFd> {
+    if ipv6 {
+        Ok(fd)
+    } else {
+        Err(Error::new(ErrorKind::InvalidInput, "not an IPv6 socket"))
+    }
+}
+
+#[cfg(unix)]
+fn set_hop_limit(fd: RawFd, hops: u32) -> Result<()> {
+    let hops = std::os::raw::c_int::try_from(hops)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "hop limit out of range"))?;
+    crate::sys::setsockopt_raw(
+        fd,
+        crate::sys::IPPROTO_IPV6,
+        crate::sys::IPV6_UNICAST_HOPS,
+        &hops,
+    )
+}
+
+#[cfg(unix)]
+fn hop_limit(fd: RawFd) -> Result<u32> {
+    crate::sys::getsockopt_int(fd, crate::sys::IPPROTO_IPV6, crate::sys::IPV6_UNICAST_HOPS)
+        .map(|v| v as u32)
+}
+
+impl AbstractStream {
+    /// The descriptor of the IP socket underneath
+    #[cfg(unix)]
+    fn ip_fd(&self) -> Result<RawFd> {
+        match self {
+            Self::Tcp(_) | Self::WebSocket(_) => Ok(self.raw_fd()),
+            _ => Err(not_ip()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn is_ipv6(&self) -> Result<bool> {
+        match self {
+            Self::Tcp(s) => Ok(s.local_addr()?.is_ipv6()),
+            Self::WebSocket(s) => Ok(s.get_ref().local_addr()?.is_ipv6()),
+            Self::Unix(_) => Err(not_ip()),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(_) => Err(not_ip()),
+            Self::Pipe(_) => Err(not_ip()),
+        }
+    }
+
+    /// Marks packets with `tos`, the byte holding the DSCP code point
+    /// in its top six bits and ECN in the bottom two
+    ///
+    /// This is `IP_TOS`, or `IPV6_TCLASS` on an IPv6 socket. Marking
+    /// only matters to networks that classify traffic by it.
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        #[cfg(unix)]
+        return crate::sys::set_tos(self.ip_fd()?, self.is_ipv6()?, tos);
+        #[cfg(not(unix))]
+        {
+            let _ = tos;
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn tos(&self) -> Result<u8> {
+        #[cfg(unix)]
+        return crate::sys::tos(self.ip_fd()?, self.is_ipv6()?);
+        #[cfg(not(unix))]
+        Err(no_ip_options())
+    }
+
+    /// The hop limit of unicast packets, the IPv6 counterpart of the
+    /// time-to-live
+    ///
+    /// Fails with `InvalidInput` on IPv4 sockets.
+    pub fn set_hop_limit(&self, hops: u32) -> Result<()> {
+        #[cfg(unix)]
+        return set_hop_limit(ipv6_fd(self.ip_fd()?, self.is_ipv6()?)?, hops);
+        #[cfg(not(unix))]
+        {
+            let _ = hops;
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn hop_limit(&self) -> Result<u32> {
+        #[cfg(unix)]
+        return hop_limit(ipv6_fd(self.ip_fd()?, self.is_ipv6()?)?);
+        #[cfg(not(unix))]
+        Err(no_ip_options())
+    }
+
+    /// Where the connection was headed before an iptables `REDIRECT`
+    /// or `DNAT` rule sent it here, for transparent proxies
+    ///
+    /// Fails for connections that weren't redirected, and with
+    /// `Unsupported` except on Linux.
+    pub fn original_dst(&self) -> Result<IpSocketAddr> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return crate::sys::original_dst(self.ip_fd()?, self.is_ipv6()?);
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "finding the original destination needs Linux",
+        ))
+    }
+
+    /// Chooses the TCP congestion control algorithm, such as `"bbr"`
+    /// or `"cubic"`
+    ///
+    /// The kernel must have the algorithm available, and on Linux an
+    /// unprivileged process may only pick those listed in
+    /// `net.ipv4.tcp_allowed_congestion_control`.
+    pub fn set_congestion(&self, name: &str) -> Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        return crate::sys::set_congestion(self.ip_fd()?, name);
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+        {
+            let _ = name;
+            Err(no_congestion())
+        }
+    }
+
+    /// The TCP congestion control algorithm in use
+    pub fn congestion(&self) -> Result<String> {
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        return crate::sys::congestion(self.ip_fd()?);
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+        Err(no_congestion())
+    }
+}
+
+impl AbstractListener {
+    #[cfg(unix)]
+    fn ip_fd(&self) -> Result<RawFd> {
+        match self {
+            Self::Unix(_) => Err(not_ip()),
+            Self::Tcp(_) => Ok(self.raw_fd()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn is_ipv6(&self) -> Result<bool> {
+        match self {
+            Self::Tcp(l) => Ok(l.local_addr()?.is_ipv6()),
+            Self::Unix(_) => Err(not_ip()),
+        }
+    }
+
+    /// Marks the packets of accepted connections, like
+    /// [`AbstractStream::set_tos`]
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        #[cfg(unix)]
+        return crate::sys::set_tos(self.ip_fd()?, self.is_ipv6()?, tos);
+        #[cfg(not(unix))]
+        {
+            let _ = tos;
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn tos(&self) -> Result<u8> {
+        #[cfg(unix)]
+        return crate::sys::tos(self.ip_fd()?, self.is_ipv6()?);
+        #[cfg(not(unix))]
+        Err(no_ip_options())
+    }
+
+    /// The hop limit of unicast packets, the IPv6 counterpart of the
+    /// time-to-live
+    ///
+    /// Fails with `InvalidInput` on IPv4 sockets.
+    pub fn set_hop_limit(&self, hops: u32) -> Result<()> {
+        #[cfg(unix)]
+        return set_hop_limit(ipv6_fd(self.ip_fd()?, self.is_ipv6()?)?, hops);
+        #[cfg(not(unix))]
+        {
+            let _ = hops;
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn hop_limit(&self) -> Result<u32> {
+        #[cfg(unix)]
+        return hop_limit(ipv6_fd(self.ip_fd()?, self.is_ipv6()?)?);
+        #[cfg(not(unix))]
+        Err(no_ip_options())
+    }
+
+    /// Requires TCP MD5 signatures (RFC 2385), made with `key`, on
+    /// connections from `peer`, or stops requiring them if `key` is empty
+    ///
+    /// Each peer has its own key, and connections from peers without one
+    /// are accepted unsigned. Keys are at most 80 bytes. Fails with
+    /// `Unsupported` except on Linux.
+    pub fn set_md5_key(&self, peer: IpAddr, key: &[u8]) -> Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return set_md5_key(self.ip_fd()?, self.is_ipv6()?, peer, key);
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let _ = (peer, key);
+            Err(no_md5())
+        }
+    }
+
+    /// Chooses the congestion control algorithm accepted connections
+    /// start with, like [`AbstractStream::set_congestion`]
+    pub fn set_congestion(&self, name: &str) -> Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        return crate::sys::set_congestion(self.ip_fd()?, name);
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+        {
+            let _ = name;
+            Err(no_congestion())
+        }
+    }
+}
+
+impl AbstractDatagram {
+    #[cfg(unix)]
+    fn ipv6_fd(&self) -> Result<RawFd> {
+        let s = self.udp()?;
+        ipv6_fd(s.as_raw_fd(), s.local_addr()?.is_ipv6())
+    }
+
+    /// Like [`AbstractStream::set_hop_limit`]
+    pub fn set_hop_limit(&self, hops: u32) -> Result<()> {
+        #[cfg(unix)]
+        return set_hop_limit(self.ipv6_fd()?, hops);
+        #[cfg(not(unix))]
+        {
+            let _ = hops;
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn hop_limit(&self) -> Result<u32> {
+        #[cfg(unix)]
+        return hop_limit(self.ipv6_fd()?);
+        #[cfg(not(unix))]
+        Err(no_ip_options())
+    }
+
+    /// Whether received datagrams come with their destination address
+    /// and arrival interface (`IPV6_RECVPKTINFO`), on IPv6 sockets
+    ///
+    /// On Linux, `recv_msg` gives them as
+    /// [`ReceivedControl::PacketInfo`](crate::ReceivedControl::PacketInfo).
+    pub fn set_recv_pktinfo(&self, on: bool) -> Result<()> {
+        #[cfg(unix)]
+        return self.set_ipv6_flag(crate::sys::IPV6_RECVPKTINFO, on);
+        #[cfg(not(unix))]
+        {
+            let _ = on;
+            Err(no_ip_options())
+        }
+    }
+
+    /// Whether received datagrams come with their traffic class
+    /// (`IPV6_RECVTCLASS`), on IPv6 sockets
+    ///
+    /// On Linux, `recv_msg` gives it as
+    /// [`ReceivedControl::TrafficClass`](crate::ReceivedControl::TrafficClass).
+    pub fn set_recv_tclass(&self, on: bool) -> Result<()> {
+        #[cfg(unix)]
+        return self.set_ipv6_flag(crate::sys::IPV6_RECVTCLASS, on);
+        #[cfg(not(unix))]
+        {
+            let _ = on;
+            Err(no_ip_options())
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_ipv6_flag(&self, name: std::os::raw::c_int, on: bool) -> Result<()> {
+        let on = std::os::raw::c_int::from(on);
+        crate::sys::setsockopt_raw(self.ipv6_fd()?, crate::sys::IPPROTO_IPV6, name, &on)
+    }
+
+    /// Marks sent datagrams, like [`AbstractStream::set_tos`]
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        let s = self.udp()?;
+        #[cfg(unix)]
+        return crate::sys::set_tos(s.as_raw_fd(), s.local_addr()?.is_ipv6(), tos);
+        #[cfg(not(unix))]
+        {
+            let _ = (s, tos);
+            Err(no_ip_options())
+        }
+    }
+
+    pub fn tos(&self) -> Result<u8> {
+        let s = self.udp()?;
+        #[cfg(unix)]
+        return crate::sys::tos(s.as_raw_fd(), s.local_addr()?.is_ipv6());
+        #[cfg(not(unix))]
+        {
+            let _ = s;
+            Err(no_ip_options())
+        }
+    }
+}
+
+/// A borrowed socket, for setting options this crate doesn't wrap
+///
+/// Levels, names and values are those of the platform's `setsockopt`
+/// and `getsockopt`, so they are up to the caller to get right.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+pub struct SockRef<'a> {
+    fd: BorrowedFd<'a>,
+}
+
+#[cfg(unix)]
+impl SockRef<'_> {
+    pub fn set_option_int(&self, level: i32, name: i32, value: i32) -> Result<()> {
+        crate::sys::setsockopt_raw(self.fd.as_raw_fd(), level, name, &value)
+    }
+
+    pub fn option_int(&self, level: i32, name: i32) -> Result<i32> {
+        crate::sys::getsockopt_int(self.fd.as_raw_fd(), level, name)
+    }
+
+    /// Sets an option whose value is a struct or string, given as bytes
+    pub fn set_option_bytes(&self, level: i32, name: i32, value: &[u8]) -> Result<()> {
+        crate::sys::setsockopt_bytes(self.fd.as_raw_fd(), level, name, value)
+    }
+
+    /// Reads an option into `buf`, giving how many bytes it filled
+    pub fn option_bytes(&self, level: i32, name: i32, buf: &mut [u8]) -> Result<usize> {
+        crate::sys::getsockopt_bytes(self.fd.as_raw_fd(), level, name, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for SockRef<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for SockRef<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// So that event loops such as calloop's `Generic` can watch a stream
+/// directly; a WebSocket gives the connection under it
+#[cfg(unix)]
+impl AsFd for AbstractStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(s) => s.as_fd(),
+            Self::Unix(s) => s.as_fd(),
+            Self::WebSocket(s) => s.get_ref().as_fd(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            Self::Shm(s) => s.as_fd(),
+            Self::Pipe(s) => s.as_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for AbstractStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for AbstractListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(l) => l.as_fd(),
+            Self::Unix(l) => l.as_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for AbstractListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for AbstractDatagram {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Udp(s) => s.as_fd(),
+            Self::Unix(s) => s.as_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for AbstractDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AbstractStream {
+    /// Borrows the underlying socket, that of the connection under a
+    /// WebSocket
+    pub fn as_socket(&self) -> SockRef<'_> {
+        SockRef { fd: self.as_fd() }
+    }
+}
+
+#[cfg(unix)]
+impl AbstractListener {
+    pub fn as_socket(&self) -> SockRef<'_> {
+        SockRef { fd: self.as_fd() }
+    }
+}
+
+#[cfg(unix)]
+impl AbstractDatagram {
+    pub fn as_socket(&self) -> SockRef<'_> {
+        SockRef { fd: self.as_fd() }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+
+    #[test]
+    fn congestion() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let c = listener.local_addr().unwrap().connect_any().unwrap();
+        // whatever the default is, it can always be chosen again
+        let name = c.congestion().unwrap();
+        assert!(!name.is_empty());
+        c.set_congestion(&name).unwrap();
+        listener.set_congestion(&name).unwrap();
+        let e = c.set_congestion("anysocket-none").unwrap_err();
+        assert_ne!(e.kind(), ErrorKind::Unsupported);
+
+        let (u, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let e = AbstractStream::from(u).congestion().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn original_dst() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let _c = listener.local_addr().unwrap().connect_any().unwrap();
+        let (s, _) = listener.accept().unwrap();
+        // without a REDIRECT rule there's none, or no netfilter at all
+        let e = s.original_dst().unwrap_err();
+        assert_ne!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn tos() {
+        // DSCP AF41
+        let af41 = 34 << 2;
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        listener.set_tos(af41).unwrap();
+        assert_eq!(listener.tos().unwrap(), af41);
+        let c = listener.local_addr().unwrap().connect_any().unwrap();
+        c.set_tos(af41).unwrap();
+        assert_eq!(c.tos().unwrap(), af41);
+
+        let d = AbstractDatagram::bind("udp:127.0.0.1:0").unwrap();
+        d.set_tos(af41).unwrap();
+        assert_eq!(d.tos().unwrap(), af41);
+        assert_eq!(d.hop_limit().unwrap_err().kind(), ErrorKind::InvalidInput);
+        // IPV6_TCLASS, where there's IPv6
+        if let Ok(d) = AbstractDatagram::bind("udp:[::1]:0") {
+            d.set_tos(af41).unwrap();
+            assert_eq!(d.tos().unwrap(), af41);
+            d.set_hop_limit(7).unwrap();
+            assert_eq!(d.hop_limit().unwrap(), 7);
+            d.set_recv_pktinfo(true).unwrap();
+            d.set_recv_tclass(true).unwrap();
+            d.connect(&d.local_addr().unwrap()).unwrap();
+            d.send(b"x").unwrap();
+            let mut buf = [0u8; 4];
+            let got = d
+                .recv_msg(&mut [std::io::IoSliceMut::new(&mut buf)], 128)
+                .unwrap();
+            assert_eq!(got.len, 1);
+            let mut seen = (false, false);
+            for c in got.control {
+                match c {
+                    crate::ReceivedControl::PacketInfo { addr, .. } => {
+                        assert!(addr.is_loopback());
+                        seen.0 = true;
+                    }
+                    crate::ReceivedControl::TrafficClass(tc) => {
+                        assert_eq!(tc, af41);
+                        seen.1 = true;
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(seen, (true, true));
+        }
+
+        let (u, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert_eq!(
+            AbstractStream::from(u).set_tos(af41).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn sock_ref() {
+        // SOL_SOCKET and SO_KEEPALIVE
+        let (sol, keepalive) = (1, 9);
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let stream = listener.local_addr().unwrap().connect_any().unwrap();
+        let sock = stream.as_socket();
+        assert_eq!(sock.option_int(sol, keepalive).unwrap(), 0);
+        sock.set_option_int(sol, keepalive, 1).unwrap();
+        assert_eq!(sock.option_int(sol, keepalive).unwrap(), 1);
+        let mut buf = [0u8; 4];
+        assert_eq!(sock.option_bytes(sol, keepalive, &mut buf).unwrap(), 4);
+        assert_eq!(i32::from_ne_bytes(buf), 1);
+        assert_eq!(listener.as_socket().as_raw_fd(), listener.raw_fd());
+    }
+}
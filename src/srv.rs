@@ -0,0 +1,297 @@
+//! Finding a service through DNS SRV records, for `srv:` addresses
+
+use crate::{AbstractStream, AbstractToSocketAddrs};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr as IpSocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// Connects to one of the targets `name`, such as `_imap._tcp.example.com`,
+/// has SRV records for, trying them in the order RFC 2782 gives
+pub(crate) fn connect(name: &str) -> Result<AbstractStream> {
+    let mut records = order(lookup(name)?, crate::random_u64);
+    // a target of "." says there's deliberately no such service
+    records.retain(|r| r.target != ".");
+    if records.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} has no servers", name),
+        ));
+    }
+    let targets = records
+        .iter()
+        .map(|r| format!("tcp:{}:{}", r.target.trim_end_matches('.'), r.port));
+    crate::first_success(targets, "connect", |t| t.connect_any())
+}
+
+/// Asks each nameserver in `/etc/resolv.conf` in turn
+fn lookup(name: &str) -> Result<Vec<Record>> {
+    let query = query(&crate::idna::to_ascii(name)?, crate::random_u64() as u16)?;
+    crate::first_success(nameservers(), "resolve SRV records with", |server| {
+        let reply = ask_udp(server, &query)?;
+        // truncated, so ask again over TCP for all of it
+        let reply = if reply.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+            ask_tcp(server, &query)?
+        } else {
+            reply
+        };
+        parse(&reply, &query[..2])
+    })
+}
+
+fn nameservers() -> Vec<IpSocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let servers: Vec<_> = conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| IpSocketAddr::new(ip, 53))
+        .collect();
+    if servers.is_empty() {
+        return vec![IpSocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)];
+    }
+    servers
+}
+
+fn ask_udp(server: IpSocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let any: IpAddr = match server {
+        IpSocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpSocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((any, 0))?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.send(query)?;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = socket.recv(&mut buf)?;
+        // anything else is a late reply to someone else's query
+        if n >= 2 && buf[..2] == query[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+fn ask_tcp(server: IpSocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let mut s = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    s.set_read_timeout(Some(TIMEOUT))?;
+    let mut msg = (query.len() as u16).to_be_bytes().to_vec();
+    msg.extend_from_slice(query);
+    s.write_all(&msg)?;
+    let mut len = [0u8; 2];
+    s.read_exact(&mut len)?;
+    let mut reply = vec![0u8; u16::from_be_bytes(len) as usize];
+    s.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+fn query(name: &str, id: u16) -> Result<Vec<u8>> {
+    let mut q = id.to_be_bytes().to_vec();
+    // recursion desired, one question
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("bad domain name {:?}", name),
+            ));
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(q)
+}
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed DNS reply")
+}
+
+fn u16_at(b: &[u8], at: usize) -> Result<u16> {
+    b.get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(malformed)
+}
+
+/// Reads the possibly compressed name at `at`, giving it and where
+/// the bytes after it start
+fn name_at(b: &[u8], mut at: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let len = *b.get(at).ok_or_else(malformed)? as usize;
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(at + 2);
+            at = (u16_at(b, at)? & 0x3fff) as usize;
+            continue;
+        }
+        if len == 0 {
+            if name.is_empty() {
+                name.push('.');
+            }
+            return Ok((name, end.unwrap_or(at + 1)));
+        }
+        let label = b.get(at + 1..at + 1 + len).ok_or_else(malformed)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label));
+        at += 1 + len;
+    }
+    // a loop of pointers
+    Err(malformed())
+}
+
+fn parse(reply: &[u8], id: &[u8]) -> Result<Vec<Record>> {
+    if reply.len() < 12 || reply[..2] != *id {
+        return Err(malformed());
+    }
+    match reply[3] & 0x0f {
+        0 => {}
+        3 => return Err(Error::new(ErrorKind::NotFound, "no such domain")),
+        rcode => {
+            return Err(Error::other(format!(
+                "nameserver failed with code {}",
+                rcode
+            )))
+        }
+    }
+    let questions = u16_at(reply, 4)?;
+    let answers = u16_at(reply, 6)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        at = name_at(reply, at)?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        at = name_at(reply, at)?.1;
+        let kind = u16_at(reply, at)?;
+        let len = u16_at(reply, at + 8)? as usize;
+        let data = at + 10;
+        at = data + len;
+        if reply.len() < at {
+            return Err(malformed());
+        }
+        // CNAMEs the nameserver followed come first
+        if kind != TYPE_SRV {
+            continue;
+        }
+        records.push(Record {
+            priority: u16_at(reply, data)?,
+            weight: u16_at(reply, data + 2)?,
+            port: u16_at(reply, data + 4)?,
+            target: name_at(reply, data + 6)?.0,
+        });
+    }
+    Ok(records)
+}
+
+/// Sorts by priority, then within each priority picks one at a time,
+/// each with a chance in proportion to its weight
+fn order(mut records: Vec<Record>, mut random: impl FnMut() -> u64) -> Vec<Record> {
+    records.sort_by_key(|r| (r.priority, r.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same = records
+            .iter()
+            .take_while(|r| r.priority == priority)
+            .count();
+        let mut group: Vec<_> = records.drain(..same).collect();
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|r| r.weight as u64).sum();
+            let mut pick = random() % (total + 1);
+            let i = group
+                .iter()
+                .position(|r| {
+                    if pick <= r.weight as u64 {
+                        return true;
+                    }
+                    pick -= r.weight as u64;
+                    false
+                })
+                .unwrap_or(group.len() - 1);
+            ordered.push(group.remove(i));
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(priority: u16, weight: u16, target: &str) -> Record {
+        Record {
+            priority,
+            weight,
+            port: 80,
+            target: target.into(),
+        }
+    }
+
+    #[test]
+    fn parses_reply() {
+        let q = query("_x._tcp.example.com", 0x1234).unwrap();
+        let mut reply = q.clone();
+        reply[2] = 0x81;
+        reply[3] = 0x80;
+        reply[7] = 1;
+        // the owner name points back at the question
+        reply.extend_from_slice(&[0xc0, 12]);
+        reply.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 60, 0, 12]);
+        reply.extend_from_slice(&[0, 10, 0, 5, 0x1f, 0x90]);
+        // "srv" then a pointer to "example.com" in the question
+        reply.extend_from_slice(&[3, b's', b'r', b'v', 0xc0, 20]);
+        assert_eq!(
+            parse(&reply, &q[..2]).unwrap(),
+            [Record {
+                priority: 10,
+                weight: 5,
+                port: 8080,
+                target: "srv.example.com".into(),
+            }]
+        );
+        assert!(parse(&reply[..reply.len() - 1], &q[..2]).is_err());
+
+        reply[3] = 0x83;
+        assert_eq!(
+            parse(&reply, &q[..2]).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn orders_by_priority_and_weight() {
+        let records = vec![
+            record(20, 0, "last"),
+            record(10, 0, "unweighted"),
+            record(10, 60, "heavy"),
+            record(10, 40, "light"),
+        ];
+        let targets = |random: u64| -> Vec<String> {
+            order(records.clone(), || random)
+                .into_iter()
+                .map(|r| r.target)
+                .collect()
+        };
+        // 0 picks the zero weight record first, as RFC 2782 allows
+        assert_eq!(targets(0), ["unweighted", "heavy", "light", "last"]);
+        assert_eq!(targets(30), ["heavy", "light", "unweighted", "last"]);
+        assert_eq!(targets(90), ["light", "heavy", "unweighted", "last"]);
+    }
+}
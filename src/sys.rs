@@ -0,0 +1,1550 @@
+//! The few libc calls that std doesn't expose for every socket type
+//!
+//! std already links against libc, so these are declared directly
+//! rather than pulling in a dependency.
+
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::RawFd;
+
+const MSG_PEEK: c_int = 0x2;
+const F_GETFD: c_int = 1;
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const SOL_SOCKET: c_int = 1;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) const SOL_SOCKET: c_int = 0xffff;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_ACCEPTCONN: c_int = 30;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SO_ACCEPTCONN: c_int = 0x2;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SOCK_STREAM: c_int = if cfg!(any(target_arch = "mips", target_arch = "mips64")) {
+    2
+} else {
+    1
+};
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SOCK_STREAM: c_int = 1;
+
+pub(crate) const IPPROTO_IP: c_int = 0;
+pub(crate) const IPPROTO_TCP: c_int = 6;
+pub(crate) const IPPROTO_IPV6: c_int = 41;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sockopt {
+    use std::os::raw::c_int;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const SO_SNDBUF: c_int = 7;
+    pub const SO_RCVBUF: c_int = 8;
+    pub const SO_REUSEADDR: c_int = 2;
+    pub const SO_REUSEPORT: c_int = 15;
+}
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sockopt {
+    use std::os::raw::c_int;
+    pub const SO_KEEPALIVE: c_int = 0x8;
+    pub const SO_SNDBUF: c_int = 0x1001;
+    pub const SO_RCVBUF: c_int = 0x1002;
+    pub const SO_REUSEADDR: c_int = 0x4;
+    pub const SO_REUSEPORT: c_int = 0x200;
+}
+use sockopt::*;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+const AF_INET: u8 = 2;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const AF_INET6: u8 = 10;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const AF_INET6: u8 = 30;
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+const AF_INET6: u8 = 28;
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+const AF_INET6: u8 = 24;
+
+/// Whether sockaddrs start with a length byte, as on the BSDs
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+const SOCKADDR_LEN: bool = true;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SOCKADDR_LEN: bool = false;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::os::raw::c_int;
+
+    pub const TCP_DEFER_ACCEPT: c_int = 9;
+
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "sparc64")))]
+    pub const SOCK_NONBLOCK: c_int = 0o4000;
+    #[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+    pub const SOCK_NONBLOCK: c_int = 0o200;
+    #[cfg(target_arch = "sparc64")]
+    pub const SOCK_NONBLOCK: c_int = 0x4000;
+
+    #[cfg(not(target_arch = "sparc64"))]
+    pub const SOCK_CLOEXEC: c_int = 0o2000000;
+    #[cfg(target_arch = "sparc64")]
+    pub const SOCK_CLOEXEC: c_int = 0x400000;
+}
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use linux::*;
+
+extern "C" {
+    fn recv(fd: c_int, buf: *mut c_void, len: usize, flags: c_int) -> isize;
+    fn send(fd: c_int, buf: *const c_void, len: usize, flags: c_int) -> isize;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn dup2(fd: c_int, new: c_int) -> c_int;
+    fn getsockopt(fd: c_int, level: c_int, name: c_int, val: *mut c_void, len: *mut u32) -> c_int;
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, val: *const c_void, len: u32) -> c_int;
+    fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+    fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+    fn connect(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+    fn listen(fd: c_int, backlog: c_int) -> c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn sendmsg(fd: c_int, msg: *const MsgHdr, flags: c_int) -> isize;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recvmsg(fd: c_int, msg: *mut MsgHdr, flags: c_int) -> isize;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn sendmmsg(fd: c_int, msgs: *mut MMsgHdr, vlen: u32, flags: c_int) -> c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recvmmsg(
+        fd: c_int,
+        msgs: *mut MMsgHdr,
+        vlen: u32,
+        flags: c_int,
+        timeout: *mut c_void,
+    ) -> c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn accept4(fd: c_int, addr: *mut c_void, len: *mut u32, flags: c_int) -> c_int;
+    #[cfg(feature = "signals")]
+    fn signal(signum: c_int, handler: usize) -> usize;
+    #[cfg(any(feature = "signals", target_os = "linux", target_os = "android"))]
+    fn pipe(fds: *mut c_int) -> c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn splice(
+        fd_in: c_int,
+        off_in: *mut i64,
+        fd_out: c_int,
+        off_out: *mut i64,
+        len: usize,
+        flags: std::os::raw::c_uint,
+    ) -> isize;
+    #[cfg(feature = "signals")]
+    fn write(fd: c_int, buf: *const c_void, len: usize) -> isize;
+}
+
+fn cvt(r: isize) -> Result<usize> {
+    if r < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(r as usize)
+    }
+}
+
+fn cvt_int(r: c_int) -> Result<c_int> {
+    if r < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+/// Whether `fd` stays open in processes started with exec
+pub(crate) fn set_inheritable(fd: RawFd, inheritable: bool) -> Result<()> {
+    let flags = cvt_int(unsafe { fcntl(fd, F_GETFD) })?;
+    let flags = if inheritable {
+        flags & !FD_CLOEXEC
+    } else {
+        flags | FD_CLOEXEC
+    };
+    cvt_int(unsafe { fcntl(fd, F_SETFD, flags) }).map(drop)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const O_NONBLOCK: c_int = SOCK_NONBLOCK;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) const O_NONBLOCK: c_int = 4;
+
+const F_GETFL: c_int = 3;
+const F_SETFL: c_int = 4;
+
+/// Whether `fd` is nonblocking now
+pub(crate) fn fd_nonblocking(fd: RawFd) -> Result<bool> {
+    Ok(cvt_int(unsafe { fcntl(fd, F_GETFL) })? & O_NONBLOCK != 0)
+}
+
+/// Whether reads and writes on `fd` fail with `WouldBlock` rather than
+/// wait, for descriptors that aren't std sockets
+pub(crate) fn set_fd_nonblocking(fd: RawFd, nonblocking: bool) -> Result<()> {
+    let flags = cvt_int(unsafe { fcntl(fd, F_GETFL) })?;
+    let flags = if nonblocking {
+        flags | O_NONBLOCK
+    } else {
+        flags & !O_NONBLOCK
+    };
+    cvt_int(unsafe { fcntl(fd, F_SETFL, flags) }).map(drop)
+}
+
+/// Closes whatever `fd` refers to while keeping the number open, now
+/// referring to `/dev/null`, so it's still safe to use from anywhere
+/// that has borrowed it
+pub(crate) fn close_in_place(fd: RawFd) -> Result<()> {
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    cvt_int(unsafe { dup2(std::os::unix::io::AsRawFd::as_raw_fd(&null), fd) })?;
+    set_inheritable(fd, false)
+}
+
+/// Accepts a connection with `SOCK_*` flags set atomically,
+/// the peer's address is left to `getpeername`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn accept4_fd(fd: RawFd, flags: c_int) -> Result<std::os::unix::io::OwnedFd> {
+    use std::os::unix::io::FromRawFd;
+    loop {
+        match cvt_int(unsafe { accept4(fd, std::ptr::null_mut(), std::ptr::null_mut(), flags) }) {
+            Ok(new) => return Ok(unsafe { std::os::unix::io::OwnedFd::from_raw_fd(new) }),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "signals")]
+const TERMINATE: [c_int; 2] = [2, 15];
+
+/// What SIGINT and SIGTERM did before [`on_terminate`] changed them
+#[cfg(feature = "signals")]
+pub(crate) type Dispositions = [usize; 2];
+
+/// Calls `handler` on SIGINT and SIGTERM, giving what they did before
+#[cfg(feature = "signals")]
+pub(crate) fn on_terminate(handler: extern "C" fn(c_int)) -> Result<Dispositions> {
+    let mut previous = [0; 2];
+    for (i, &sig) in TERMINATE.iter().enumerate() {
+        match set_disposition(sig, handler as usize) {
+            Ok(was) => previous[i] = was,
+            Err(e) => {
+                // put back the ones already changed
+                for (&sig, &was) in TERMINATE.iter().zip(&previous).take(i) {
+                    let _ = set_disposition(sig, was);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(previous)
+}
+
+/// Puts SIGINT and SIGTERM back to what [`on_terminate`] found
+#[cfg(feature = "signals")]
+pub(crate) fn restore_terminate(previous: &Dispositions) -> Result<()> {
+    for (&sig, &was) in TERMINATE.iter().zip(previous) {
+        set_disposition(sig, was)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "signals")]
+fn set_disposition(sig: c_int, handler: usize) -> Result<usize> {
+    const SIG_ERR: usize = usize::MAX;
+    match unsafe { signal(sig, handler) } {
+        SIG_ERR => Err(Error::last_os_error()),
+        was => Ok(was),
+    }
+}
+
+/// A pipe's read and write ends, neither inherited by exec
+#[cfg(any(feature = "signals", target_os = "linux", target_os = "android"))]
+pub(crate) fn pipe_fds() -> Result<(std::os::unix::io::OwnedFd, std::os::unix::io::OwnedFd)> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let mut fds = [0 as c_int; 2];
+    cvt_int(unsafe { pipe(fds.as_mut_ptr()) })?;
+    let fds = unsafe {
+        (
+            std::os::unix::io::OwnedFd::from_raw_fd(fds[0]),
+            std::os::unix::io::OwnedFd::from_raw_fd(fds[1]),
+        )
+    };
+    set_inheritable(fds.0.as_raw_fd(), false)?;
+    set_inheritable(fds.1.as_raw_fd(), false)?;
+    Ok(fds)
+}
+
+/// Moves everything `from` sends to `to` within the kernel, through a
+/// pipe, until `from` reaches its end
+///
+/// Gives `Ok(None)` if the descriptors can't be spliced and nothing
+/// was moved, so the caller can copy them some other way.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn splice_all(from: RawFd, to: RawFd) -> Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+    const SPLICE_F_MOVE: std::os::raw::c_uint = 1;
+    const CHUNK: usize = 64 * 1024;
+    let (read, write) = pipe_fds()?;
+    let splice_one = |from: RawFd, to: RawFd, len: usize| loop {
+        let null = std::ptr::null_mut();
+        match cvt(unsafe { splice(from, null, to, null, len, SPLICE_F_MOVE) }) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            other => return other,
+        }
+    };
+    let mut total = 0u64;
+    loop {
+        let n = match splice_one(from, write.as_raw_fd(), CHUNK) {
+            Ok(0) => return Ok(Some(total)),
+            Ok(n) => n,
+            Err(e) if total == 0 && e.raw_os_error() == Some(22) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut left = n;
+        while left > 0 {
+            left -= splice_one(read.as_raw_fd(), to, left)?;
+        }
+        total += n as u64;
+    }
+}
+
+/// Writes a byte to `fd`, and nothing else, so it's safe in a signal handler
+#[cfg(feature = "signals")]
+pub(crate) fn write_byte(fd: RawFd) {
+    unsafe { write(fd, b"x".as_ptr() as *const c_void, 1) };
+}
+
+/// Like `TcpStream::peek`, for any socket
+pub(crate) fn peek(fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+    cvt(unsafe { recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), MSG_PEEK) })
+}
+
+const MSG_OOB: c_int = 0x1;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const SO_OOBINLINE: c_int = 10;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) const SO_OOBINLINE: c_int = 0x100;
+
+/// Sends `buf` as TCP urgent data
+pub(crate) fn send_oob(fd: RawFd, buf: &[u8]) -> Result<usize> {
+    cvt(unsafe { send(fd, buf.as_ptr() as *const c_void, buf.len(), MSG_OOB) })
+}
+
+/// Receives the TCP urgent byte
+pub(crate) fn recv_oob(fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+    cvt(unsafe { recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), MSG_OOB) })
+}
+
+/// Reads an integer socket option
+pub(crate) fn getsockopt_int(fd: RawFd, level: c_int, name: c_int) -> Result<c_int> {
+    let mut val: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as u32;
+    cvt_int(unsafe {
+        getsockopt(
+            fd,
+            level,
+            name,
+            &mut val as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    })?;
+    Ok(val)
+}
+
+/// Reads a socket option into `buf`, giving how much the kernel filled
+pub(crate) fn getsockopt_bytes(
+    fd: RawFd,
+    level: c_int,
+    name: c_int,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let mut len = buf.len() as u32;
+    cvt_int(unsafe { getsockopt(fd, level, name, buf.as_mut_ptr() as *mut c_void, &mut len) })?;
+    Ok(len as usize)
+}
+
+/// Sets a socket option to `val` as it is
+pub(crate) fn setsockopt_bytes(fd: RawFd, level: c_int, name: c_int, val: &[u8]) -> Result<()> {
+    let len = val.len() as u32;
+    cvt_int(unsafe { setsockopt(fd, level, name, val.as_ptr() as *const c_void, len) }).map(drop)
+}
+
+/// Sets a socket option to the bytes of `val`
+pub(crate) fn setsockopt_raw<T>(fd: RawFd, level: c_int, name: c_int, val: &T) -> Result<()> {
+    let len = std::mem::size_of::<T>() as u32;
+    cvt_int(unsafe { setsockopt(fd, level, name, val as *const T as *const c_void, len) }).map(drop)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TCP_CONGESTION: c_int = 13;
+#[cfg(target_os = "freebsd")]
+const TCP_CONGESTION: c_int = 64;
+
+/// Chooses `fd`'s congestion control algorithm by name
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn set_congestion(fd: RawFd, name: &str) -> Result<()> {
+    cvt_int(unsafe {
+        setsockopt(
+            fd,
+            IPPROTO_TCP,
+            TCP_CONGESTION,
+            name.as_ptr() as *const c_void,
+            name.len() as u32,
+        )
+    })
+    .map(drop)
+}
+
+/// The name of `fd`'s congestion control algorithm
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn congestion(fd: RawFd) -> Result<String> {
+    let mut buf = [0u8; 16];
+    let len = getsockopt_bytes(fd, IPPROTO_TCP, TCP_CONGESTION, &mut buf)?;
+    let name = &buf[..len.min(buf.len())];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Makes `fd` only send and receive through the interface named `name`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn bind_to_device(fd: RawFd, name: &str, _ipv6: bool) -> Result<()> {
+    const SO_BINDTODEVICE: c_int = 25;
+    let len = name.len() as u32;
+    cvt_int(unsafe {
+        setsockopt(
+            fd,
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            name.as_ptr() as *const c_void,
+            len,
+        )
+    })
+    .map(drop)
+}
+
+/// Makes `fd` only send and receive through the interface named `name`
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub(crate) fn bind_to_device(fd: RawFd, name: &str, ipv6: bool) -> Result<()> {
+    const IP_BOUND_IF: c_int = 25;
+    const IPV6_BOUND_IF: c_int = 125;
+    extern "C" {
+        fn if_nametoindex(name: *const std::os::raw::c_char) -> u32;
+    }
+    let cname = std::ffi::CString::new(name)
+        .map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "interface name has a NUL"))?;
+    let index = unsafe { if_nametoindex(cname.as_ptr()) };
+    if index == 0 {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no interface named {:?}", name),
+        ));
+    }
+    let index = index as c_int;
+    if ipv6 {
+        setsockopt_raw(fd, IPPROTO_IPV6, IPV6_BOUND_IF, &index)
+    } else {
+        setsockopt_raw(fd, IPPROTO_IP, IP_BOUND_IF, &index)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_MULTICAST_IF: c_int = 32;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const IP_MULTICAST_IF: c_int = 9;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPV6_MULTICAST_IF: c_int = 17;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const IPV6_MULTICAST_IF: c_int = 9;
+
+/// Chooses the interface multicast datagrams are sent from by its address
+pub(crate) fn set_multicast_if_v4(fd: RawFd, interface: std::net::Ipv4Addr) -> Result<()> {
+    setsockopt_raw(fd, IPPROTO_IP, IP_MULTICAST_IF, &interface.octets())
+}
+
+/// Chooses the interface multicast datagrams are sent from by its index
+pub(crate) fn set_multicast_if_v6(fd: RawFd, interface: u32) -> Result<()> {
+    setsockopt_raw(fd, IPPROTO_IPV6, IPV6_MULTICAST_IF, &interface)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_TOS: c_int = 1;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const IP_TOS: c_int = 3;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod ipv6 {
+    use std::os::raw::c_int;
+    pub const IPV6_UNICAST_HOPS: c_int = 16;
+    pub const IPV6_RECVPKTINFO: c_int = 49;
+    pub const IPV6_PKTINFO: c_int = 50;
+    pub const IPV6_RECVTCLASS: c_int = 66;
+    pub const IPV6_TCLASS: c_int = 67;
+}
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod ipv6 {
+    use std::os::raw::c_int;
+    pub const IPV6_UNICAST_HOPS: c_int = 4;
+    pub const IPV6_RECVPKTINFO: c_int = 61;
+    pub const IPV6_RECVTCLASS: c_int = 35;
+    pub const IPV6_TCLASS: c_int = 36;
+}
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+mod ipv6 {
+    use std::os::raw::c_int;
+    pub const IPV6_UNICAST_HOPS: c_int = 4;
+    pub const IPV6_RECVPKTINFO: c_int = 36;
+    pub const IPV6_RECVTCLASS: c_int = 57;
+    pub const IPV6_TCLASS: c_int = 61;
+}
+pub(crate) use ipv6::*;
+
+fn tos_option(ipv6: bool) -> (c_int, c_int) {
+    if ipv6 {
+        (IPPROTO_IPV6, IPV6_TCLASS)
+    } else {
+        (IPPROTO_IP, IP_TOS)
+    }
+}
+
+/// Sets the byte that carries DSCP and ECN: `IP_TOS`, or
+/// `IPV6_TCLASS` on an IPv6 socket
+pub(crate) fn set_tos(fd: RawFd, ipv6: bool, tos: u8) -> Result<()> {
+    let (level, name) = tos_option(ipv6);
+    setsockopt_raw(fd, level, name, &c_int::from(tos))
+}
+
+pub(crate) fn tos(fd: RawFd, ipv6: bool) -> Result<u8> {
+    let (level, name) = tos_option(ipv6);
+    getsockopt_int(fd, level, name).map(|v| v as u8)
+}
+
+/// `struct msghdr` as glibc and bionic lay it out; musl's narrower
+/// lengths are padded so the same layout works on little-endian targets
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    namelen: u32,
+    iov: *mut c_void,
+    iovlen: usize,
+    control: *mut c_void,
+    controllen: usize,
+    flags: c_int,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MSG_NOSIGNAL: c_int = 0x4000;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MSG_CMSG_CLOEXEC: c_int = 0x40000000;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const MSG_TRUNC: c_int = 0x20;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const MSG_CTRUNC: c_int = 0x8;
+
+/// Sends the data in `iov` along with the already encoded `control` messages
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn send_msg(fd: RawFd, iov: &[std::io::IoSlice<'_>], control: &[u8]) -> Result<usize> {
+    let msg = MsgHdr {
+        name: std::ptr::null_mut(),
+        namelen: 0,
+        // IoSlice is guaranteed to be ABI compatible with iovec
+        iov: iov.as_ptr() as *mut c_void,
+        iovlen: iov.len(),
+        control: if control.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            control.as_ptr() as *mut c_void
+        },
+        controllen: control.len(),
+        flags: 0,
+    };
+    cvt(unsafe { sendmsg(fd, &msg, MSG_NOSIGNAL) })
+}
+
+/// Receives into `iov` and `control`, giving the number of bytes read,
+/// how much of `control` was filled and the `MSG_*` flags
+///
+/// Received descriptors are closed on exec.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn recv_msg(
+    fd: RawFd,
+    iov: &mut [std::io::IoSliceMut<'_>],
+    control: &mut [u8],
+) -> Result<(usize, usize, c_int)> {
+    let mut msg = MsgHdr {
+        name: std::ptr::null_mut(),
+        namelen: 0,
+        iov: iov.as_mut_ptr() as *mut c_void,
+        iovlen: iov.len(),
+        control: control.as_mut_ptr() as *mut c_void,
+        controllen: control.len(),
+        flags: 0,
+    };
+    let n = cvt(unsafe { recvmsg(fd, &mut msg, MSG_CMSG_CLOEXEC) })?;
+    Ok((n, msg.controllen, msg.flags))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct MMsgHdr {
+    hdr: MsgHdr,
+    len: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MSG_WAITFORONE: c_int = 0x10000;
+
+/// `struct iovec`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct Iovec {
+    base: *mut c_void,
+    len: usize,
+}
+
+/// One `msghdr` per buffer, each with a single iovec in `iovs`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mmsg_headers(iovs: &mut [Iovec]) -> Vec<MMsgHdr> {
+    iovs.iter_mut()
+        .map(|iov| MMsgHdr {
+            hdr: MsgHdr {
+                name: std::ptr::null_mut(),
+                namelen: 0,
+                iov: iov as *mut Iovec as *mut c_void,
+                iovlen: 1,
+                control: std::ptr::null_mut(),
+                controllen: 0,
+                flags: 0,
+            },
+            len: 0,
+        })
+        .collect()
+}
+
+/// Sends each buffer as its own datagram in one call, giving how many were sent
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn send_mmsg(fd: RawFd, bufs: &[&[u8]]) -> Result<usize> {
+    // sendmmsg doesn't write through the iovecs, it only takes them as *mut
+    let mut iovs: Vec<Iovec> = bufs
+        .iter()
+        .map(|b| Iovec {
+            base: b.as_ptr() as *mut c_void,
+            len: b.len(),
+        })
+        .collect();
+    let mut msgs = mmsg_headers(&mut iovs);
+    let n = cvt_int(unsafe { sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as u32, MSG_NOSIGNAL) })?;
+    Ok(n as usize)
+}
+
+/// Receives up to one datagram per buffer, waiting only for the first,
+/// and stores their lengths in `lens`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn recv_mmsg(fd: RawFd, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> Result<usize> {
+    let count = bufs.len().min(lens.len());
+    let mut iovs: Vec<Iovec> = bufs[..count]
+        .iter_mut()
+        .map(|b| Iovec {
+            base: b.as_mut_ptr() as *mut c_void,
+            len: b.len(),
+        })
+        .collect();
+    let mut msgs = mmsg_headers(&mut iovs);
+    let n = cvt_int(unsafe {
+        recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as u32,
+            MSG_WAITFORONE,
+            std::ptr::null_mut(),
+        )
+    })? as usize;
+    for (len, msg) in lens.iter_mut().zip(&msgs[..n]) {
+        *len = msg.len as usize;
+    }
+    Ok(n)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern "C" {
+    fn getpid() -> c_int;
+    fn getuid() -> u32;
+    fn getgid() -> u32;
+}
+
+/// This process's pid, uid and gid
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn current_ids() -> (i32, u32, u32) {
+    unsafe { (getpid(), getuid(), getgid()) }
+}
+
+/// Binds a Unix socket to an abstract name the kernel makes up, by
+/// giving `bind` an address that is nothing but the family
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn autobind(fd: RawFd) -> Result<()> {
+    const AF_UNIX: u16 = 1;
+    let family = AF_UNIX;
+    cvt_int(unsafe { bind(fd, &family as *const u16 as *const c_void, 2) }).map(drop)
+}
+
+/// Turns TCP keepalive on with probes starting after `idle` where the
+/// platform lets that be chosen, or turns it off
+pub(crate) fn set_keepalive(fd: RawFd, idle: Option<std::time::Duration>) -> Result<()> {
+    setsockopt_raw(fd, SOL_SOCKET, SO_KEEPALIVE, &(idle.is_some() as c_int))?;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    const TCP_KEEPIDLE: Option<c_int> = Some(if cfg!(target_os = "freebsd") { 256 } else { 4 });
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const TCP_KEEPIDLE: Option<c_int> = Some(0x10);
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    const TCP_KEEPIDLE: Option<c_int> = None;
+    match (idle, TCP_KEEPIDLE) {
+        (Some(idle), Some(opt)) => {
+            let secs = idle.as_secs().clamp(1, c_int::MAX as u64) as c_int;
+            setsockopt_raw(fd, IPPROTO_TCP, opt, &secs)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sets the kernel's receive or send buffer size
+pub(crate) fn set_buffer_size(fd: RawFd, send: bool, size: usize) -> Result<()> {
+    let size = size.min(c_int::MAX as usize) as c_int;
+    setsockopt_raw(
+        fd,
+        SOL_SOCKET,
+        if send { SO_SNDBUF } else { SO_RCVBUF },
+        &size,
+    )
+}
+
+/// Installs a FreeBSD accept filter such as `httpready` on a listening socket
+#[cfg(target_os = "freebsd")]
+pub(crate) fn set_accept_filter(fd: RawFd, name: &str) -> Result<()> {
+    const SO_ACCEPTFILTER: c_int = 0x1000;
+
+    #[repr(C)]
+    struct AcceptFilterArg {
+        af_name: [u8; 16],
+        af_arg: [u8; 256 - 16],
+    }
+
+    let mut arg = AcceptFilterArg {
+        af_name: [0; 16],
+        af_arg: [0; 256 - 16],
+    };
+    if name.len() >= arg.af_name.len() || name.contains('\0') {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid accept filter name {:?}", name),
+        ));
+    }
+    arg.af_name[..name.len()].copy_from_slice(name.as_bytes());
+    setsockopt_raw(fd, SOL_SOCKET, SO_ACCEPTFILTER, &arg)
+}
+
+/// Whether `fd` is a socket that has had `listen` called on it
+pub(crate) fn is_listening(fd: RawFd) -> Result<bool> {
+    getsockopt_int(fd, SOL_SOCKET, SO_ACCEPTCONN).map(|v| v != 0)
+}
+
+/// Makes `fd` available as `target` once the child process execs
+///
+/// Only async-signal-safe calls are made, because this runs between
+/// fork and exec.
+pub(crate) fn move_fd_for_exec(fd: RawFd, target: RawFd) -> Result<()> {
+    if fd == target {
+        let flags = cvt_int(unsafe { fcntl(fd, F_GETFD) })?;
+        cvt_int(unsafe { fcntl(fd, F_SETFD, flags & !FD_CLOEXEC) }).map(drop)
+    } else {
+        // dup2 leaves the new descriptor without FD_CLOEXEC
+        cvt_int(unsafe { dup2(fd, target) }).map(drop)
+    }
+}
+
+/// A `sockaddr_in` or `sockaddr_in6`, laid out by hand
+#[repr(C, align(4))]
+struct RawSockAddr([u8; 28]);
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn raw_sockaddr(addr: &std::net::SocketAddr) -> Result<(RawSockAddr, u32, c_int)> {
+    let mut raw = RawSockAddr([0; 28]);
+    let b = &mut raw.0;
+    let (family, len) = match addr {
+        std::net::SocketAddr::V4(a) => {
+            b[4..8].copy_from_slice(&a.ip().octets());
+            (AF_INET, 16)
+        }
+        std::net::SocketAddr::V6(a) => {
+            b[4..8].copy_from_slice(&a.flowinfo().to_ne_bytes());
+            b[8..24].copy_from_slice(&a.ip().octets());
+            b[24..28].copy_from_slice(&a.scope_id().to_ne_bytes());
+            (AF_INET6, 28)
+        }
+    };
+    if SOCKADDR_LEN {
+        b[0] = len as u8;
+        b[1] = family;
+    } else {
+        b[..2].copy_from_slice(&u16::from(family).to_ne_bytes());
+    }
+    b[2..4].copy_from_slice(&addr.port().to_be_bytes());
+    Ok((raw, len, c_int::from(family)))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+)))]
+fn raw_sockaddr(_: &std::net::SocketAddr) -> Result<(RawSockAddr, u32, c_int)> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "socket addresses are not known on this platform",
+    ))
+}
+
+/// `struct tcp_md5sig`, whose address is a `sockaddr_storage`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C, align(8))]
+struct Md5Sig([u8; 216]);
+
+/// The longest key `TCP_MD5SIG` takes
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const MD5_KEY_MAX: usize = 80;
+
+/// Signs `fd`'s segments to and from `peer` with `key`, or stops
+/// signing them if `key` is empty
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_md5sig(fd: RawFd, peer: &std::net::SocketAddr, key: &[u8]) -> Result<()> {
+    const TCP_MD5SIG: c_int = 14;
+    if key.len() > MD5_KEY_MAX {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("TCP MD5 keys are at most {} bytes", MD5_KEY_MAX),
+        ));
+    }
+    let (addr, len, _) = raw_sockaddr(peer)?;
+    let mut sig = Md5Sig([0; 216]);
+    sig.0[..len as usize].copy_from_slice(&addr.0[..len as usize]);
+    sig.0[130..132].copy_from_slice(&(key.len() as u16).to_ne_bytes());
+    sig.0[136..136 + key.len()].copy_from_slice(key);
+    setsockopt_raw(fd, IPPROTO_TCP, TCP_MD5SIG, &sig)
+}
+
+/// Lets `fd` bind to addresses that aren't local and take connections
+/// redirected by a `TPROXY` rule, which needs `CAP_NET_ADMIN`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_transparent(fd: RawFd, ipv6: bool) -> Result<()> {
+    const IP_TRANSPARENT: c_int = 19;
+    const IPV6_TRANSPARENT: c_int = 75;
+    if ipv6 {
+        setsockopt_raw(fd, IPPROTO_IPV6, IPV6_TRANSPARENT, &1 as &c_int)
+    } else {
+        setsockopt_raw(fd, IPPROTO_IP, IP_TRANSPARENT, &1 as &c_int)
+    }
+}
+
+/// Where an iptables `REDIRECT` or `DNAT` rule had `fd`'s connection
+/// going before sending it here
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn original_dst(fd: RawFd, ipv6: bool) -> Result<std::net::SocketAddr> {
+    // SO_ORIGINAL_DST and IP6T_SO_ORIGINAL_DST
+    const ORIGINAL_DST: c_int = 80;
+    let mut raw = RawSockAddr([0; 28]);
+    let level = if ipv6 { IPPROTO_IPV6 } else { IPPROTO_IP };
+    let len = getsockopt_bytes(fd, level, ORIGINAL_DST, &mut raw.0)?;
+    parse_sockaddr(&raw.0[..len.min(raw.0.len())]).ok_or_else(|| {
+        Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unexpected original destination",
+        )
+    })
+}
+
+extern "C" {
+    fn getnameinfo(
+        addr: *const c_void,
+        len: u32,
+        host: *mut std::os::raw::c_char,
+        host_len: u32,
+        serv: *mut std::os::raw::c_char,
+        serv_len: u32,
+        flags: c_int,
+    ) -> c_int;
+    fn gai_strerror(code: c_int) -> *const std::os::raw::c_char;
+}
+
+/// The name the resolver gives for `addr`, from a PTR record or the
+/// hosts file, or `None` if it has none
+pub(crate) fn host_name(addr: &std::net::SocketAddr) -> Result<Option<String>> {
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    const NI_NAMEREQD: c_int = 8;
+    #[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+    const NI_NAMEREQD: c_int = 4;
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    const EAI_NONAME: c_int = -2;
+    #[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+    const EAI_NONAME: c_int = 8;
+    let (raw, len, _) = raw_sockaddr(addr)?;
+    let mut host = [0 as std::os::raw::c_char; 1025];
+    let r = unsafe {
+        getnameinfo(
+            raw.0.as_ptr().cast(),
+            len,
+            host.as_mut_ptr(),
+            host.len() as u32,
+            std::ptr::null_mut(),
+            0,
+            NI_NAMEREQD,
+        )
+    };
+    match r {
+        0 => {
+            let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+            Ok(Some(name.to_string_lossy().into_owned()))
+        }
+        EAI_NONAME => Ok(None),
+        code => {
+            let message = unsafe { std::ffi::CStr::from_ptr(gai_strerror(code)) };
+            Err(Error::other(message.to_string_lossy().into_owned()))
+        }
+    }
+}
+
+/// The family of the `sockaddr` in `b`
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn sockaddr_family(b: &[u8]) -> Option<u8> {
+    use std::convert::TryFrom;
+    if SOCKADDR_LEN {
+        b.get(1).copied()
+    } else {
+        let family = u16::from_ne_bytes([*b.first()?, *b.get(1)?]);
+        u8::try_from(family).ok()
+    }
+}
+
+/// Reads a `sockaddr_in` or `sockaddr_in6`
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn parse_sockaddr(b: &[u8]) -> Option<std::net::SocketAddr> {
+    use std::convert::TryInto;
+    let family = sockaddr_family(b)?;
+    let port = u16::from_be_bytes(b.get(2..4)?.try_into().unwrap());
+    if family == AF_INET && b.len() >= 8 {
+        let ip: [u8; 4] = b[4..8].try_into().unwrap();
+        Some((std::net::Ipv4Addr::from(ip), port).into())
+    } else if family == AF_INET6 && b.len() >= 28 {
+        let ip: [u8; 16] = b[8..24].try_into().unwrap();
+        Some(
+            std::net::SocketAddrV6::new(
+                ip.into(),
+                port,
+                u32::from_ne_bytes(b[4..8].try_into().unwrap()),
+                u32::from_ne_bytes(b[24..28].try_into().unwrap()),
+            )
+            .into(),
+        )
+    } else {
+        None
+    }
+}
+
+/// `struct ifaddrs`, as far as the address
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+#[repr(C)]
+struct IfAddrs {
+    next: *mut IfAddrs,
+    name: *const std::os::raw::c_char,
+    flags: std::os::raw::c_uint,
+    addr: *const u8,
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+extern "C" {
+    fn getifaddrs(addrs: *mut *mut IfAddrs) -> c_int;
+    fn freeifaddrs(addrs: *mut IfAddrs);
+}
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: NfdsT, timeout: c_int) -> c_int;
+}
+
+/// The IP addresses of the interfaces that are up, with their
+/// interfaces' names
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+pub(crate) fn interface_addrs() -> Result<Vec<(String, std::net::SocketAddr)>> {
+    const IFF_UP: std::os::raw::c_uint = 1;
+    let mut head = std::ptr::null_mut();
+    cvt_int(unsafe { getifaddrs(&mut head) })?;
+    let mut out = vec![];
+    let mut at = head;
+    while let Some(ifa) = unsafe { at.as_ref() } {
+        at = ifa.next;
+        if ifa.addr.is_null() || ifa.flags & IFF_UP == 0 {
+            continue;
+        }
+        let head = unsafe { std::slice::from_raw_parts(ifa.addr, 2) };
+        let len = match sockaddr_family(head) {
+            Some(AF_INET) => 16,
+            Some(AF_INET6) => 28,
+            _ => continue,
+        };
+        let raw = unsafe { std::slice::from_raw_parts(ifa.addr, len) };
+        if let Some(addr) = parse_sockaddr(raw) {
+            let name = unsafe { std::ffi::CStr::from_ptr(ifa.name) };
+            out.push((name.to_string_lossy().into_owned(), addr));
+        }
+    }
+    unsafe { freeifaddrs(head) };
+    Ok(out)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd"
+)))]
+pub(crate) fn interface_addrs() -> Result<Vec<(String, std::net::SocketAddr)>> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "listing interface addresses is not supported on this platform",
+    ))
+}
+
+/// `nfds_t`, which only glibc and musl make a `long`
+#[cfg(target_os = "linux")]
+type NfdsT = std::os::raw::c_ulong;
+#[cfg(not(target_os = "linux"))]
+type NfdsT = std::os::raw::c_uint;
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: std::os::raw::c_short,
+    revents: std::os::raw::c_short,
+}
+
+pub(crate) const POLLIN: std::os::raw::c_short = 1;
+pub(crate) const POLLOUT: std::os::raw::c_short = 4;
+
+/// Waits until at least one of `fds` is ready for its `events`, giving
+/// what each is ready for, or nothing for all of them once `timeout`
+/// passes
+pub(crate) fn poll_fds(
+    fds: &[(RawFd, std::os::raw::c_short)],
+    timeout: Option<std::time::Duration>,
+) -> Result<Vec<std::os::raw::c_short>> {
+    let mut polled: Vec<_> = fds
+        .iter()
+        .map(|&(fd, events)| PollFd {
+            fd,
+            events,
+            revents: 0,
+        })
+        .collect();
+    let deadline = timeout.map(|t| std::time::Instant::now() + t);
+    loop {
+        let ms = match deadline {
+            // rounded up, so as not to wake just before it and spin
+            Some(d) => {
+                let left = d.saturating_duration_since(std::time::Instant::now());
+                left.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as c_int
+            }
+            None => -1,
+        };
+        match cvt_int(unsafe { poll(polled.as_mut_ptr(), polled.len() as NfdsT, ms) }) {
+            Ok(_) => return Ok(polled.iter().map(|p| p.revents).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Waits until at least one of `fds` can be read from, or accepted
+/// on, and says which can
+pub(crate) fn wait_readable(fds: &[RawFd]) -> Result<Vec<bool>> {
+    let fds: Vec<_> = fds.iter().map(|&fd| (fd, POLLIN)).collect();
+    Ok(poll_fds(&fds, None)?.into_iter().map(|r| r != 0).collect())
+}
+
+/// An unconnected TCP socket of `addr`'s family, closed on exec
+pub(crate) fn tcp_socket(addr: &std::net::SocketAddr) -> Result<std::os::unix::io::OwnedFd> {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+    let (_, _, family) = raw_sockaddr(addr)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let ty = SOCK_STREAM | SOCK_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let ty = SOCK_STREAM;
+    let fd = cvt_int(unsafe { socket(family, ty, 0) })?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    set_inheritable(std::os::unix::io::AsRawFd::as_raw_fd(&fd), false)?;
+    Ok(fd)
+}
+
+/// Gives `fd` the local address `addr`
+pub(crate) fn bind_ip(fd: RawFd, addr: &std::net::SocketAddr) -> Result<()> {
+    let (raw, len, _) = raw_sockaddr(addr)?;
+    cvt_int(unsafe { bind(fd, &raw as *const RawSockAddr as *const c_void, len) }).map(drop)
+}
+
+/// A TCP listener on `addr`, sharing it through `SO_REUSEPORT` with
+/// any others bound the same way by the same user if `reuseport`, and
+/// with `IP_TRANSPARENT` set before binding if `transparent`.
+/// `SO_REUSEADDR` is always set, as std does, so an address whose old
+/// connections are still in `TIME_WAIT` can be bound again.
+pub(crate) fn bind_tcp(
+    addr: &std::net::SocketAddr,
+    reuseport: bool,
+    transparent: bool,
+) -> Result<std::net::TcpListener> {
+    let fd = tcp_socket(addr)?;
+    let raw = std::os::unix::io::AsRawFd::as_raw_fd(&fd);
+    setsockopt_raw(raw, SOL_SOCKET, SO_REUSEADDR, &1 as &c_int)?;
+    if reuseport {
+        setsockopt_raw(raw, SOL_SOCKET, SO_REUSEPORT, &1 as &c_int)?;
+    }
+    if transparent {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        set_transparent(raw, addr.is_ipv6())?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "IP_TRANSPARENT needs Linux",
+        ));
+    }
+    bind_ip(raw, addr)?;
+    // what std uses
+    cvt_int(unsafe { listen(raw, 128) })?;
+    Ok(fd.into())
+}
+
+/// Changes how many connections may wait on the already listening
+/// `fd`, with `None` for as many as the system allows
+pub(crate) fn set_backlog(fd: RawFd, backlog: Option<u32>) -> Result<()> {
+    let backlog = backlog.map_or(-1, |b| b.min(c_int::MAX as u32) as c_int);
+    cvt_int(unsafe { listen(fd, backlog) }).map(drop)
+}
+
+/// Connects to `addr` without blocking, looking every so often at
+/// whether `cancelled` has been set, and giving up once it is
+pub(crate) fn connect_unless(
+    addr: &std::net::SocketAddr,
+    cancelled: &std::sync::atomic::AtomicBool,
+) -> Result<std::net::TcpStream> {
+    use std::os::unix::io::AsRawFd;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const EINPROGRESS: i32 = 115;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const EINPROGRESS: i32 = 36;
+    const CHECK: std::time::Duration = std::time::Duration::from_millis(20);
+    let s = std::net::TcpStream::from(tcp_socket(addr)?);
+    s.set_nonblocking(true)?;
+    match connect_ip(s.as_raw_fd(), addr) {
+        Err(e) if e.raw_os_error() == Some(EINPROGRESS) => loop {
+            if cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(Error::other("another attempt connected first"));
+            }
+            if poll_fds(&[(s.as_raw_fd(), POLLOUT)], Some(CHECK))?[0] != 0 {
+                break;
+            }
+        },
+        r => r?,
+    }
+    if let Some(e) = s.take_error()? {
+        return Err(e);
+    }
+    s.set_nonblocking(false)?;
+    Ok(s)
+}
+
+/// Connects `fd` to `addr`, waiting until it's done
+pub(crate) fn connect_ip(fd: RawFd, addr: &std::net::SocketAddr) -> Result<()> {
+    let (raw, len, _) = raw_sockaddr(addr)?;
+    cvt_int(unsafe { connect(fd, &raw as *const RawSockAddr as *const c_void, len) }).map(drop)
+}
+
+#[cfg(all(feature = "shm", target_os = "linux"))]
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn syscall(number: std::os::raw::c_long, ...) -> std::os::raw::c_long;
+}
+
+/// Maps the first `len` bytes of `fd` for reading and writing, shared
+/// with every other process that maps it
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub(crate) fn map_shared(fd: RawFd, len: usize) -> Result<*mut u8> {
+    const PROT_READ_WRITE: c_int = 3;
+    const MAP_SHARED: c_int = 1;
+    let at = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ_WRITE,
+            MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if at as isize == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(at as *mut u8)
+}
+
+/// Undoes [`map_shared`]
+///
+/// # Safety
+/// Nothing may use the mapping afterwards.
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub(crate) unsafe fn unmap(at: *mut u8, len: usize) {
+    munmap(at as *mut c_void, len);
+}
+
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "x86_64"))]
+const SYS_FUTEX: std::os::raw::c_long = 202;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "x86"))]
+const SYS_FUTEX: std::os::raw::c_long = 240;
+#[cfg(all(
+    feature = "shm",
+    target_os = "linux",
+    any(target_arch = "aarch64", target_arch = "riscv64")
+))]
+const SYS_FUTEX: std::os::raw::c_long = 98;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "arm"))]
+const SYS_FUTEX: std::os::raw::c_long = 240;
+#[cfg(all(
+    feature = "shm",
+    target_os = "linux",
+    any(target_arch = "powerpc", target_arch = "powerpc64")
+))]
+const SYS_FUTEX: std::os::raw::c_long = 221;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "s390x"))]
+const SYS_FUTEX: std::os::raw::c_long = 238;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "mips"))]
+const SYS_FUTEX: std::os::raw::c_long = 4238;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "mips64"))]
+const SYS_FUTEX: std::os::raw::c_long = 5194;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "loongarch64"))]
+const SYS_FUTEX: std::os::raw::c_long = 98;
+#[cfg(all(feature = "shm", target_os = "linux", target_arch = "sparc64"))]
+const SYS_FUTEX: std::os::raw::c_long = 142;
+#[cfg(all(
+    feature = "shm",
+    target_os = "linux",
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "arm",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "s390x",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "loongarch64",
+        target_arch = "sparc64"
+    ))
+))]
+compile_error!("the shm feature doesn't know the futex system call on this architecture");
+
+/// Sleeps while `word` is still `expected`, for at most `timeout`,
+/// giving whether it timed out
+///
+/// Not the private futex operations, since the word may be in memory
+/// shared with another process.
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub(crate) fn futex_wait(
+    word: &std::sync::atomic::AtomicU32,
+    expected: u32,
+    timeout: Option<std::time::Duration>,
+) -> Result<bool> {
+    const FUTEX_WAIT: c_int = 0;
+    #[repr(C)]
+    struct Timespec {
+        sec: std::os::raw::c_long,
+        nsec: std::os::raw::c_long,
+    }
+    let ts = timeout.map(|t| Timespec {
+        sec: t.as_secs().min(std::os::raw::c_long::MAX as u64) as _,
+        nsec: t.subsec_nanos() as _,
+    });
+    let ts_ptr = ts
+        .as_ref()
+        .map_or(std::ptr::null(), |ts| ts as *const Timespec);
+    let r = unsafe { syscall(SYS_FUTEX, word.as_ptr(), FUTEX_WAIT, expected, ts_ptr) };
+    if r == 0 {
+        return Ok(false);
+    }
+    let e = Error::last_os_error();
+    match e.raw_os_error() {
+        // ETIMEDOUT
+        Some(110) => Ok(true),
+        // EAGAIN, the word had already changed, and EINTR
+        Some(11) | Some(4) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+/// Wakes everything waiting on `word`
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub(crate) fn futex_wake(word: &std::sync::atomic::AtomicU32) {
+    const FUTEX_WAKE: c_int = 1;
+    unsafe { syscall(SYS_FUTEX, word.as_ptr(), FUTEX_WAKE, c_int::MAX) };
+}
+
+extern "C" {
+    fn posix_openpt(flags: c_int) -> c_int;
+    fn grantpt(fd: c_int) -> c_int;
+    fn unlockpt(fd: c_int) -> c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn ptsname_r(fd: c_int, buf: *mut std::os::raw::c_char, len: usize) -> c_int;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn ptsname(fd: c_int) -> *mut std::os::raw::c_char;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_NOCTTY: c_int = 0o400;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const O_NOCTTY: c_int = 0x20000;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+const O_NOCTTY: c_int = 0x8000;
+
+/// The master side of a new pseudo-terminal, with the terminal side
+/// ready to be opened
+pub(crate) fn open_pty() -> Result<std::os::unix::io::OwnedFd> {
+    use std::os::unix::io::FromRawFd;
+    const O_RDWR: c_int = 2;
+    let fd = cvt_int(unsafe { posix_openpt(O_RDWR | O_NOCTTY) })?;
+    let fd = unsafe { std::os::unix::io::OwnedFd::from_raw_fd(fd) };
+    let raw = std::os::unix::io::AsRawFd::as_raw_fd(&fd);
+    set_inheritable(raw, false)?;
+    cvt_int(unsafe { grantpt(raw) })?;
+    cvt_int(unsafe { unlockpt(raw) })?;
+    Ok(fd)
+}
+
+/// The path of the terminal side of the pseudo-terminal whose master
+/// is `fd`
+pub(crate) fn pty_path(fd: RawFd) -> Result<std::path::PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let name = {
+        let mut buf = [0 as std::os::raw::c_char; 128];
+        // returns the error rather than setting errno
+        let r = unsafe { ptsname_r(fd, buf.as_mut_ptr(), buf.len()) };
+        if r != 0 {
+            return Err(Error::from_raw_os_error(r));
+        }
+        unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }.to_owned()
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let name = {
+        // ptsname uses a static buffer
+        static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = LOCK.lock().unwrap();
+        let at = unsafe { ptsname(fd) };
+        if at.is_null() {
+            return Err(Error::last_os_error());
+        }
+        unsafe { std::ffi::CStr::from_ptr(at) }.to_owned()
+    };
+    Ok(std::ffi::OsStr::from_bytes(name.to_bytes()).into())
+}
+
+#[cfg(feature = "serial")]
+mod termios {
+    use std::os::raw::c_int;
+
+    /// `struct termios`, left opaque apart from `c_cflag`, and big
+    /// enough for every platform's
+    #[repr(C, align(8))]
+    pub struct Termios(pub [u8; 256]);
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub type Speed = std::os::raw::c_ulong;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub type Speed = u32;
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub type Flags = std::os::raw::c_ulong;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub type Flags = u32;
+
+    /// After c_iflag and c_oflag
+    pub const CFLAG_OFFSET: usize = 2 * std::mem::size_of::<Flags>();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub const CREAD_CLOCAL: Flags = 0o200 | 0o4000;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub const CREAD_CLOCAL: Flags = 0x800 | 0x8000;
+
+    extern "C" {
+        pub fn tcgetattr(fd: c_int, t: *mut Termios) -> c_int;
+        pub fn tcsetattr(fd: c_int, when: c_int, t: *const Termios) -> c_int;
+        pub fn cfmakeraw(t: *mut Termios);
+        pub fn cfsetspeed(t: *mut Termios, speed: Speed) -> c_int;
+    }
+
+    /// The `B*` constant for `baud`, which on Linux isn't the number
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        not(any(target_arch = "powerpc", target_arch = "powerpc64"))
+    ))]
+    pub fn speed(baud: u32) -> Option<Speed> {
+        const RATES: [u32; 30] = [
+            50, 75, 110, 134, 150, 200, 300, 600, 1200, 1800, 2400, 4800, 9600, 19200, 38400,
+            57600, 115200, 230400, 460800, 500000, 576000, 921600, 1000000, 1152000, 1500000,
+            2000000, 2500000, 3000000, 3500000, 4000000,
+        ];
+        let i = RATES.iter().position(|&r| r == baud)? as u32;
+        // B50 is 1 up to B38400 at 0o17, then 0o10001 for B57600 on
+        Some(if i < 15 { i + 1 } else { 0o10001 + (i - 15) })
+    }
+
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        any(target_arch = "powerpc", target_arch = "powerpc64")
+    ))]
+    pub fn speed(_baud: u32) -> Option<Speed> {
+        None
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn speed(baud: u32) -> Option<Speed> {
+        Some(baud as Speed)
+    }
+}
+
+/// Puts the terminal `fd` in raw mode with 8 data bits, no parity and
+/// no modem control, at `baud` if given
+#[cfg(feature = "serial")]
+pub(crate) fn configure_serial(fd: RawFd, baud: Option<u32>) -> Result<()> {
+    use std::convert::TryInto;
+    use termios::*;
+    const TCSANOW: c_int = 0;
+    let speed = match baud {
+        Some(baud) => Some(speed(baud).ok_or_else(|| {
+            Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {}", baud),
+            )
+        })?),
+        None => None,
+    };
+    let mut t = Termios([0; 256]);
+    cvt_int(unsafe { tcgetattr(fd, &mut t) })?;
+    unsafe { cfmakeraw(&mut t) };
+    let at = CFLAG_OFFSET..CFLAG_OFFSET + std::mem::size_of::<Flags>();
+    let cflag = Flags::from_ne_bytes(t.0[at.clone()].try_into().unwrap()) | CREAD_CLOCAL;
+    t.0[at].copy_from_slice(&cflag.to_ne_bytes());
+    if let Some(speed) = speed {
+        cvt_int(unsafe { cfsetspeed(&mut t, speed) })?;
+    }
+    cvt_int(unsafe { tcsetattr(fd, TCSANOW, &t) }).map(drop)
+}
@@ -0,0 +1,185 @@
+//! The kernel's statistics about a TCP connection
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+/// What the kernel knows about a TCP connection's health, from Linux's
+/// `TCP_INFO`
+///
+/// Counters that the running kernel is too old to report are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// The connection's state, as Linux's `TCP_ESTABLISHED` and so on
+    pub state: u8,
+    /// Retransmissions of the current unacknowledged segment
+    pub retransmits: u8,
+    /// The retransmission timeout
+    pub rto: Duration,
+    pub snd_mss: u32,
+    pub rcv_mss: u32,
+    /// Segments sent but not yet acknowledged
+    pub unacked: u32,
+    /// Segments thought to be lost
+    pub lost: u32,
+    /// Segments being retransmitted
+    pub retrans: u32,
+    /// Retransmissions over the life of the connection
+    pub total_retrans: u32,
+    /// The path MTU
+    pub pmtu: u32,
+    /// The smoothed round trip time
+    pub rtt: Duration,
+    /// How much the round trip time varies
+    pub rtt_var: Duration,
+    /// The lowest round trip time seen
+    pub min_rtt: Duration,
+    /// The congestion window, in segments
+    pub snd_cwnd: u32,
+    pub snd_ssthresh: u32,
+    /// Bytes per second the connection is paced to
+    pub pacing_rate: u64,
+    /// Bytes per second recently delivered to the peer
+    pub delivery_rate: u64,
+    pub bytes_sent: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub bytes_retrans: u64,
+    pub segs_out: u32,
+    pub segs_in: u32,
+    /// Bytes written but not yet sent
+    pub notsent_bytes: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl TcpInfo {
+    fn parse(b: &[u8]) -> TcpInfo {
+        use std::convert::TryInto;
+        let byte = |at: usize| b.get(at).copied().unwrap_or(0);
+        let u32_at = |at: usize| {
+            b.get(at..at + 4)
+                .map_or(0, |b| u32::from_ne_bytes(b.try_into().unwrap()))
+        };
+        let u64_at = |at: usize| {
+            b.get(at..at + 8)
+                .map_or(0, |b| u64::from_ne_bytes(b.try_into().unwrap()))
+        };
+        let micros = |at: usize| Duration::from_micros(u32_at(at).into());
+        TcpInfo {
+            state: byte(0),
+            retransmits: byte(2),
+            rto: micros(8),
+            snd_mss: u32_at(16),
+            rcv_mss: u32_at(20),
+            unacked: u32_at(24),
+            lost: u32_at(32),
+            retrans: u32_at(36),
+            pmtu: u32_at(60),
+            rtt: micros(68),
+            rtt_var: micros(72),
+            snd_ssthresh: u32_at(76),
+            snd_cwnd: u32_at(80),
+            total_retrans: u32_at(100),
+            pacing_rate: u64_at(104),
+            bytes_acked: u64_at(120),
+            bytes_received: u64_at(128),
+            segs_out: u32_at(136),
+            segs_in: u32_at(140),
+            notsent_bytes: u32_at(144),
+            min_rtt: micros(148),
+            delivery_rate: u64_at(160),
+            bytes_sent: u64_at(200),
+            bytes_retrans: u64_at(208),
+        }
+    }
+}
+
+fn no_tcp_info() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "TCP statistics need a TCP connection on Linux or macOS",
+    )
+}
+
+impl AbstractStream {
+    /// The kernel's statistics about this connection
+    ///
+    /// Fails with `Unsupported` on Unix domain sockets and on platforms
+    /// other than Linux.
+    pub fn tcp_info(&self) -> Result<TcpInfo> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            const TCP_INFO: i32 = 11;
+            if let Self::Unix(_) = self {
+                return Err(no_tcp_info());
+            }
+            let mut buf = [0u8; 232];
+            let len = crate::sys::getsockopt_bytes(
+                self.raw_fd(),
+                crate::sys::IPPROTO_TCP,
+                TCP_INFO,
+                &mut buf,
+            )?;
+            Ok(TcpInfo::parse(&buf[..len.min(buf.len())]))
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        Err(no_tcp_info())
+    }
+
+    /// The kernel's smoothed estimate of the round trip time
+    ///
+    /// Works on Linux and macOS, and fails with `Unsupported` on Unix
+    /// domain sockets and elsewhere.
+    pub fn rtt(&self) -> Result<Duration> {
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        {
+            use std::convert::TryInto;
+            const TCP_CONNECTION_INFO: i32 = 0x106;
+            if let Self::Unix(_) = self {
+                return Err(no_tcp_info());
+            }
+            let mut buf = [0u8; 128];
+            let len = crate::sys::getsockopt_bytes(
+                self.raw_fd(),
+                crate::sys::IPPROTO_TCP,
+                TCP_CONNECTION_INFO,
+                &mut buf,
+            )?;
+            // tcpi_srtt, in milliseconds
+            let srtt = buf[..len.min(buf.len())]
+                .get(44..48)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "short TCP_CONNECTION_INFO"))?;
+            Ok(Duration::from_millis(
+                u32::from_ne_bytes(srtt.try_into().unwrap()).into(),
+            ))
+        }
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        self.tcp_info().map(|info| info.rtt)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::AbstractToSocketAddrs;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn tcp_info() {
+        let listener = "127.0.0.1:0".bind_any().unwrap();
+        let mut c = listener.local_addr().unwrap().connect_any().unwrap();
+        let (mut s, _) = listener.accept().unwrap();
+        c.write_all(b"hello").unwrap();
+        s.read_exact(&mut [0u8; 5]).unwrap();
+        let info = c.tcp_info().unwrap();
+        // TCP_ESTABLISHED
+        assert_eq!(info.state, 1);
+        assert!(info.snd_cwnd > 0);
+        assert!(info.bytes_acked > 0 || info.segs_out > 0);
+        assert!(c.rtt().unwrap() < Duration::from_secs(1));
+
+        let (u, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let e = AbstractStream::from(u).tcp_info().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+}
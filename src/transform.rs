@@ -0,0 +1,196 @@
+//! Encoding what's written and decoding what's read, with a pluggable
+//! transform
+
+use crate::AbstractStream;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Turns bytes into what goes over the wire and back again
+///
+/// Each call gets whatever bytes have arrived or been written, so a
+/// transform that works in frames or blocks keeps the incomplete end
+/// of its input until the rest comes.
+pub trait Transform {
+    /// Encodes bytes being written, appending them to `out`
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()>;
+
+    /// Decodes bytes that have been read, appending those it can to
+    /// `out`
+    fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()>;
+
+    /// Appends anything `encode` held back, on flush
+    fn flush(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let _ = out;
+        Ok(())
+    }
+}
+
+/// A stream whose writes go through [`Transform::encode`] and whose
+/// reads come out of [`Transform::decode`]
+///
+/// Encoded bytes that a nonblocking stream couldn't take yet are kept
+/// and written ahead of the next write or flush.
+#[derive(Debug)]
+pub struct Transformed<T, S = AbstractStream> {
+    inner: S,
+    transform: T,
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    pending: Vec<u8>,
+}
+
+impl<T: Transform, S> Transformed<T, S> {
+    pub fn new(inner: S, transform: T) -> Self {
+        Transformed {
+            inner,
+            transform,
+            decoded: vec![],
+            decoded_pos: 0,
+            pending: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn transform(&self) -> &T {
+        &self.transform
+    }
+
+    /// Gives back the stream, dropping anything decoded but not yet
+    /// read and anything encoded but not yet written
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<T: Transform, S: Write> Transformed<T, S> {
+    fn write_pending(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.inner.write(&self.pending)?;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write encoded bytes",
+                ));
+            }
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transform, S: Read> Read for Transformed<T, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut chunk = [0u8; 8192];
+        while self.decoded_pos == self.decoded.len() {
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.decoded.clear();
+            self.decoded_pos = 0;
+            self.transform.decode(&chunk[..n], &mut self.decoded)?;
+        }
+        let left = &self.decoded[self.decoded_pos..];
+        let n = left.len().min(buf.len());
+        buf[..n].copy_from_slice(&left[..n]);
+        self.decoded_pos += n;
+        Ok(n)
+    }
+}
+
+impl<T: Transform, S: Write> Write for Transformed<T, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_pending()?;
+        self.transform.encode(buf, &mut self.pending)?;
+        // buf is taken either way, what's left goes out next time
+        match self.write_pending() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.transform.flush(&mut self.pending)?;
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Xors with a repeating key and holds back an odd trailing byte,
+    /// to check that partial input carries over
+    struct Pairs {
+        key: u8,
+        held: Option<u8>,
+    }
+
+    impl Transform for Pairs {
+        fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.extend(data.iter().map(|b| b ^ self.key));
+            Ok(())
+        }
+
+        fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            let mut data: Vec<u8> = self
+                .held
+                .take()
+                .into_iter()
+                .chain(data.iter().copied())
+                .collect();
+            if data.len() % 2 == 1 {
+                self.held = data.pop();
+            }
+            out.extend(data.iter().map(|b| b ^ self.key));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut w = Transformed::new(
+            vec![],
+            Pairs {
+                key: 0x5a,
+                held: None,
+            },
+        );
+        w.write_all(b"hello!").unwrap();
+        w.flush().unwrap();
+        let wire = w.into_inner();
+        assert_ne!(wire, b"hello!");
+
+        // a byte at a time, so every other read leaves one held back
+        struct Trickle(Vec<u8>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0.remove(0);
+                Ok(1)
+            }
+        }
+        let mut r = Transformed::new(
+            Trickle(wire),
+            Pairs {
+                key: 0x5a,
+                held: None,
+            },
+        );
+        let mut got = String::new();
+        r.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "hello!");
+    }
+}
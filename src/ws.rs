@@ -0,0 +1,569 @@
+//! WebSocket client transport, for `ws://` addresses
+//!
+//! Only the client side is implemented. Every `write` is sent as one
+//! binary frame and the payload of incoming text, binary and continuation
+//! frames is delivered by `read` as a plain byte stream. Pings are
+//! answered automatically and a close frame from the peer reads as EOF.
+//! A frame that fails partway through being sent, as when a write
+//! times out, leaves the peer expecting the rest of it, so every write
+//! after that fails. Reads can be retried after a timeout or
+//! `WouldBlock` at any point, a frame header that only partly arrived
+//! is kept until the rest does.
+
+use crate::os_random;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER: usize = 8192;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// A byte stream carried over WebSocket binary frames
+#[derive(Debug)]
+pub struct WebSocketStream {
+    inner: TcpStream,
+    /// Payload bytes left in the frame currently being read
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_pos: usize,
+    /// The header of the next frame as far as it has arrived, followed
+    /// by the payload if it is a control frame
+    partial: Vec<u8>,
+    closed: bool,
+    /// Set once a frame was partly sent, after which nothing else can be
+    poisoned: AtomicBool,
+}
+
+/// Connects to a `ws://host[:port][/path]` url and performs the upgrade
+pub(crate) fn connect(url: &str) -> Result<WebSocketStream> {
+    if url.starts_with("wss://") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "wss:// requires TLS, which is not supported",
+        ));
+    }
+    let rest = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a ws:// url"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "ws:// url has no host"));
+    }
+    let authority = crate::idna::host_port(authority)?;
+    let authority = &authority[..];
+    let has_port = match authority.rfind(':') {
+        Some(i) => !authority[i..].contains(']'),
+        None => false,
+    };
+    let inner = if has_port {
+        TcpStream::connect(authority)?
+    } else {
+        let host = authority.trim_start_matches('[').trim_end_matches(']');
+        TcpStream::connect((host, 80))?
+    };
+    WebSocketStream::handshake(inner, authority, path)
+}
+
+impl WebSocketStream {
+    fn handshake(mut inner: TcpStream, host: &str, path: &str) -> Result<Self> {
+        let mut nonce = [0u8; 16];
+        os_random(&mut nonce);
+        let key = base64(&nonce);
+        write!(
+            inner,
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        )?;
+
+        // read byte at a time so no frame data is consumed with the headers
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HEADER {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "websocket response headers too long",
+                ));
+            }
+            let mut b = [0u8];
+            if inner.read(&mut b)? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed during websocket handshake",
+                ));
+            }
+            head.push(b[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or("");
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("websocket upgrade refused: {}", status),
+            ));
+        }
+        let accept = lines
+            .filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+            .map(|(_, value)| value.trim());
+        if accept != Some(accept_key(&key).as_str()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "bad Sec-WebSocket-Accept in websocket upgrade",
+            ));
+        }
+
+        Ok(WebSocketStream::new(inner))
+    }
+
+    fn new(inner: TcpStream) -> Self {
+        WebSocketStream {
+            inner,
+            remaining: 0,
+            mask: None,
+            mask_pos: 0,
+            partial: vec![],
+            closed: false,
+            poisoned: AtomicBool::new(false),
+        }
+    }
+
+    /// The underlying Tcp connection
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    /// Sends a close frame and then shuts down the Tcp connection
+    pub fn shutdown(&self, how: std::net::Shutdown) -> Result<()> {
+        if how != std::net::Shutdown::Read {
+            self.write_frame(OP_CLOSE, &[])?;
+        }
+        self.inner.shutdown(how)
+    }
+
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        if self.poisoned.load(Ordering::SeqCst) {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "an earlier websocket frame was only partly sent",
+            ));
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let len = payload.len();
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else if len <= u16::MAX as usize {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        // clients are required to mask everything they send, with keys
+        // the peer can't predict
+        let mut mask = [0u8; 4];
+        os_random(&mut mask);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        let mut sent = 0;
+        while sent < frame.len() {
+            match (&self.inner).write(&frame[sent..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if sent > 0 {
+                        self.poisoned.store(true, Ordering::SeqCst);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads frame headers until a data frame with payload is reached,
+    /// returns false if the peer closed the connection
+    fn next_data_frame(&mut self) -> Result<bool> {
+        loop {
+            if !self.fill_partial(2)? {
+                return Ok(false);
+            }
+            let h = [self.partial[0], self.partial[1]];
+            let extended = match h[1] & 0x7f {
+                126 => 2,
+                127 => 8,
+                _ => 0,
+            };
+            let masked = h[1] & 0x80 != 0;
+            let header = 2 + extended + if masked { 4 } else { 0 };
+            self.fill_partial(header)?;
+
+            let opcode = h[0] & 0x0f;
+            let len = match extended {
+                2 => u16::from_be_bytes([self.partial[2], self.partial[3]]) as u64,
+                8 => {
+                    let mut b = [0u8; 8];
+                    b.copy_from_slice(&self.partial[2..10]);
+                    u64::from_be_bytes(b)
+                }
+                _ => (h[1] & 0x7f) as u64,
+            };
+            let mask = if masked {
+                let mut m = [0u8; 4];
+                m.copy_from_slice(&self.partial[header - 4..header]);
+                Some(m)
+            } else {
+                None
+            };
+
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                    self.partial.clear();
+                    if len == 0 {
+                        continue;
+                    }
+                    self.remaining = len;
+                    self.mask = mask;
+                    self.mask_pos = 0;
+                    return Ok(true);
+                }
+                OP_CLOSE | OP_PING | OP_PONG => {
+                    if len > 125 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "oversized websocket control frame",
+                        ));
+                    }
+                    self.fill_partial(header + len as usize)?;
+                    let mut payload = self.partial.split_off(header);
+                    self.partial.clear();
+                    if let Some(m) = mask {
+                        payload
+                            .iter_mut()
+                            .enumerate()
+                            .for_each(|(i, b)| *b ^= m[i % 4]);
+                    }
+                    if opcode == OP_CLOSE {
+                        if !self.closed {
+                            self.closed = true;
+                            // echo the status code back, as the protocol asks
+                            let _ = self.write_frame(OP_CLOSE, &payload[..payload.len().min(2)]);
+                        }
+                        return Ok(false);
+                    } else if opcode == OP_PING {
+                        self.write_frame(OP_PONG, &payload)?;
+                    }
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown websocket opcode {}", opcode),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Reads until `partial` holds `want` bytes, returns false if the
+    /// connection closed cleanly before another frame began. Whatever
+    /// arrived is kept if this fails, so it can be called again.
+    fn fill_partial(&mut self, want: usize) -> Result<bool> {
+        while self.partial.len() < want {
+            let mut b = [0u8; 128];
+            let n = (want - self.partial.len()).min(b.len());
+            match self.inner.read(&mut b[..n]) {
+                Ok(0) if self.partial.is_empty() => return Ok(false),
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed inside a websocket frame",
+                    ))
+                }
+                Ok(n) => self.partial.extend_from_slice(&b[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 && (self.closed || !self.next_data_frame()?) {
+            self.closed = true;
+            return Ok(0);
+        }
+        let want = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed inside a websocket frame",
+            ));
+        }
+        if let Some(m) = self.mask {
+            for b in &mut buf[..n] {
+                *b ^= m[self.mask_pos % 4];
+                self.mask_pos += 1;
+            }
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.write_frame(OP_BINARY, buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// SHA-1, which the handshake needs for Sec-WebSocket-Accept
+/// (and nothing else; it is not used for security here)
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                block[i * 4],
+                block[i * 4 + 1],
+                block[i * 4 + 2],
+                block[i * 4 + 3],
+            ]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (hi, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (i, v) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn accept_key_rfc6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    /// A minimal server: answers the upgrade, then echoes one frame
+    /// back unmasked after sending a ping
+    fn echo_server(listener: TcpListener) {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut b = [0u8];
+            s.read_exact(&mut b).unwrap();
+            head.push(b[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let key = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        write!(
+            s,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        )
+        .unwrap();
+
+        let mut h = [0u8; 6];
+        s.read_exact(&mut h).unwrap();
+        assert_eq!(h[0], 0x80 | OP_BINARY);
+        let len = (h[1] & 0x7f) as usize;
+        let mut payload = vec![0u8; len];
+        s.read_exact(&mut payload).unwrap();
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= h[2 + i % 4]);
+
+        s.write_all(&[0x80 | OP_PING, 1, b'p']).unwrap();
+        s.write_all(&[0x80 | OP_BINARY, len as u8]).unwrap();
+        s.write_all(&payload).unwrap();
+        s.write_all(&[0x80 | OP_CLOSE, 0]).unwrap();
+
+        let mut pong = [0u8; 7];
+        s.read_exact(&mut pong).unwrap();
+        assert_eq!(pong[0], 0x80 | OP_PONG);
+    }
+
+    #[test]
+    fn round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/echo", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || echo_server(listener));
+
+        let mut ws = connect(&url).unwrap();
+        ws.write_all(b"hello").unwrap();
+        let mut got = String::new();
+        ws.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "hello");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn partial_frame_poisons() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let inner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // never read from, so a big enough frame can't all be sent
+        let _peer = listener.accept().unwrap();
+        inner
+            .set_write_timeout(Some(std::time::Duration::from_millis(50)))
+            .unwrap();
+        let mut ws = WebSocketStream::new(inner);
+        assert!(ws.write(&vec![0; 64 << 20]).is_err());
+        assert_eq!(ws.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn frames_trickle_in() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let inner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        inner.set_nonblocking(true).unwrap();
+        let mut ws = WebSocketStream::new(inner);
+
+        let payload: Vec<u8> = (0..130).collect();
+        let mut frames = vec![0x80 | OP_PING, 0x80 | 2, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2];
+        frames.extend_from_slice(&[0x80 | OP_BINARY, 126, 0, 130]);
+        frames.extend_from_slice(&payload);
+        frames.extend_from_slice(&[0x80 | OP_CLOSE, 0]);
+
+        let mut got = vec![];
+        let mut would_block = 0;
+        for &byte in &frames {
+            peer.write_all(&[byte]).unwrap();
+            // wait for the byte to arrive, so each read sees just that one
+            while ws.get_ref().peek(&mut [0]).is_err() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let mut buf = [0u8; 256];
+            loop {
+                match ws.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => got.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        would_block += 1;
+                        break;
+                    }
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+        assert_eq!(got, payload);
+        assert!(ws.closed);
+        // every byte but the one closing the stream left a read waiting
+        assert_eq!(would_block, frames.len() - 1);
+
+        let mut pong = [0u8; 8];
+        peer.read_exact(&mut pong).unwrap();
+        assert_eq!(pong[0], 0x80 | OP_PONG);
+        let data: Vec<u8> = (0..2).map(|i| pong[6 + i] ^ pong[2 + i]).collect();
+        assert_eq!(data, b"hi");
+    }
+
+    #[test]
+    fn wss_unsupported() {
+        let e = connect("wss://example.com/").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+}